@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+drop index jwt_family_id;
+alter table jwt drop column expires_at;
+alter table jwt drop column used_at;
+alter table jwt drop column kind;
+alter table jwt drop column family_id;
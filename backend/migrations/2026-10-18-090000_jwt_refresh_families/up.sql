@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- existing tokens carry no family, they can't be rotated: force everyone to log in again
+delete from jwt;
+alter table jwt add column family_id text not null default '';
+alter table jwt add column kind text not null default 'access' CHECK ( kind in ('access', 'refresh') );
+alter table jwt add column used_at big integer;
+alter table jwt add column expires_at big integer not null default 0;
+create index jwt_family_id on jwt(family_id);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+drop table roles_mfa;
+drop table mfa_challenges;
+drop table recovery_codes;
+drop table totp;
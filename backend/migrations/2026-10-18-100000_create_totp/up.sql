@@ -0,0 +1,22 @@
+-- Your SQL goes here
+create table totp (
+    user_id integer primary key not null references users(id),
+    secret text not null,
+    enabled integer default (0) CHECK (enabled IN (0, 1)) not null,
+    last_step big integer default (0) not null
+);
+create table recovery_codes (
+    id integer primary key not null,
+    user_id integer not null references users(id),
+    code_hash text not null,
+    used integer default (0) CHECK (used IN (0, 1)) not null
+);
+create table mfa_challenges (
+    id text primary key not null,
+    user_id integer not null references users(id),
+    attempts integer default (0) not null,
+    expires_at big integer not null
+);
+create table roles_mfa (
+    role text primary key CHECK ( role in ('root', 'super', 'user') ) not null
+);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+create table login_attempts (
+    attempt_key text primary key not null,
+    failures integer default (0) not null,
+    last_failure big integer default (0) not null,
+    locked_until big integer default (0) not null
+);
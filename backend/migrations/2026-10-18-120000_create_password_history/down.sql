@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop index password_history_user;
+drop table password_history;
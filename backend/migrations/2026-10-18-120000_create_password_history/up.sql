@@ -0,0 +1,8 @@
+-- Your SQL goes here
+create table password_history (
+    id integer primary key not null,
+    user_id integer not null references users(id),
+    hash text not null,
+    created_at big integer not null
+);
+create index password_history_user on password_history(user_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop table password_resets;
+drop index users_email;
+alter table users drop column email;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+alter table users add column email text;
+create unique index users_email on users(email);
+create table password_resets (
+    id integer primary key not null,
+    user_id integer not null references users(id),
+    token_hash text unique not null,
+    expires_at big integer not null
+);
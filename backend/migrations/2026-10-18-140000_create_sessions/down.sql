@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop index sessions_user;
+drop table sessions;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+create table sessions (
+    id text primary key not null,
+    user_id integer not null references users(id),
+    created_at big integer not null,
+    last_seen big integer not null,
+    ip text not null,
+    user_agent text not null
+);
+create index sessions_user on sessions(user_id);
+insert into sessions (id, user_id, created_at, last_seen, ip, user_agent)
+select distinct family_id, user_id, strftime('%s', 'now'), strftime('%s', 'now'), '', ''
+from jwt;
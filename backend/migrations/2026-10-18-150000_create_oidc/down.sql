@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+alter table sessions drop column oidc_client_id;
+drop table oidc_codes;
+drop table oidc_redirect_uris;
+drop table oidc_clients;
@@ -0,0 +1,30 @@
+-- Your SQL goes here
+create table oidc_clients (
+    client_id text primary key not null,
+    name text not null,
+    -- null for public clients, which have to use pkce instead
+    secret_hash text,
+    created_at big integer not null
+);
+create table oidc_redirect_uris (
+    id integer primary key not null,
+    client_id text not null references oidc_clients(client_id),
+    uri text not null,
+    -- 'login' or 'logout'
+    kind text not null,
+    unique (client_id, uri, kind)
+);
+create table oidc_codes (
+    code_hash text primary key not null,
+    client_id text not null references oidc_clients(client_id),
+    user_id integer not null references users(id),
+    redirect_uri text not null,
+    scope text not null,
+    nonce text,
+    code_challenge text,
+    auth_time big integer not null,
+    ip text not null,
+    user_agent text not null,
+    expires_at big integer not null
+);
+alter table sessions add column oidc_client_id text references oidc_clients(client_id);
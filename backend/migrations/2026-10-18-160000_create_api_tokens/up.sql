@@ -0,0 +1,15 @@
+-- Your SQL goes here
+create table api_tokens (
+    id integer primary key not null,
+    user_id integer not null references users(id),
+    name text not null,
+    -- first characters of the token, enough for the user to recognize it
+    prefix text not null,
+    token_hash text unique not null,
+    -- space separated
+    scopes text not null,
+    created_at big integer not null,
+    expires_at big integer not null,
+    last_used_at big integer
+);
+create index api_tokens_user on api_tokens(user_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop table service_account_credentials;
+alter table users drop column kind;
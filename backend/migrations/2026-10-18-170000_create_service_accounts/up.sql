@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- 'user' for people logging in with a password, 'service' for service accounts
+alter table users add column kind text not null default 'user';
+create table service_account_credentials (
+    user_id integer primary key not null references users(id),
+    client_id text unique not null,
+    secret_hash text not null,
+    created_at big integer not null
+);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- authentication backend vouching for the user: 'local' or 'ldap'
+alter table users add column backend text not null default 'local';
@@ -0,0 +1,22 @@
+-- Your SQL goes here
+-- subjects of upstream identity providers, linked to the rauth user they log in as
+create table federated_identities (
+    id integer primary key not null,
+    provider text not null,
+    subject text not null,
+    user_id integer not null references users(id),
+    created_at big integer not null,
+    unique (provider, subject)
+);
+create index federated_identities_user_id on federated_identities(user_id);
+-- logins in progress at an upstream provider, keyed by the hash of their state parameter
+create table federation_states (
+    state_hash text primary key not null,
+    provider text not null,
+    nonce text not null,
+    code_verifier text not null,
+    return_to text,
+    -- set when a logged in user links their account instead of logging in
+    link_user_id integer references users(id),
+    expires_at big integer not null
+);
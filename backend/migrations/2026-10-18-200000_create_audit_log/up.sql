@@ -0,0 +1,15 @@
+-- Your SQL goes here
+-- what admins did as other users. users are kept by id and login, without foreign key, so
+-- entries outlive the users they mention
+create table audit_log (
+    id integer primary key not null,
+    created_at big integer not null,
+    actor_id integer not null,
+    actor_login text not null,
+    user_id integer not null,
+    user_login text not null,
+    action text not null,
+    detail text not null,
+    ip text not null
+);
+create index audit_log_created_at on audit_log(created_at);
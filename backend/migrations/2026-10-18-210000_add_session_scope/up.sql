@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- scopes granted to the openid connect client the session was opened for
+alter table sessions add column scope text;
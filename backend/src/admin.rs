@@ -0,0 +1,458 @@
+use crate::api_error::ApiError;
+use crate::config::{self, Config};
+use crate::establish_connection;
+use crate::models::domain_rule_model::{DomainRule, NewDomainRule};
+use crate::models::group_model::{Group, NewGroup};
+use crate::models::group_user_model::GroupUser;
+use crate::models::jwt_model::JWTInternal;
+use crate::models::session_model::Session;
+use crate::models::url_rule_model::{NewURLRule, URLRule};
+use crate::models::user_model::{NewUser, User};
+use crate::password_hasher::PasswordHasher;
+use clap::{Parser, Subcommand};
+use diesel::SqliteConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use dotenvy::dotenv;
+use env_logger::Env;
+use std::fmt;
+use std::io::BufRead;
+use std::process::ExitCode;
+
+pub(crate) const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// administers rauth through its database, without going through the api. it reads the
+/// same configuration as the server
+#[derive(Parser)]
+#[command(name = "rauth-admin")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// creates the database or applies the migrations it lacks, then asks for a root
+    /// password when root has none yet
+    Init {
+        /// read the password from the first line of stdin instead of the terminal
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// sets the root password, and logs root out everywhere
+    RootPassword {
+        /// read the password from the first line of stdin instead of the terminal
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// lists and creates users, given by login or by id
+    #[command(subcommand)]
+    Users(UserCommand),
+    /// manages groups and their members, groups are given by name or by id
+    #[command(subcommand)]
+    Groups(GroupCommand),
+    /// manages the url and domain rules giving groups access
+    #[command(subcommand)]
+    Rules(RuleCommand),
+    /// lists and revokes the login sessions of a user
+    #[command(subcommand)]
+    Sessions(SessionCommand),
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    List,
+    /// creates a user with a password, in the public group like the api does
+    Create {
+        login: String,
+        #[arg(long)]
+        email: Option<String>,
+        /// read the password from the first line of stdin instead of the terminal
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// groups the user belongs to
+    Groups {
+        user: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum GroupCommand {
+    List,
+    Create {
+        name: String,
+    },
+    /// users of the group
+    Members {
+        group: String,
+    },
+    /// adds a user to the group
+    Add {
+        group: String,
+        user: String,
+    },
+    /// removes a user from the group
+    Remove {
+        group: String,
+        user: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum RuleCommand {
+    List,
+    /// gives the group access to an url
+    AddUrl {
+        group: String,
+        url: String,
+    },
+    /// gives the group access to a domain
+    AddDomain {
+        group: String,
+        domain: String,
+    },
+    DeleteUrl {
+        id: i32,
+    },
+    DeleteDomain {
+        id: i32,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionCommand {
+    List {
+        user: String,
+    },
+    /// revokes every session of the user, or only the one given
+    Revoke {
+        user: String,
+        #[arg(long)]
+        session: Option<String>,
+    },
+}
+
+enum AdminError {
+    Api(ApiError),
+    Other(String),
+}
+
+impl From<ApiError> for AdminError {
+    fn from(e: ApiError) -> Self {
+        AdminError::Api(e)
+    }
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminError::Api(e @ ApiError::PasswordPolicy { violations }) => {
+                write!(f, "{e}")?;
+                for violation in violations {
+                    write!(f, "\n  {violation:?}")?;
+                }
+                Ok(())
+            }
+            AdminError::Api(e) => write!(f, "{e}"),
+            AdminError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// entry point of the `rauth-admin` binary
+#[must_use]
+pub fn run() -> ExitCode {
+    let cli = Cli::parse();
+    dotenv().ok();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration:\n{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    config::init(config);
+    env_logger::init_from_env(Env::default().default_filter_or("warn"));
+    let mut db = establish_connection(&config::get().database.url);
+    match execute(&mut db, cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn execute(db: &mut SqliteConnection, command: Command) -> Result<(), AdminError> {
+    match command {
+        Command::Init { password_stdin } => init(db, || read_password(password_stdin)),
+        Command::RootPassword { password_stdin } => {
+            set_root_password(db, &read_password(password_stdin)?)
+        }
+        Command::Users(command) => users(db, command),
+        Command::Groups(command) => groups(db, command),
+        Command::Rules(command) => rules(db, command),
+        Command::Sessions(command) => sessions(db, command),
+    }
+}
+
+/// the password is only asked for when root needs one
+fn init(
+    db: &mut SqliteConnection,
+    password: impl FnOnce() -> Result<String, AdminError>,
+) -> Result<(), AdminError> {
+    let applied = db
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(|e| AdminError::Other(format!("couldn't migrate the database: {e}")))?;
+    for version in &applied {
+        println!("applied migration {version}");
+    }
+    let root = User::root(db)?;
+    if PasswordHasher::is_hash(&root.hash) {
+        println!("root already has a password.");
+        return Ok(());
+    }
+    println!("root has no password yet.");
+    set_root_password(db, &password()?)
+}
+
+fn set_root_password(db: &mut SqliteConnection, password: &str) -> Result<(), AdminError> {
+    let mut root = User::root(db)?;
+    root.hash = User::hash_new_password(db, &config::get().passwords.policy, &root, password)?;
+    User::update_user(db, &root)?;
+    JWTInternal::invalidate_user(db, &root)?;
+    println!("root password set.");
+    Ok(())
+}
+
+/// asks twice on the terminal, so a typo doesn't lock anyone out
+fn read_password(from_stdin: bool) -> Result<String, AdminError> {
+    let failed = |e: std::io::Error| AdminError::Other(format!("couldn't read password: {e}"));
+    if from_stdin {
+        let mut password = String::new();
+        std::io::stdin()
+            .lock()
+            .read_line(&mut password)
+            .map_err(failed)?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let password = rpassword::prompt_password("password: ").map_err(failed)?;
+    if rpassword::prompt_password("password again: ").map_err(failed)? != password {
+        return Err(AdminError::Other("passwords don't match.".to_string()));
+    }
+    Ok(password)
+}
+
+/// the login is tried first, a login can look like an id
+fn find_user(db: &mut SqliteConnection, user: &str) -> Result<User, ApiError> {
+    match (User::by_login(db, user), user.parse()) {
+        (Err(ApiError::UserNotFound), Ok(user_id)) => User::get(db, user_id),
+        (found, _) => found,
+    }
+}
+
+/// the name is tried first, a name can look like an id
+fn find_group(db: &mut SqliteConnection, group: &str) -> Result<Group, ApiError> {
+    match (Group::by_name(db, group), group.parse()) {
+        (Err(ApiError::Group), Ok(group_id)) => Group::get(db, group_id),
+        (found, _) => found,
+    }
+}
+
+fn print_user(user: &User) {
+    println!(
+        "{}\t{}\t{}",
+        user.id,
+        user.login,
+        user.email.as_deref().unwrap_or_default()
+    );
+}
+
+fn print_group(group: &Group) {
+    println!("{}\t{}", group.id, group.name);
+}
+
+fn users(db: &mut SqliteConnection, command: UserCommand) -> Result<(), AdminError> {
+    match command {
+        UserCommand::List => User::get_all(db)?.iter().for_each(print_user),
+        UserCommand::Create {
+            login,
+            email,
+            password_stdin,
+        } => {
+            let new_user = NewUser {
+                login,
+                hash: read_password(password_stdin)?,
+                email,
+            };
+            let user = User::create(db, &config::get().passwords.policy, &new_user)?;
+            let public_group = Group::get(db, 1)?;
+            GroupUser::add_user_to_group(db, &user, &public_group)?;
+            print_user(&user);
+        }
+        UserCommand::Groups { user } => {
+            let user = find_user(db, &user)?;
+            User::get_groups(db, &user)?.iter().for_each(print_group);
+        }
+    }
+    Ok(())
+}
+
+fn groups(db: &mut SqliteConnection, command: GroupCommand) -> Result<(), AdminError> {
+    match command {
+        GroupCommand::List => Group::get_all(db)?.iter().for_each(print_group),
+        GroupCommand::Create { name } => {
+            print_group(&Group::create_group(db, &NewGroup { name })?);
+        }
+        GroupCommand::Members { group } => {
+            let group = find_group(db, &group)?;
+            Group::users_from_group(db, &group)?
+                .iter()
+                .for_each(print_user);
+        }
+        GroupCommand::Add { group, user } => {
+            let (group, user) = (find_group(db, &group)?, find_user(db, &user)?);
+            GroupUser::add_user_to_group(db, &user, &group)?;
+            println!("added.");
+        }
+        GroupCommand::Remove { group, user } => {
+            let (group, user) = (find_group(db, &group)?, find_user(db, &user)?);
+            GroupUser::remove_user_from_group(db, &user, &group)?;
+            println!("removed.");
+        }
+    }
+    Ok(())
+}
+
+fn rules(db: &mut SqliteConnection, command: RuleCommand) -> Result<(), AdminError> {
+    match command {
+        RuleCommand::List => {
+            for rule in URLRule::get_all(db)? {
+                println!("{}\turl\t{}\t{}", rule.id, rule.url, rule.group_id);
+            }
+            for rule in DomainRule::get_all(db)? {
+                println!("{}\tdomain\t{}\t{}", rule.id, rule.domain, rule.group_id);
+            }
+        }
+        RuleCommand::AddUrl { group, url } => {
+            let group_id = find_group(db, &group)?.id;
+            let rule = URLRule::create(db, &NewURLRule { url, group_id })?;
+            println!("{}\turl\t{}\t{}", rule.id, rule.url, rule.group_id);
+        }
+        RuleCommand::AddDomain { group, domain } => {
+            let group_id = find_group(db, &group)?.id;
+            let rule = DomainRule::create(db, &NewDomainRule { domain, group_id })?;
+            println!("{}\tdomain\t{}\t{}", rule.id, rule.domain, rule.group_id);
+        }
+        RuleCommand::DeleteUrl { id } => {
+            URLRule::delete(db, id)?;
+            println!("deleted.");
+        }
+        RuleCommand::DeleteDomain { id } => {
+            DomainRule::delete(db, id)?;
+            println!("deleted.");
+        }
+    }
+    Ok(())
+}
+
+fn sessions(db: &mut SqliteConnection, command: SessionCommand) -> Result<(), AdminError> {
+    match command {
+        SessionCommand::List { user } => {
+            let user = find_user(db, &user)?;
+            for session in Session::for_user(db, &user)? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    session.id,
+                    session.created_at,
+                    session.last_seen,
+                    session.ip,
+                    session.user_agent
+                );
+            }
+        }
+        SessionCommand::Revoke { user, session } => {
+            let user = find_user(db, &user)?;
+            match session {
+                None => JWTInternal::invalidate_user(db, &user)?,
+                Some(session_id) => match Session::get(db, &session_id)? {
+                    Some(session) if session.user_id == user.id => {
+                        JWTInternal::delete_family(db, &session.id)?;
+                    }
+                    _ => return Err(ApiError::SessionNotFound.into()),
+                },
+            }
+            println!("revoked.");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use diesel::Connection;
+
+    fn unused() -> Result<String, AdminError> {
+        panic!("root already has a password, none should be asked for")
+    }
+
+    #[test]
+    fn init_creates_the_database_and_sets_the_root_password_once() {
+        config::init_for_tests();
+        let mut db = SqliteConnection::establish(":memory:").unwrap();
+
+        assert!(matches!(
+            init(&mut db, || Ok("short".to_string())),
+            Err(AdminError::Api(ApiError::PasswordPolicy { .. }))
+        ));
+        assert!(!db.has_pending_migration(MIGRATIONS).unwrap());
+        assert!(!PasswordHasher::is_hash(&User::root(&mut db).unwrap().hash));
+
+        assert!(init(&mut db, || Ok(test_support::PASSWORD.to_string())).is_ok());
+        let root = User::root(&mut db).unwrap();
+        assert!(PasswordHasher::verify(test_support::PASSWORD, &root.hash));
+
+        // run again, as after an upgrade, it only migrates
+        assert!(init(&mut db, unused).is_ok());
+        assert_eq!(User::root(&mut db).unwrap().hash, root.hash);
+    }
+
+    #[test]
+    fn setting_the_root_password_logs_root_out() {
+        let storage = test_support::storage();
+        let mut db = storage.db.lock().unwrap();
+        let root = User::root(&mut db).unwrap();
+        test_support::session(&mut db, &test_support::key_ring(), &root, None);
+        assert_eq!(Session::for_user(&mut db, &root).unwrap().len(), 1);
+
+        let password = "An0ther-r00t-pass!";
+        assert!(set_root_password(&mut db, password).is_ok());
+        assert!(PasswordHasher::verify(
+            password,
+            &User::root(&mut db).unwrap().hash
+        ));
+        assert!(Session::for_user(&mut db, &root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn users_and_groups_are_found_by_name_or_id() {
+        let storage = test_support::storage();
+        let mut db = storage.db.lock().unwrap();
+        let public = Group::get(&mut db, 1).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[&public]);
+
+        assert_eq!(find_user(&mut db, "alice").unwrap().id, alice.id);
+        assert_eq!(
+            find_user(&mut db, &alice.id.to_string()).unwrap().id,
+            alice.id
+        );
+        assert!(matches!(
+            find_user(&mut db, "nobody"),
+            Err(ApiError::UserNotFound)
+        ));
+        assert_eq!(find_group(&mut db, &public.name).unwrap().id, public.id);
+        assert_eq!(find_group(&mut db, "1").unwrap().id, public.id);
+    }
+}
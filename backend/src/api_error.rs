@@ -1,7 +1,9 @@
-use actix_web::http::header::ContentType;
+use crate::password_policy::PolicyViolation;
+use actix_web::http::header::{ContentType, CACHE_CONTROL, WWW_AUTHENTICATE};
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use derive_more::{Display, Error};
+use serde::Serialize;
 
 #[derive(Debug, Display, Error)]
 pub enum ApiError {
@@ -18,6 +20,26 @@ pub enum ApiError {
     #[display(fmt = "User not found.")]
     UserNotFound,
 
+    #[display(fmt = "Session not found.")]
+    SessionNotFound,
+
+    #[display(fmt = "Error with api token.")]
+    ApiToken,
+    #[display(fmt = "Api token not found.")]
+    ApiTokenNotFound,
+
+    #[display(fmt = "Error with service account.")]
+    ServiceAccount,
+    #[display(fmt = "Service account not found.")]
+    ServiceAccountNotFound,
+
+    #[display(fmt = "Couldn't log in with the identity provider.")]
+    Federation,
+    #[display(fmt = "Identity provider not found.")]
+    FederationProviderNotFound,
+    #[display(fmt = "Linked identity not found.")]
+    FederatedIdentityNotFound,
+
     #[display(fmt = "Error with domain rule.")]
     DomainRule,
 
@@ -34,31 +56,120 @@ pub enum ApiError {
     Jwt,
     #[display(fmt = "Cannot delete root user !")]
     CantDeleteRoot,
+    #[display(fmt = "This user can't be impersonated.")]
+    Impersonation,
+    #[display(fmt = "Cross-site request refused.")]
+    CrossSiteRequest,
+
+    #[display(fmt = "Error with two-factor authentication.")]
+    Mfa,
+    #[display(fmt = "Two-factor authentication is required for your role, ask an administrator.")]
+    MfaEnrollmentRequired,
+
+    #[display(fmt = "Too many failed attempts, try again later.")]
+    TooManyAttempts,
+
+    #[display(fmt = "Invalid or expired password reset token.")]
+    PasswordReset,
+
+    #[display(fmt = "Password doesn't comply with the password policy.")]
+    PasswordPolicy { violations: Vec<PolicyViolation> },
+
+    #[display(fmt = "OpenID Connect client not found.")]
+    OidcClientNotFound,
+    #[display(fmt = "Error with OpenID Connect client.")]
+    OidcClient,
+    #[display(fmt = "{error}")]
+    OAuth { error: OAuthError },
+}
+
+/// error codes of the token endpoint, rfc 6749 section 5.2
+#[derive(Debug, Display, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OAuthError {
+    #[display(fmt = "The request is missing a parameter or is malformed.")]
+    InvalidRequest,
+    #[display(fmt = "Client authentication failed.")]
+    InvalidClient,
+    #[display(fmt = "The grant is invalid, expired or was issued to another client.")]
+    InvalidGrant,
+    #[display(fmt = "The grant type isn't supported.")]
+    UnsupportedGrantType,
+}
+
+#[derive(Serialize)]
+struct OAuthErrorBody {
+    error: OAuthError,
+    error_description: String,
+}
+
+/// lets clients tell the user which rules their password breaks
+#[derive(Serialize)]
+struct PolicyErrorBody<'a> {
+    error: String,
+    violations: &'a [PolicyViolation],
 }
 
 impl ResponseError for ApiError {
     fn status_code(&self) -> StatusCode {
         match *self {
+            ApiError::OAuth {
+                error: OAuthError::InvalidClient,
+            } => StatusCode::UNAUTHORIZED,
             ApiError::GroupCreation
             | ApiError::Role
             | ApiError::Group
             | ApiError::DomainRule
             | ApiError::URLRule
             | ApiError::UserCreation
+            | ApiError::Mfa
+            | ApiError::PasswordReset
+            | ApiError::OidcClient
+            | ApiError::ApiToken
+            | ApiError::ServiceAccount
+            | ApiError::Federation
+            | ApiError::OAuth { .. }
             | ApiError::User => StatusCode::BAD_REQUEST,
-            ApiError::UserNotFound => StatusCode::NOT_FOUND,
-            ApiError::CantDeleteRoot => StatusCode::UNPROCESSABLE_ENTITY,
-            ApiError::Jwt => StatusCode::FORBIDDEN,
+            ApiError::UserNotFound
+            | ApiError::SessionNotFound
+            | ApiError::ApiTokenNotFound
+            | ApiError::ServiceAccountNotFound
+            | ApiError::FederationProviderNotFound
+            | ApiError::FederatedIdentityNotFound
+            | ApiError::OidcClientNotFound => StatusCode::NOT_FOUND,
+            ApiError::CantDeleteRoot | ApiError::PasswordPolicy { .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Jwt
+            | ApiError::MfaEnrollmentRequired
+            | ApiError::Impersonation
+            | ApiError::CrossSiteRequest => StatusCode::FORBIDDEN,
+            ApiError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
             ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .insert_header(("Access-Control-Allow-Origin", "http://localhost:5173"))
-            .insert_header(("Access-Control-Allow-Origin", "http://localhost.dummy:5173"))
-            .insert_header(("Access-Control-Allow-Credentials", "true"))
-            .body(self.to_string())
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::PasswordPolicy { violations } => response.json(PolicyErrorBody {
+                error: self.to_string(),
+                violations,
+            }),
+            ApiError::OAuth { error } => {
+                if *error == OAuthError::InvalidClient {
+                    response.insert_header((WWW_AUTHENTICATE, "Basic"));
+                }
+                response
+                    .insert_header((CACHE_CONTROL, "no-store"))
+                    .json(OAuthErrorBody {
+                        error: *error,
+                        error_description: error.to_string(),
+                    })
+            }
+            _ => response
+                .insert_header(ContentType::html())
+                .body(self.to_string()),
+        }
     }
 }
@@ -0,0 +1,525 @@
+use crate::api_error::ApiError;
+use crate::config::{Config, LdapConfig};
+use crate::helpers::try_get_connection;
+use crate::models::group_user_model::GroupUser;
+use crate::models::user_model::User;
+use crate::password_hasher::PasswordHasher;
+use crate::StorageState;
+use actix_web::web;
+use ldap3::{dn_escape, LdapConn, LdapConnSettings, Scope, SearchEntry};
+use log::error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// ldap result code of a bind with wrong credentials, or an unknown dn
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+/// where a user's password is checked, recorded on the users a backend provisioned
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuthBackend {
+    /// password hashes stored by rauth
+    Local,
+    /// bind to an ldap directory
+    Ldap,
+}
+
+impl AuthBackend {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuthBackend::Local => "local",
+            AuthBackend::Ldap => "ldap",
+        }
+    }
+}
+
+/// what a backend makes of a login and password
+pub(crate) enum Verdict {
+    /// the login isn't this backend's, the next one is asked
+    Unknown,
+    /// the login is this backend's and the password is wrong
+    Rejected,
+    Accepted(User),
+}
+
+/// checks passwords for some of the users. backends can block (a directory can be slow to
+/// answer) so they are run off the request path, and only lock the database while they use it
+pub(crate) trait Authenticator: Send + Sync {
+    fn authenticate(
+        &self,
+        storage: &web::Data<StorageState>,
+        login: &str,
+        password: &str,
+    ) -> Result<Verdict, ApiError>;
+}
+
+/// users whose password hash rauth stores. hashes are checked without the database, a
+/// verification takes a while. a wrong password is rejected and an unknown login still goes
+/// through a verification, so the timing doesn't tell whether the login exists. a hash made
+/// with other settings than the configured ones is replaced once it matched
+pub(crate) struct LocalAuthenticator;
+
+impl Authenticator for LocalAuthenticator {
+    fn authenticate(
+        &self,
+        storage: &web::Data<StorageState>,
+        login: &str,
+        password: &str,
+    ) -> Result<Verdict, ApiError> {
+        let user = User::find_local(&mut *try_get_connection(storage)?, login)?;
+        let hasher = PasswordHasher::get();
+        let Some(mut user) = user else {
+            hasher.verify_nothing(password);
+            return Ok(Verdict::Unknown);
+        };
+        if !PasswordHasher::verify(password, &user.hash) {
+            return Ok(Verdict::Rejected);
+        }
+        if hasher.needs_rehash(&user.hash) {
+            user.hash = hasher.hash(password)?;
+            User::store_rehash(&mut *try_get_connection(storage)?, &user);
+        }
+        Ok(Verdict::Accepted(user))
+    }
+}
+
+/// what the directory tells about a user who bound successfully
+pub(crate) struct DirectoryEntry {
+    pub(crate) email: Option<String>,
+    /// dns of the groups the user belongs to
+    pub(crate) groups: Vec<String>,
+}
+
+/// the part of an ldap server [`LdapAuthenticator`] relies on, so an in-process directory can
+/// stand in for a real one
+pub(crate) trait Directory: Send + Sync {
+    /// binds as the user, none when the directory refuses the credentials
+    fn bind(&self, login: &str, password: &str) -> Result<Option<DirectoryEntry>, String>;
+}
+
+/// an ldap server reached with a simple bind
+pub(crate) struct LdapDirectory {
+    url: String,
+    settings: LdapConnSettings,
+    bind_dn: String,
+    email_attribute: String,
+    group_attribute: String,
+}
+
+impl LdapDirectory {
+    pub(crate) fn new(config: &LdapConfig) -> Self {
+        Self {
+            url: config.url.clone(),
+            settings: LdapConnSettings::new()
+                .set_conn_timeout(Duration::from_secs(config.timeout))
+                .set_starttls(config.starttls),
+            bind_dn: config.bind_dn.clone(),
+            email_attribute: config.email_attribute.clone(),
+            group_attribute: config.group_attribute.clone(),
+        }
+    }
+}
+
+impl Directory for LdapDirectory {
+    fn bind(&self, login: &str, password: &str) -> Result<Option<DirectoryEntry>, String> {
+        let dn = self.bind_dn.replace("{login}", &dn_escape(login));
+        let mut ldap =
+            LdapConn::with_settings(self.settings.clone(), &self.url).map_err(|e| e.to_string())?;
+        let bound = ldap.simple_bind(&dn, password).map_err(|e| e.to_string())?;
+        if bound.rc == LDAP_INVALID_CREDENTIALS {
+            let _ = ldap.unbind();
+            return Ok(None);
+        }
+        bound.success().map_err(|e| e.to_string())?;
+        let (entries, _) = ldap
+            .search(
+                &dn,
+                Scope::Base,
+                "(objectClass=*)",
+                vec![self.email_attribute.as_str(), self.group_attribute.as_str()],
+            )
+            .and_then(ldap3::SearchResult::success)
+            .map_err(|e| e.to_string())?;
+        let _ = ldap.unbind();
+        let Some(entry) = entries.into_iter().next().map(SearchEntry::construct) else {
+            return Err(format!("{dn} bound but can't be read"));
+        };
+        Ok(Some(DirectoryEntry {
+            email: entry
+                .attrs
+                .get(&self.email_attribute)
+                .and_then(|values| values.first().cloned()),
+            groups: entry
+                .attrs
+                .get(&self.group_attribute)
+                .cloned()
+                .unwrap_or_default(),
+        }))
+    }
+}
+
+/// users of a directory, created on their first login. the directory keeps their password,
+/// and the membership of the rauth groups mapped to directory groups follows it at each login
+pub(crate) struct LdapAuthenticator<D: Directory> {
+    directory: D,
+    /// lowercased ldap group dn to rauth group name
+    groups: BTreeMap<String, String>,
+}
+
+impl<D: Directory> LdapAuthenticator<D> {
+    pub(crate) fn new(directory: D, groups: &BTreeMap<String, String>) -> Self {
+        Self {
+            directory,
+            groups: groups
+                .iter()
+                .map(|(dn, name)| (dn.to_lowercase(), name.clone()))
+                .collect(),
+        }
+    }
+
+    fn sync_groups(
+        &self,
+        db: &mut diesel::SqliteConnection,
+        user: &User,
+        entry: &DirectoryEntry,
+    ) -> Result<(), ApiError> {
+        let managed: Vec<&str> = self.groups.values().map(String::as_str).collect();
+        let member_of: Vec<&str> = entry
+            .groups
+            .iter()
+            .filter_map(|dn| self.groups.get(&dn.to_lowercase()))
+            .map(String::as_str)
+            .collect();
+        GroupUser::sync_managed_groups(db, user, &managed, &member_of)
+    }
+}
+
+impl<D: Directory> Authenticator for LdapAuthenticator<D> {
+    fn authenticate(
+        &self,
+        storage: &web::Data<StorageState>,
+        login: &str,
+        password: &str,
+    ) -> Result<Verdict, ApiError> {
+        // an empty password would make an anonymous bind, which most servers accept
+        if password.is_empty() {
+            return Ok(Verdict::Unknown);
+        }
+        // an unreachable directory leaves the login to the next backends
+        let entry = match self.directory.bind(login, password) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(Verdict::Unknown),
+            Err(e) => {
+                error!("ldap: {e}");
+                return Ok(Verdict::Unknown);
+            }
+        };
+        let mut db = try_get_connection(storage)?;
+        let user = match User::provision(&mut db, login, entry.email.as_deref(), AuthBackend::Ldap)
+        {
+            Ok(user) => user,
+            Err(ApiError::User) => return Ok(Verdict::Rejected),
+            Err(e) => return Err(e),
+        };
+        self.sync_groups(&mut db, &user, &entry)?;
+        Ok(Verdict::Accepted(user))
+    }
+}
+
+/// the configured backends, asked in order until one knows the login
+pub(crate) struct Authenticators(Vec<Box<dyn Authenticator>>);
+
+impl Authenticators {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self(
+            config
+                .authentication
+                .backends
+                .iter()
+                .map(|backend| -> Box<dyn Authenticator> {
+                    match backend {
+                        AuthBackend::Local => Box::new(LocalAuthenticator),
+                        AuthBackend::Ldap => Box::new(LdapAuthenticator::new(
+                            LdapDirectory::new(&config.ldap),
+                            &config.ldap.groups,
+                        )),
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// every failure is [`ApiError::User`], whichever backend it came from
+    pub(crate) fn authenticate(
+        &self,
+        storage: &web::Data<StorageState>,
+        login: &str,
+        password: &str,
+    ) -> Result<User, ApiError> {
+        for authenticator in &self.0 {
+            match authenticator.authenticate(storage, login, password)? {
+                Verdict::Accepted(user) => return Ok(user),
+                Verdict::Rejected => return Err(ApiError::User),
+                Verdict::Unknown => {}
+            }
+        }
+        Err(ApiError::User)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::models::group_model::{Group, NewGroup};
+    use crate::models::user_model::NewUser;
+    use crate::test_support;
+    use std::sync::Mutex;
+
+    const PASSWORD: &str = "Alic3-pass-w0rd!xyz";
+
+    struct DirectoryUser {
+        password: String,
+        email: Option<String>,
+        /// group dns
+        groups: Vec<String>,
+    }
+
+    /// an ldap stand-in: users by login, or a server that can't be reached
+    struct MemoryDirectory {
+        reachable: bool,
+        users: Mutex<BTreeMap<String, DirectoryUser>>,
+    }
+
+    impl MemoryDirectory {
+        fn new() -> Self {
+            Self {
+                reachable: true,
+                users: Mutex::new(BTreeMap::new()),
+            }
+        }
+
+        fn unreachable() -> Self {
+            Self {
+                reachable: false,
+                ..Self::new()
+            }
+        }
+
+        fn with_user(self, login: &str, email: Option<&str>, groups: &[&str]) -> Self {
+            self.set_user(login, email, groups);
+            self
+        }
+
+        fn set_user(&self, login: &str, email: Option<&str>, groups: &[&str]) {
+            self.users.lock().unwrap().insert(
+                login.to_string(),
+                DirectoryUser {
+                    password: PASSWORD.to_string(),
+                    email: email.map(str::to_string),
+                    groups: groups.iter().map(|dn| (*dn).to_string()).collect(),
+                },
+            );
+        }
+    }
+
+    impl Directory for MemoryDirectory {
+        fn bind(&self, login: &str, password: &str) -> Result<Option<DirectoryEntry>, String> {
+            if !self.reachable {
+                return Err("connection refused".to_string());
+            }
+            Ok(self
+                .users
+                .lock()
+                .unwrap()
+                .get(login)
+                .filter(|user| user.password == password)
+                .map(|user| DirectoryEntry {
+                    email: user.email.clone(),
+                    groups: user.groups.clone(),
+                }))
+        }
+    }
+
+    fn ldap(directory: MemoryDirectory) -> LdapAuthenticator<MemoryDirectory> {
+        let groups = BTreeMap::from([
+            (
+                "cn=devs,ou=groups,dc=example,dc=com".to_string(),
+                "devs".to_string(),
+            ),
+            (
+                "cn=ops,ou=groups,dc=example,dc=com".to_string(),
+                "ops".to_string(),
+            ),
+        ]);
+        LdapAuthenticator::new(directory, &groups)
+    }
+
+    fn local_user(storage: &web::Data<StorageState>, login: &str) -> User {
+        let mut db = try_get_connection(storage).unwrap();
+        let new_user = NewUser {
+            login: login.to_string(),
+            hash: PASSWORD.to_string(),
+            email: None,
+        };
+        User::create(&mut db, &config::get().passwords.policy, &new_user).unwrap()
+    }
+
+    fn group_names(storage: &web::Data<StorageState>, user: &User) -> Vec<String> {
+        let mut db = try_get_connection(storage).unwrap();
+        User::get_groups(&mut db, user)
+            .unwrap()
+            .into_iter()
+            .map(|group| group.name)
+            .collect()
+    }
+
+    #[test]
+    fn local_users_are_checked_against_their_hash() {
+        let storage = test_support::storage();
+        let bobby = local_user(&storage, "bobby");
+        assert!(matches!(
+            LocalAuthenticator.authenticate(&storage, "bobby", PASSWORD),
+            Ok(Verdict::Accepted(user)) if user.id == bobby.id
+        ));
+        assert!(matches!(
+            LocalAuthenticator.authenticate(&storage, "bobby", "wrong"),
+            Ok(Verdict::Rejected)
+        ));
+        assert!(matches!(
+            LocalAuthenticator.authenticate(&storage, "nobody", PASSWORD),
+            Ok(Verdict::Unknown)
+        ));
+    }
+
+    #[test]
+    fn bcrypt_hashes_are_replaced_at_the_next_login() {
+        let storage = test_support::storage();
+        let mut bobby = local_user(&storage, "bobby");
+        bobby.hash = bcrypt::hash(PASSWORD, 4).unwrap();
+        User::store_rehash(&mut try_get_connection(&storage).unwrap(), &bobby);
+        let stored_hash = || {
+            User::get(&mut try_get_connection(&storage).unwrap(), bobby.id)
+                .unwrap()
+                .hash
+        };
+
+        assert!(matches!(
+            LocalAuthenticator.authenticate(&storage, "bobby", "wrong"),
+            Ok(Verdict::Rejected)
+        ));
+        assert_eq!(stored_hash(), bobby.hash);
+
+        assert!(matches!(
+            LocalAuthenticator.authenticate(&storage, "bobby", PASSWORD),
+            Ok(Verdict::Accepted(user)) if user.hash.starts_with("$argon2id$")
+        ));
+        let rehashed = stored_hash();
+        assert!(rehashed.starts_with("$argon2id$"));
+        assert!(PasswordHasher::verify(PASSWORD, &rehashed));
+        assert!(!PasswordHasher::get().needs_rehash(&rehashed));
+
+        // the new hash is kept from then on
+        assert!(matches!(
+            LocalAuthenticator.authenticate(&storage, "bobby", PASSWORD),
+            Ok(Verdict::Accepted(_))
+        ));
+        assert_eq!(stored_hash(), rehashed);
+    }
+
+    #[test]
+    fn directory_users_are_provisioned_and_follow_their_groups() {
+        let storage = test_support::storage();
+        {
+            let mut db = try_get_connection(&storage).unwrap();
+            for name in ["devs", "ops"] {
+                Group::create_group(
+                    &mut db,
+                    &NewGroup {
+                        name: name.to_string(),
+                    },
+                )
+                .unwrap();
+            }
+        }
+        let authenticator = ldap(MemoryDirectory::new().with_user(
+            "alice",
+            Some("alice@example.com"),
+            &[
+                "CN=Devs,OU=Groups,DC=example,DC=com",
+                "cn=unmapped,dc=example,dc=com",
+            ],
+        ));
+
+        let Ok(Verdict::Accepted(alice)) = authenticator.authenticate(&storage, "alice", PASSWORD)
+        else {
+            panic!("directory user refused");
+        };
+        assert_eq!(alice.email.as_deref(), Some("alice@example.com"));
+        let groups = group_names(&storage, &alice);
+        assert!(groups.contains(&"devs".to_string()));
+        assert!(!groups.contains(&"ops".to_string()));
+
+        authenticator.directory.set_user(
+            "alice",
+            Some("alice@example.org"),
+            &["cn=ops,ou=groups,dc=example,dc=com"],
+        );
+        let Ok(Verdict::Accepted(again)) = authenticator.authenticate(&storage, "alice", PASSWORD)
+        else {
+            panic!("directory user refused");
+        };
+        assert_eq!(again.id, alice.id);
+        assert_eq!(again.email.as_deref(), Some("alice@example.org"));
+        let groups = group_names(&storage, &again);
+        assert!(!groups.contains(&"devs".to_string()));
+        assert!(groups.contains(&"ops".to_string()));
+    }
+
+    #[test]
+    fn directory_refusals_are_left_to_the_next_backend() {
+        let storage = test_support::storage();
+        let authenticator = ldap(MemoryDirectory::new().with_user("alice", None, &[]));
+        assert!(matches!(
+            authenticator.authenticate(&storage, "alice", "wrong"),
+            Ok(Verdict::Unknown)
+        ));
+        // no anonymous bind
+        assert!(matches!(
+            authenticator.authenticate(&storage, "alice", ""),
+            Ok(Verdict::Unknown)
+        ));
+        let authenticators =
+            Authenticators(vec![Box::new(authenticator), Box::new(LocalAuthenticator)]);
+        assert!(matches!(
+            authenticators.authenticate(&storage, "alice", "wrong"),
+            Err(ApiError::User)
+        ));
+    }
+
+    #[test]
+    fn unreachable_directory_leaves_local_users_able_to_log_in() {
+        let storage = test_support::storage();
+        let bobby = local_user(&storage, "bobby");
+        let authenticators = Authenticators(vec![
+            Box::new(ldap(MemoryDirectory::unreachable())),
+            Box::new(LocalAuthenticator),
+        ]);
+        assert!(matches!(
+            authenticators.authenticate(&storage, "bobby", PASSWORD),
+            Ok(user) if user.id == bobby.id
+        ));
+    }
+
+    #[test]
+    fn directory_cant_take_over_a_local_login() {
+        let storage = test_support::storage();
+        local_user(&storage, "bobby");
+        let authenticator = ldap(MemoryDirectory::new().with_user("bobby", None, &[]));
+        assert!(matches!(
+            authenticator.authenticate(&storage, "bobby", PASSWORD),
+            Ok(Verdict::Rejected)
+        ));
+    }
+}
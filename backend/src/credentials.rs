@@ -0,0 +1,87 @@
+use crate::api_error::ApiError;
+use crate::session_cookie::SessionCookie;
+use actix_web::cookie::Cookie;
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CredentialSource {
+    Header,
+    Cookie,
+}
+
+/// raw token sent by the caller, either as `Authorization: Bearer <token>` or as the access
+/// token cookie
+#[derive(Debug, Clone)]
+pub(crate) struct Credentials {
+    pub(crate) token: String,
+    pub(crate) source: CredentialSource,
+}
+
+impl Credentials {
+    /// the `Authorization` header takes precedence over the cookie. a present but malformed
+    /// header is rejected instead of silently falling back to the cookie
+    pub(crate) fn from_request(req: &HttpRequest) -> Result<Option<Credentials>, ApiError> {
+        if let Some(raw_header) = req.headers().get(AUTHORIZATION) {
+            let Ok(header) = raw_header.to_str() else {
+                return Err(ApiError::Jwt);
+            };
+            return Self::from_authorization(header).map(Some);
+        }
+        Ok(SessionCookie::Access.read(req).map(|token| Credentials {
+            token,
+            source: CredentialSource::Cookie,
+        }))
+    }
+
+    /// same as `from_request`, for requests rauth is only told the headers of
+    pub(crate) fn from_headers(
+        authorization: Option<&str>,
+        cookie: Option<&str>,
+    ) -> Result<Option<Credentials>, ApiError> {
+        if let Some(header) = authorization {
+            return Self::from_authorization(header).map(Some);
+        }
+        Ok(cookie
+            .and_then(|cookie| {
+                cookie
+                    .split(';')
+                    .filter_map(|pair| Cookie::parse(pair.trim()).ok())
+                    .find(|cookie| cookie.name() == SessionCookie::Access.name())
+            })
+            .map(|cookie| Credentials {
+                token: cookie.value().to_string(),
+                source: CredentialSource::Cookie,
+            }))
+    }
+
+    /// the scheme is case insensitive, as for every http authentication scheme
+    fn from_authorization(header: &str) -> Result<Credentials, ApiError> {
+        match header.trim_start().split_once(' ') {
+            Some((scheme, token))
+                if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() =>
+            {
+                Ok(Credentials {
+                    token: token.trim().to_string(),
+                    source: CredentialSource::Header,
+                })
+            }
+            _ => Err(ApiError::Jwt),
+        }
+    }
+}
+
+impl FromRequest for Credentials {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match Credentials::from_request(req) {
+            Ok(Some(credentials)) => Ok(credentials),
+            Ok(None) => Err(ApiError::Jwt),
+            Err(e) => Err(e),
+        })
+    }
+}
@@ -0,0 +1,737 @@
+use crate::access::{self, Access, IDENTITY_HEADER_PREFIX};
+use crate::api_error::ApiError;
+use crate::config;
+use crate::credentials::{CredentialSource, Credentials};
+use crate::key_ring::KeyRing;
+use crate::models::jwt_model::Claims;
+use crate::session_cookie::SessionCookie;
+use crate::StorageState;
+use actix_web::web;
+use log::{error, info};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::ToSocketAddrs;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Code, Status};
+use url::Url;
+
+/// `google.rpc.Code` of the answers
+const OK: i32 = 0;
+const PERMISSION_DENIED: i32 = 7;
+const UNAUTHENTICATED: i32 = 16;
+
+// the parts of envoy's `envoy.service.auth.v3` messages rauth reads or writes, with the tags
+// of envoy's protos. fields left out are skipped when decoding
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct CheckRequest {
+    #[prost(message, optional, tag = "1")]
+    attributes: Option<AttributeContext>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct AttributeContext {
+    #[prost(message, optional, tag = "1")]
+    source: Option<Peer>,
+    #[prost(message, optional, tag = "4")]
+    request: Option<RequestAttributes>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Peer {
+    #[prost(message, optional, tag = "1")]
+    address: Option<Address>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Address {
+    #[prost(message, optional, tag = "1")]
+    socket_address: Option<SocketAddress>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SocketAddress {
+    #[prost(string, tag = "2")]
+    address: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RequestAttributes {
+    #[prost(message, optional, tag = "2")]
+    http: Option<HttpRequest>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HttpRequest {
+    #[prost(string, tag = "2")]
+    method: String,
+    /// lowercased names
+    #[prost(map = "string, string", tag = "3")]
+    headers: HashMap<String, String>,
+    /// with the query
+    #[prost(string, tag = "4")]
+    path: String,
+    #[prost(string, tag = "5")]
+    host: String,
+    #[prost(string, tag = "6")]
+    scheme: String,
+    /// instead of `headers` when envoy is set to `encode_raw_headers`
+    #[prost(message, optional, tag = "13")]
+    header_map: Option<HeaderMap>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HeaderMap {
+    #[prost(message, repeated, tag = "1")]
+    headers: Vec<HeaderValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HeaderValue {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(string, tag = "2")]
+    value: String,
+    #[prost(bytes = "vec", tag = "3")]
+    raw_value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct CheckResponse {
+    #[prost(message, optional, tag = "1")]
+    status: Option<RpcStatus>,
+    #[prost(oneof = "HttpResponse", tags = "2, 3")]
+    http_response: Option<HttpResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum HttpResponse {
+    #[prost(message, tag = "2")]
+    Denied(DeniedHttpResponse),
+    #[prost(message, tag = "3")]
+    Ok(OkHttpResponse),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct DeniedHttpResponse {
+    #[prost(message, optional, tag = "1")]
+    status: Option<HttpStatus>,
+    #[prost(message, repeated, tag = "2")]
+    headers: Vec<HeaderValueOption>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HttpStatus {
+    /// `envoy.type.v3.StatusCode`, whose values are the http ones
+    #[prost(int32, tag = "1")]
+    code: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct OkHttpResponse {
+    /// added to the request sent upstream
+    #[prost(message, repeated, tag = "2")]
+    headers: Vec<HeaderValueOption>,
+    #[prost(string, repeated, tag = "5")]
+    headers_to_remove: Vec<String>,
+    /// added to the response sent to the client
+    #[prost(message, repeated, tag = "6")]
+    response_headers_to_add: Vec<HeaderValueOption>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HeaderValueOption {
+    #[prost(message, optional, tag = "1")]
+    header: Option<HeaderValue>,
+    #[prost(message, optional, tag = "2")]
+    append: Option<BoolValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct BoolValue {
+    #[prost(bool, tag = "1")]
+    value: bool,
+}
+
+impl HeaderValueOption {
+    /// replaces the header when there already is one
+    fn set(key: &str, value: &str) -> Self {
+        Self {
+            header: Some(HeaderValue {
+                key: key.to_string(),
+                value: value.to_string(),
+                raw_value: Vec::new(),
+            }),
+            append: Some(BoolValue { value: false }),
+        }
+    }
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.headers.get(name) {
+            return Some(value.clone());
+        }
+        self.header_map.as_ref().and_then(|map| {
+            map.headers
+                .iter()
+                .find(|header| header.key.eq_ignore_ascii_case(name))
+                .map(|header| {
+                    if header.raw_value.is_empty() {
+                        header.value.clone()
+                    } else {
+                        String::from_utf8_lossy(&header.raw_value).into_owned()
+                    }
+                })
+        })
+    }
+
+    fn header_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.headers.keys().cloned().collect();
+        if let Some(map) = &self.header_map {
+            names.extend(map.headers.iter().map(|header| header.key.to_lowercase()));
+        }
+        names
+    }
+
+    /// the page asked for. envoy leaves `scheme` empty behind some listeners, the
+    /// `x-forwarded-proto` it sets then tells
+    fn url(&self) -> Option<Url> {
+        let scheme = match self.scheme.as_str() {
+            "" => self
+                .header("x-forwarded-proto")
+                .unwrap_or_else(|| "http".to_string()),
+            scheme => scheme.to_string(),
+        };
+        let host = match self.host.as_str() {
+            "" => self.header(":authority")?,
+            host => host.to_string(),
+        };
+        let path = if self.path.is_empty() {
+            "/"
+        } else {
+            &self.path
+        };
+        Url::parse(&format!("{scheme}://{host}{path}")).ok()
+    }
+}
+
+/// the grpc listener, when one is configured. it runs alongside the http servers
+pub(crate) fn spawn(
+    storage: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+) -> std::io::Result<()> {
+    let Some(bind) = &config::get().ext_authz.bind else {
+        return Ok(());
+    };
+    let Some(address) = bind.to_socket_addrs()?.next() else {
+        return Err(std::io::Error::other(format!("can't resolve {bind}")));
+    };
+    let incoming = TcpIncoming::new(address, true, None).map_err(std::io::Error::other)?;
+    info!("starting ext_authz service, listening on: {address}");
+    let service = AuthorizationService { storage, key_ring };
+    actix_web::rt::spawn(async move {
+        if let Err(e) = Server::builder()
+            .add_service(service)
+            .serve_with_incoming(incoming)
+            .await
+        {
+            error!("ext_authz service stopped: {e:?}");
+        }
+    });
+    Ok(())
+}
+
+/// `envoy.service.auth.v3.Authorization`, only `Check` is part of it
+#[derive(Clone)]
+struct AuthorizationService {
+    storage: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+}
+
+impl AuthorizationService {
+    /// the evaluation of `has_access`, with the token from the request's headers and the page
+    /// from its host and path
+    fn check(&self, request: &CheckRequest) -> Result<CheckResponse, ApiError> {
+        let attributes = request.attributes.as_ref();
+        let Some(http) = attributes
+            .and_then(|attributes| attributes.request.as_ref())
+            .and_then(|request| request.http.as_ref())
+        else {
+            info!("ext_authz check without http request attributes");
+            return Err(ApiError::User);
+        };
+        let Some(url) = http.url() else {
+            info!("ext_authz check without a host and path making an url");
+            return Err(ApiError::User);
+        };
+        let ip = attributes
+            .and_then(|attributes| attributes.source.as_ref())
+            .and_then(|source| source.address.as_ref())
+            .and_then(|address| address.socket_address.as_ref())
+            .map(|socket| socket.address.clone())
+            .unwrap_or_default();
+        let access = Credentials::from_headers(
+            http.header("authorization").as_deref(),
+            http.header("cookie").as_deref(),
+        )
+        .map_or(Ok(Access::Unauthenticated), |credentials| {
+            access::check_credentials(&self.storage, &self.key_ring, credentials, &ip, &url)
+        })?;
+        match access {
+            Access::Granted {
+                credentials,
+                jwt,
+                reissued,
+            } => {
+                access::audit_impersonation(&self.storage, &jwt.claims, &http.method, &url, &ip)?;
+                granted(
+                    http,
+                    &credentials,
+                    &jwt.claims,
+                    reissued.then_some(&jwt.token),
+                )
+            }
+            Access::Unauthenticated => {
+                let location = access::login_url(&url)?;
+                let status = if access::accepts_html(http.header("accept").as_deref()) {
+                    302
+                } else {
+                    401
+                };
+                Ok(denied(
+                    UNAUTHENTICATED,
+                    status,
+                    vec![
+                        HeaderValueOption::set("location", location.as_str()),
+                        HeaderValueOption::set("www-authenticate", "Bearer"),
+                    ],
+                ))
+            }
+            Access::Denied => Ok(denied(PERMISSION_DENIED, 403, Vec::new())),
+        }
+    }
+}
+
+fn denied(code: i32, status: i32, headers: Vec<HeaderValueOption>) -> CheckResponse {
+    CheckResponse {
+        status: Some(RpcStatus { code }),
+        http_response: Some(HttpResponse::Denied(DeniedHttpResponse {
+            status: Some(HttpStatus { code: status }),
+            headers,
+        })),
+    }
+}
+
+/// the upstream gets the user's identity, in place of any the client made up, and none of
+/// rauth's credentials. a re-issued token goes back to the client
+fn granted(
+    http: &HttpRequest,
+    credentials: &Credentials,
+    claims: &Claims,
+    reissued: Option<&String>,
+) -> Result<CheckResponse, ApiError> {
+    let identity = access::identity_headers(claims)?;
+    let mut headers = identity
+        .iter()
+        .map(|(name, value)| {
+            HeaderValueOption::set(name.as_str(), value.to_str().unwrap_or_default())
+        })
+        .collect::<Vec<HeaderValueOption>>();
+    let mut headers_to_remove = http
+        .header_names()
+        .into_iter()
+        .filter(|name| {
+            name.starts_with(IDENTITY_HEADER_PREFIX)
+                && !identity
+                    .iter()
+                    .any(|(identity, _)| identity.as_str() == name)
+        })
+        .collect::<Vec<String>>();
+    if credentials.source == CredentialSource::Header {
+        headers_to_remove.push("authorization".to_string());
+    } else if let Some(cookies) = http.header("cookie") {
+        match access::without_session_cookies(&cookies) {
+            kept if kept.is_empty() => headers_to_remove.push("cookie".to_string()),
+            kept => headers.push(HeaderValueOption::set("cookie", &kept)),
+        }
+    }
+    let response_headers_to_add = match (reissued, credentials.source) {
+        (None, _) => Vec::new(),
+        (Some(token), CredentialSource::Header) => {
+            vec![HeaderValueOption::set("x-refresh-token", token)]
+        }
+        (Some(token), CredentialSource::Cookie) => vec![HeaderValueOption::set(
+            "set-cookie",
+            &SessionCookie::Access.build(token).to_string(),
+        )],
+    };
+    Ok(CheckResponse {
+        status: Some(RpcStatus { code: OK }),
+        http_response: Some(HttpResponse::Ok(OkHttpResponse {
+            headers,
+            headers_to_remove,
+            response_headers_to_add,
+        })),
+    })
+}
+
+impl UnaryService<CheckRequest> for AuthorizationService {
+    type Response = CheckResponse;
+    type Future = BoxFuture<tonic::Response<CheckResponse>, Status>;
+
+    fn call(&mut self, request: tonic::Request<CheckRequest>) -> Self::Future {
+        let response = self
+            .check(request.get_ref())
+            .map(tonic::Response::new)
+            .map_err(|e| match e {
+                ApiError::User => Status::invalid_argument(e.to_string()),
+                e => Status::internal(e.to_string()),
+            });
+        Box::pin(async move { response })
+    }
+}
+
+impl<B> Service<http::Request<B>> for AuthorizationService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if req.uri().path() == "/envoy.service.auth.v3.Authorization/Check" {
+            let service = self.clone();
+            return Box::pin(async move {
+                let mut grpc = Grpc::new(tonic::codec::ProstCodec::default());
+                Ok(grpc.unary(service, req).await)
+            });
+        }
+        Box::pin(async move {
+            let mut response = http::Response::new(empty_body());
+            let headers = response.headers_mut();
+            headers.insert(
+                tonic::Status::GRPC_STATUS,
+                (Code::Unimplemented as i32).into(),
+            );
+            headers.insert(
+                http::header::CONTENT_TYPE,
+                tonic::metadata::GRPC_CONTENT_TYPE,
+            );
+            Ok(response)
+        })
+    }
+}
+
+impl NamedService for AuthorizationService {
+    const NAME: &'static str = "envoy.service.auth.v3.Authorization";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::try_get_connection;
+    use crate::models::audit_log_model::{AuditEntry, AuditQuery};
+    use crate::models::domain_rule_model::{DomainRule, NewDomainRule};
+    use crate::models::group_model::Group;
+    use crate::models::jwt_model::JWTInternal;
+    use crate::models::user_model::User;
+    use crate::test_support;
+
+    /// envoy asking about a page of app.example.com, from 10.1.2.3
+    fn check_request(method: &str, path: &str, headers: &[(&str, &str)]) -> CheckRequest {
+        CheckRequest {
+            attributes: Some(AttributeContext {
+                source: Some(Peer {
+                    address: Some(Address {
+                        socket_address: Some(SocketAddress {
+                            address: "10.1.2.3".to_string(),
+                        }),
+                    }),
+                }),
+                request: Some(RequestAttributes {
+                    http: Some(HttpRequest {
+                        method: method.to_string(),
+                        headers: headers
+                            .iter()
+                            .map(|(name, value)| ((*name).to_string(), (*value).to_string()))
+                            .collect(),
+                        path: path.to_string(),
+                        host: "app.example.com".to_string(),
+                        scheme: "https".to_string(),
+                        header_map: None,
+                    }),
+                }),
+            }),
+        }
+    }
+
+    fn by_name(headers: &[HeaderValueOption]) -> HashMap<String, String> {
+        headers
+            .iter()
+            .filter_map(|option| option.header.as_ref())
+            .map(|header| (header.key.clone(), header.value.clone()))
+            .collect()
+    }
+
+    /// what envoy is told to do with a request let through
+    fn let_through(response: &CheckResponse) -> &OkHttpResponse {
+        assert_eq!(response.status, Some(RpcStatus { code: OK }));
+        let Some(HttpResponse::Ok(ok)) = &response.http_response else {
+            panic!("request not let through: {response:?}");
+        };
+        ok
+    }
+
+    /// the headers envoy is told to set upstream, by name
+    fn upstream_headers(response: &CheckResponse) -> HashMap<String, String> {
+        by_name(&let_through(response).headers)
+    }
+
+    /// the grpc code, and the http status and headers envoy answers the client with
+    fn denial(response: &CheckResponse) -> (i32, i32, HashMap<String, String>) {
+        let Some(HttpResponse::Denied(denied)) = &response.http_response else {
+            panic!("request let through: {response:?}");
+        };
+        (
+            response.status.as_ref().unwrap().code,
+            denied.status.as_ref().unwrap().code,
+            by_name(&denied.headers),
+        )
+    }
+
+    /// a rule letting the public group into app.example.com, a user of the group and one of
+    /// no group
+    fn alice_and_mallory(
+        storage: &web::Data<StorageState>,
+        key_ring: &KeyRing,
+    ) -> (JWTInternal, JWTInternal) {
+        let mut db = try_get_connection(storage).unwrap();
+        let public = Group::get(&mut db, 1).unwrap();
+        DomainRule::create(
+            &mut db,
+            &NewDomainRule {
+                domain: "app.example.com".to_string(),
+                group_id: public.id,
+            },
+        )
+        .unwrap();
+        let alice = test_support::user(&mut db, "alice", &[&public]);
+        let mallory = test_support::user(&mut db, "mallory", &[]);
+        (
+            test_support::session(&mut db, key_ring, &alice, None),
+            test_support::session(&mut db, key_ring, &mallory, None),
+        )
+    }
+
+    #[test]
+    fn refusals_tell_envoy_what_to_answer() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (_, mallory) = alice_and_mallory(&storage, &key_ring);
+        let service = AuthorizationService { storage, key_ring };
+        let check = |headers: &[(&str, &str)]| {
+            denial(
+                &service
+                    .check(&check_request("GET", "/page", headers))
+                    .unwrap(),
+            )
+        };
+
+        let (code, status, headers) = check(&[]);
+        assert_eq!((code, status), (UNAUTHENTICATED, 401));
+        let location = Url::parse(&headers["location"]).unwrap();
+        assert!(location.as_str().starts_with(&config::get().oidc.login_url));
+        assert!(location
+            .query_pairs()
+            .any(|(name, value)| name == "return_to" && value == "https://app.example.com/page"));
+        assert_eq!(headers["www-authenticate"], "Bearer");
+        // browsers are sent to log in
+        let (code, status, headers) = check(&[("accept", "text/html")]);
+        assert_eq!((code, status), (UNAUTHENTICATED, 302));
+        assert_eq!(Url::parse(&headers["location"]).unwrap(), location);
+        let (code, status, _) = check(&[("authorization", "Bearer not-a-token")]);
+        assert_eq!((code, status), (UNAUTHENTICATED, 401));
+
+        let (code, status, headers) =
+            check(&[("authorization", &format!("Bearer {}", mallory.token))]);
+        assert_eq!((code, status), (PERMISSION_DENIED, 403));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn upstreams_get_the_identity_and_none_of_the_credentials() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (alice, _) = alice_and_mallory(&storage, &key_ring);
+        let service = AuthorizationService { storage, key_ring };
+        let access = SessionCookie::Access.name();
+
+        let response = service
+            .check(&check_request(
+                "GET",
+                "/page",
+                &[
+                    ("authorization", &format!("Bearer {}", alice.token)),
+                    ("remote-user", "root"),
+                    ("remote-role", "root"),
+                    ("cookie", "theme=dark"),
+                ],
+            ))
+            .unwrap();
+        let ok = let_through(&response);
+        let headers = upstream_headers(&response);
+        assert_eq!(headers["remote-user"], "alice");
+        assert_eq!(headers["remote-email"], "alice@example.com");
+        assert_eq!(headers["remote-groups"], "public");
+        assert!(!headers.contains_key("cookie"));
+        let mut removed = ok.headers_to_remove.clone();
+        removed.sort();
+        assert_eq!(removed, ["authorization", "remote-role"]);
+        assert!(ok.response_headers_to_add.is_empty());
+
+        // the cookie carrying the token is dropped, the app's own are kept
+        let response = service
+            .check(&check_request(
+                "GET",
+                "/page",
+                &[("cookie", &format!("{access}={}; theme=dark", alice.token))],
+            ))
+            .unwrap();
+        assert_eq!(upstream_headers(&response)["cookie"], "theme=dark");
+        assert!(let_through(&response).headers_to_remove.is_empty());
+        let response = service
+            .check(&check_request(
+                "GET",
+                "/page",
+                &[("cookie", &format!("{access}={}", alice.token))],
+            ))
+            .unwrap();
+        assert!(!upstream_headers(&response).contains_key("cookie"));
+        assert_eq!(let_through(&response).headers_to_remove, ["cookie"]);
+    }
+
+    #[test]
+    fn reissued_tokens_go_back_the_way_they_came() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (alice, _) = alice_and_mallory(&storage, &key_ring);
+        let expiring = || {
+            let mut db = try_get_connection(&storage).unwrap();
+            let expiring = JWTInternal::from(
+                &Claims {
+                    exp: chrono::Utc::now().timestamp() + config::get().tokens.refresh_window / 2,
+                    jti: uuid::Uuid::new_v4().to_string(),
+                    ..alice.claims.clone()
+                },
+                &key_ring,
+            )
+            .unwrap();
+            JWTInternal::register(&mut db, &expiring).unwrap();
+            expiring.token
+        };
+        let service = AuthorizationService {
+            storage: storage.clone(),
+            key_ring: key_ring.clone(),
+        };
+        let handed_back = |headers: &[(&str, &str)]| {
+            let response = service
+                .check(&check_request("GET", "/page", headers))
+                .unwrap();
+            by_name(&let_through(&response).response_headers_to_add)
+        };
+
+        assert!(handed_back(&[("authorization", &format!("Bearer {}", alice.token))]).is_empty());
+        let headers = handed_back(&[("authorization", &format!("Bearer {}", expiring()))]);
+        let reissued = &headers["x-refresh-token"];
+        assert_eq!(
+            key_ring.decode::<Claims>(reissued).unwrap().user.login,
+            "alice"
+        );
+        assert!(!headers.contains_key("set-cookie"));
+
+        let access = SessionCookie::Access.name();
+        let headers = handed_back(&[("cookie", &format!("{access}={}", expiring()))]);
+        assert!(headers["set-cookie"].starts_with(&format!("{access}=")));
+        assert!(!headers.contains_key("x-refresh-token"));
+    }
+
+    #[test]
+    fn audits_and_marks_impersonated_requests() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (alice, token) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let public = Group::get(&mut db, 1).unwrap();
+            DomainRule::create(
+                &mut db,
+                &NewDomainRule {
+                    domain: "app.example.com".to_string(),
+                    group_id: public.id,
+                },
+            )
+            .unwrap();
+            let root = User::get(&mut db, 1).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[&public]);
+            let token = JWTInternal::impersonate(
+                &mut db,
+                &root,
+                &alice,
+                &key_ring,
+                &test_support::client(),
+            )
+            .unwrap()
+            .token;
+            (alice, token)
+        };
+        let service = AuthorizationService {
+            storage: storage.clone(),
+            key_ring,
+        };
+
+        let response = service
+            .check(&check_request(
+                "PUT",
+                "/page",
+                &[
+                    ("authorization", &format!("Bearer {token}")),
+                    ("remote-impersonated-by", "nobody"),
+                ],
+            ))
+            .unwrap();
+        let headers = upstream_headers(&response);
+        assert_eq!(headers["remote-user"], "alice");
+        assert_eq!(headers["remote-impersonated-by"], "root");
+
+        let mut db = try_get_connection(&storage).unwrap();
+        let entries = AuditEntry::list(
+            &mut db,
+            &AuditQuery {
+                actor: Some(1),
+                user: Some(alice.id),
+                before: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "impersonated_request");
+        assert_eq!(entries[0].detail, "PUT https://app.example.com/page");
+        assert_eq!(entries[0].ip, "10.1.2.3");
+    }
+}
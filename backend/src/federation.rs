@@ -0,0 +1,336 @@
+use crate::api_error::ApiError;
+use crate::config::{self, FederationProvider};
+use crate::models::federation_state_model::FederationState;
+use awc::Client;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use url::{form_urlencoded, Url};
+
+/// time a provider has to answer each request
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the part of a provider's discovery document rauth relies on
+#[derive(Deserialize)]
+pub(crate) struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct ProviderTokenResponse {
+    id_token: String,
+}
+
+/// who the provider says logged in, read from its id token
+pub(crate) struct ExternalIdentity {
+    pub(crate) subject: String,
+    /// value of the provider's `login_claim`
+    pub(crate) login: Option<String>,
+    /// left out when the provider says it isn't verified
+    pub(crate) email: Option<String>,
+    /// values of the provider's `groups_claim`
+    pub(crate) groups: Vec<String>,
+}
+
+fn failed(provider: &FederationProvider, e: impl std::fmt::Debug) -> ApiError {
+    error!("federation with {}: {e:?}", provider.id);
+    ApiError::Federation
+}
+
+/// where the provider sends users back to
+pub(crate) fn redirect_uri(provider: &FederationProvider) -> String {
+    format!(
+        "{}/auth/federation/{}/callback/",
+        config::get().oidc.issuer,
+        provider.id
+    )
+}
+
+async fn get_json<T: DeserializeOwned>(
+    provider: &FederationProvider,
+    url: &str,
+) -> Result<T, ApiError> {
+    let mut response = Client::builder()
+        .timeout(PROVIDER_TIMEOUT)
+        .finish()
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| failed(provider, e))?;
+    if !response.status().is_success() {
+        return Err(failed(provider, response.status()));
+    }
+    response.json().await.map_err(|e| failed(provider, e))
+}
+
+/// reads the discovery document, its issuer has to be the configured one
+pub(crate) async fn discover(provider: &FederationProvider) -> Result<ProviderMetadata, ApiError> {
+    let metadata: ProviderMetadata = get_json(
+        provider,
+        &format!(
+            "{}/.well-known/openid-configuration",
+            provider.issuer.trim_end_matches('/')
+        ),
+    )
+    .await?;
+    if metadata.issuer != provider.issuer {
+        return Err(failed(
+            provider,
+            format!("discovery names {} as issuer", metadata.issuer),
+        ));
+    }
+    Ok(metadata)
+}
+
+/// where to send the user to log in at the provider, with the state and the s256 pkce
+/// challenge of the pending login
+pub(crate) fn authorization_url(
+    provider: &FederationProvider,
+    metadata: &ProviderMetadata,
+    state: &str,
+    pending: &FederationState,
+) -> Result<String, ApiError> {
+    let mut url = Url::parse(&metadata.authorization_endpoint).map_err(|e| failed(provider, e))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &redirect_uri(provider))
+        .append_pair("scope", &provider.scopes.join(" "))
+        .append_pair("state", state)
+        .append_pair("nonce", &pending.nonce)
+        .append_pair(
+            "code_challenge",
+            &URL_SAFE_NO_PAD.encode(Sha256::digest(pending.code_verifier.as_bytes())),
+        )
+        .append_pair("code_challenge_method", "S256");
+    Ok(url.into())
+}
+
+/// exchanges the code at the provider and checks the id token it returns against the
+/// provider's keys, its issuer, the client id and the nonce of the pending login
+pub(crate) async fn exchange(
+    provider: &FederationProvider,
+    metadata: &ProviderMetadata,
+    code: &str,
+    pending: &FederationState,
+) -> Result<ExternalIdentity, ApiError> {
+    // client_secret_basic wants both parts form encoded before they are joined
+    let encode =
+        |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+    let mut response = Client::builder()
+        .timeout(PROVIDER_TIMEOUT)
+        .finish()
+        .post(&metadata.token_endpoint)
+        .basic_auth(encode(&provider.client_id), encode(&provider.client_secret))
+        .send_form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &redirect_uri(provider)),
+            ("code_verifier", &pending.code_verifier),
+        ])
+        .await
+        .map_err(|e| failed(provider, e))?;
+    if !response.status().is_success() {
+        let body = response.body().await.unwrap_or_default();
+        return Err(failed(
+            provider,
+            format!("token endpoint answered {}: {body:?}", response.status()),
+        ));
+    }
+    let tokens: ProviderTokenResponse = response.json().await.map_err(|e| failed(provider, e))?;
+
+    let header = decode_header(&tokens.id_token).map_err(|e| failed(provider, e))?;
+    // the client secret is no key for an id token, only signatures from the provider's keys
+    // are accepted
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err(failed(provider, "symmetric id token"));
+    }
+    let jwks: JwkSet = get_json(provider, &metadata.jwks_uri).await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| failed(provider, format!("no key for {:?}", header.kid)))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| failed(provider, e))?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<Map<String, Value>>(&tokens.id_token, &key, &validation)
+        .map_err(|e| failed(provider, e))?
+        .claims;
+
+    if claims.get("nonce").and_then(Value::as_str) != Some(pending.nonce.as_str()) {
+        return Err(failed(provider, "nonce mismatch"));
+    }
+    let text = |claim: &str| {
+        claims
+            .get(claim)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    let subject = text("sub").ok_or_else(|| failed(provider, "sub isn't a string"))?;
+    let email = text("email")
+        .filter(|_| claims.get("email_verified").and_then(Value::as_bool) != Some(false));
+    let groups = match claims.get(&provider.groups_claim) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        Some(Value::String(value)) => vec![value.clone()],
+        _ => Vec::new(),
+    };
+    Ok(ExternalIdentity {
+        subject,
+        login: text(&provider.login_claim),
+        email,
+        groups,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::try_get_connection;
+    use crate::test_support;
+    use crate::test_support::idp::{carol, idp, serve, MockIdp, CLIENT_ID, CODE};
+    use std::collections::HashMap;
+
+    fn pending_login() -> FederationState {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        FederationState::create(&mut db, "idp", None, None)
+            .unwrap()
+            .1
+    }
+
+    /// discovery then the exchange, as the callback does it
+    async fn log_in(
+        provider: &FederationProvider,
+        pending: &FederationState,
+    ) -> Result<ExternalIdentity, ApiError> {
+        let metadata = discover(provider).await?;
+        exchange(provider, &metadata, CODE, pending).await
+    }
+
+    #[actix_web::test]
+    async fn logs_in_through_discovery_and_the_code_exchange() {
+        let pending = pending_login();
+        let provider = serve(idp(&pending, carol(&pending)));
+
+        let metadata = discover(&provider).await.unwrap();
+        let url =
+            Url::parse(&authorization_url(&provider, &metadata, "the-state", &pending).unwrap())
+                .unwrap();
+        assert_eq!(
+            url.as_str().split('?').next(),
+            Some(format!("{}/authorize", provider.issuer).as_str())
+        );
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["redirect_uri"], redirect_uri(&provider));
+        assert_eq!(query["state"], "the-state");
+        assert_eq!(query["nonce"], pending.nonce);
+        assert_eq!(query["scope"], "openid profile email");
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert_eq!(
+            query["code_challenge"],
+            URL_SAFE_NO_PAD.encode(Sha256::digest(pending.code_verifier.as_bytes()))
+        );
+
+        let identity = exchange(&provider, &metadata, CODE, &pending)
+            .await
+            .unwrap();
+        assert_eq!(identity.subject, "42");
+        assert_eq!(identity.login.as_deref(), Some("carol"));
+        assert_eq!(identity.email.as_deref(), Some("carol@example.com"));
+        assert_eq!(identity.groups, ["staff", "admins"]);
+    }
+
+    #[actix_web::test]
+    async fn unverified_emails_are_left_out() {
+        let pending = pending_login();
+        let mut claims = carol(&pending);
+        claims["email_verified"] = Value::Bool(false);
+        claims["groups"] = Value::from("staff");
+        let identity = log_in(&serve(idp(&pending, claims)), &pending)
+            .await
+            .unwrap();
+        assert_eq!(identity.email, None);
+        assert_eq!(identity.groups, ["staff"]);
+    }
+
+    #[actix_web::test]
+    async fn discovery_naming_another_issuer_is_refused() {
+        let pending = pending_login();
+        let provider = serve(MockIdp {
+            discovered_issuer: Some("https://elsewhere.example.com".to_string()),
+            ..idp(&pending, carol(&pending))
+        });
+        assert!(matches!(
+            discover(&provider).await,
+            Err(ApiError::Federation)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn id_tokens_that_dont_match_the_login_are_refused() {
+        let pending = pending_login();
+        let tampered = [
+            ("aud", Value::from("another-client")),
+            ("iss", Value::from("https://elsewhere.example.com")),
+            ("nonce", Value::from("another-nonce")),
+            ("exp", Value::from(chrono::Utc::now().timestamp() - 3600)),
+        ];
+        for (claim, value) in tampered {
+            let mut claims = carol(&pending);
+            claims[claim] = value;
+            let provider = serve(idp(&pending, claims));
+            assert!(
+                matches!(log_in(&provider, &pending).await, Err(ApiError::Federation)),
+                "id token with another {claim} accepted"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn id_tokens_signed_with_the_client_secret_are_refused() {
+        let pending = pending_login();
+        let provider = serve(MockIdp {
+            symmetric: true,
+            ..idp(&pending, carol(&pending))
+        });
+        assert!(matches!(
+            log_in(&provider, &pending).await,
+            Err(ApiError::Federation)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn a_code_the_provider_refuses_fails_the_login() {
+        let pending = pending_login();
+        let provider = serve(MockIdp {
+            code_verifier: "another-verifier".to_string(),
+            ..idp(&pending, carol(&pending))
+        });
+        assert!(matches!(
+            log_in(&provider, &pending).await,
+            Err(ApiError::Federation)
+        ));
+    }
+}
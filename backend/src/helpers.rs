@@ -1,15 +1,75 @@
 use crate::api_error::ApiError;
+use crate::config;
 use crate::StorageState;
-use actix_web::web;
+use actix_web::{web, HttpRequest};
 use diesel::SqliteConnection;
 use log::error;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
 use std::sync::MutexGuard;
 
 pub(crate) fn try_get_connection(
     db: &web::Data<StorageState>,
-) -> Result<MutexGuard<SqliteConnection>, ApiError> {
+) -> Result<MutexGuard<'_, SqliteConnection>, ApiError> {
     db.db.lock().map_err(|e| {
         error!("2{e:?}");
         ApiError::Internal
     })
 }
+
+/// random alphanumeric string, used for one-time secrets handed to users
+pub(crate) fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// hex encoded sha256 digest, used to store high-entropy secrets that don't need a slow hash
+pub(crate) fn sha256_hex(value: &str) -> String {
+    format!("{:x}", Sha256::digest(value.as_bytes()))
+}
+
+/// address of the client the request came from. behind trusted proxies it is the nearest
+/// address of `X-Forwarded-For` none of them has, the peer's otherwise
+pub(crate) fn client_ip(req: &HttpRequest) -> String {
+    let Some(mut client) = req.peer_addr().map(|addr| addr.ip().to_canonical()) else {
+        return String::new();
+    };
+    let config = config::get();
+    let mut forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<&str>>()
+        .into_iter()
+        .rev();
+    while config.trusts_proxy(client) {
+        match forwarded.next().and_then(|hop| hop.parse::<IpAddr>().ok()) {
+            Some(hop) => client = hop.to_canonical(),
+            None => break,
+        }
+    }
+    client.to_string()
+}
+
+/// text made safe to put in html, between tags or in a quoted attribute
+pub(crate) fn escape_html(text: &str) -> String {
+    text.chars()
+        .fold(String::with_capacity(text.len()), |mut escaped, c| {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#39;"),
+                c => escaped.push(c),
+            }
+            escaped
+        })
+}
@@ -0,0 +1,36 @@
+use diesel::{Connection, SqliteConnection};
+use std::sync::Mutex;
+
+pub(crate) mod access;
+pub mod admin;
+pub(crate) mod api_error;
+pub(crate) mod authenticator;
+pub(crate) mod config;
+pub(crate) mod credentials;
+pub(crate) mod ext_authz;
+pub(crate) mod federation;
+pub(crate) mod helpers;
+pub(crate) mod key_ring;
+pub(crate) mod mailer;
+pub(crate) mod middlewares;
+pub(crate) mod models;
+pub(crate) mod password_hasher;
+pub(crate) mod password_policy;
+pub(crate) mod proxy;
+pub(crate) mod routes;
+pub(crate) mod schema;
+pub mod server;
+pub(crate) mod session_cookie;
+#[cfg(test)]
+pub(crate) mod test_support;
+
+struct StorageState {
+    db: Mutex<SqliteConnection>,
+}
+/// # Panics
+/// panics if can't connect to database
+#[must_use]
+pub fn establish_connection(database_url: &str) -> SqliteConnection {
+    SqliteConnection::establish(database_url)
+        .unwrap_or_else(|_| panic!("Error connecting to {database_url}"))
+}
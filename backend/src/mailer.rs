@@ -0,0 +1,99 @@
+use crate::api_error::ApiError;
+use crate::config::MailConfig;
+use lettre::message::Mailbox;
+use lettre::{Message, SmtpTransport, Transport};
+use log::{debug, error, info};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub(crate) struct Mail {
+    pub(crate) to: String,
+    pub(crate) subject: String,
+    pub(crate) body: String,
+}
+
+/// delivers mails to users, sending can block so it's kept off the request path
+pub(crate) trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), ApiError>;
+}
+
+/// appends mails to a file, for local testing. without one only who they were for is logged,
+/// their body carries secrets such as reset tokens and is kept to the debug log
+pub(crate) struct FileMailer {
+    path: Option<PathBuf>,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), ApiError> {
+        let rendered = format!(
+            "To: {}\nSubject: {}\n\n{}\n\n",
+            mail.to, mail.subject, mail.body
+        );
+        let Some(path) = &self.path else {
+            info!(
+                "mail to {} not sent, no transport configured: {}",
+                mail.to, mail.subject
+            );
+            debug!("unsent mail:\n{rendered}");
+            return Ok(());
+        };
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(rendered.as_bytes()))
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+}
+
+pub(crate) struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), ApiError> {
+        let to = mail.to.parse::<Mailbox>().map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&mail.subject)
+            .body(mail.body.clone())
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        self.transport.send(&message).map(|_| ()).map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })
+    }
+}
+
+/// smtp when an url is configured, otherwise mails go to the configured file or the log
+pub(crate) fn mailer_from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>, String> {
+    match &config.smtp_url {
+        Some(url) => Ok(Arc::new(SmtpMailer {
+            transport: SmtpTransport::from_url(url)
+                .map_err(|e| format!("mail.smtp_url: {e}"))?
+                .build(),
+            from: config
+                .from
+                .as_deref()
+                .unwrap_or_default()
+                .parse()
+                .map_err(|e| format!("mail.from: {e}"))?,
+        })),
+        None => Ok(Arc::new(FileMailer {
+            path: config.file.clone(),
+        })),
+    }
+}
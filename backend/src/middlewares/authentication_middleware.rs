@@ -1,11 +1,16 @@
 use crate::api_error::ApiError;
-use crate::helpers::try_get_connection;
+use crate::credentials::{CredentialSource, Credentials};
+use crate::helpers::{client_ip, try_get_connection};
+use crate::key_ring::KeyRing;
+use crate::models::api_token_model::{ApiToken, TokenScope};
 use crate::models::jwt_model::{Claims, JWTInternal};
-use crate::{KeySet, StorageState};
-use actix_web::cookie::time::Duration;
-use actix_web::cookie::Cookie;
+use crate::models::session_model::Session;
+use crate::routes::oidc_routes::USERINFO_PATH;
+use crate::session_cookie::SessionCookie;
+use crate::StorageState;
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::{web, HttpMessage};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
 use log::error;
@@ -13,6 +18,112 @@ use std::future::{ready, Ready};
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
+/// validates the request's token, re-issuing it when its family was rotated or when it is
+/// about to expire. the flag tells whether the client has to be handed the new token.
+/// api tokens are checked against `scope`, access tokens of openid connect clients are only
+/// good for `TokenScope::UserInfo`
+pub(crate) fn authenticate(
+    req: &HttpRequest,
+    scope: TokenScope,
+) -> Result<(Credentials, JWTInternal, bool), ApiError> {
+    let Some(storage) = req.app_data::<web::Data<StorageState>>() else {
+        error!("couldn't access storage");
+        return Err(ApiError::Internal);
+    };
+    let Some(key_ring) = req.app_data::<web::Data<KeyRing>>() else {
+        error!("couldn't access key ring");
+        return Err(ApiError::Internal);
+    };
+    let Some(credentials) = Credentials::from_request(req)? else {
+        return Err(ApiError::Jwt);
+    };
+    let (claims, reissue) =
+        authenticate_credentials(storage, key_ring, &credentials, scope, &client_ip(req))?;
+    Ok((credentials, claims, reissue))
+}
+
+/// the claims of a request checked for `TokenScope::Access`, `None` for visitors. they are kept
+/// in the request so extractors needing them share a single authentication
+pub(crate) fn access_claims(req: &HttpRequest) -> Result<Option<Claims>, ApiError> {
+    if let Some(AccessClaims(claims)) = req.extensions().get::<AccessClaims>() {
+        return Ok(Some(claims.clone()));
+    }
+    if Credentials::from_request(req)?.is_none() {
+        return Ok(None);
+    }
+    let (_, claims, _) = authenticate(req, TokenScope::Access)?;
+    req.extensions_mut()
+        .insert(AccessClaims(claims.claims.clone()));
+    Ok(Some(claims.claims))
+}
+
+#[derive(Clone)]
+struct AccessClaims(Claims);
+
+/// `authenticate`, for credentials that didn't come with a request of the api
+pub(crate) fn authenticate_credentials(
+    storage: &web::Data<StorageState>,
+    key_ring: &KeyRing,
+    credentials: &Credentials,
+    scope: TokenScope,
+    ip: &str,
+) -> Result<(JWTInternal, bool), ApiError> {
+    let mut db = try_get_connection(storage)?;
+    if ApiToken::is_api_token(&credentials.token) {
+        let claims = ApiToken::claims(&mut db, &credentials.token, scope)?;
+        let token = credentials.token.clone();
+        return Ok((JWTInternal { claims, token }, false));
+    }
+
+    let claims = JWTInternal::validate_jwt(&mut db, &credentials.token, key_ring)?;
+    // tokens of openid connect clients let them read the user's claims, nothing more
+    if claims.aud.is_some() && scope != TokenScope::UserInfo {
+        return Err(ApiError::Jwt);
+    }
+    let needs_refresh = JWTInternal::needs_refresh(&mut db, &claims).map_err(|e| {
+        error!("{e:?}");
+        e
+    })?;
+    let reissue = needs_refresh || JWTInternal::in_refresh_window(&mut db, &claims)?;
+    let claims = if reissue {
+        // a token re-issued ahead of its expiry stays valid, requests already sent with it by
+        // the client still go through
+        let refresh = JWTInternal::refresh(&mut db, &claims, key_ring, needs_refresh)?;
+        JWTInternal::register(&mut db, &refresh)?;
+        refresh
+    } else {
+        JWTInternal::from(&claims, key_ring)?
+    };
+    Session::touch(&mut db, &claims.claims.family, ip)?;
+    Ok((claims, reissue))
+}
+
+/// gives the client its re-issued token, the way it sent the previous one
+pub(crate) fn hand_over_token(
+    response: &mut HttpResponse,
+    credentials: &Credentials,
+    token: &str,
+) -> Result<(), ApiError> {
+    if credentials.source == CredentialSource::Header {
+        // bearer callers don't keep cookies, hand them the new token in a header
+        let refresh_header = HeaderValue::from_str(token).map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?;
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-refresh-token"), refresh_header);
+        Ok(())
+    } else {
+        response
+            .add_cookie(&SessionCookie::Access.build(token))
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+}
+
 pub struct AuthenticationMiddleware<S> {
     service: Rc<S>,
 }
@@ -34,110 +145,26 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let mut db = if let Some(storage) = req.app_data::<web::Data<StorageState>>() {
-            match try_get_connection(storage) {
-                Ok(db) => db,
-                Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-            }
+        // errors are answered here rather than returned, so outer middlewares (cors) still
+        // get to decorate the response
+        let scope = if req.path() == USERINFO_PATH {
+            TokenScope::UserInfo
+        } else if req.method().is_safe() {
+            TokenScope::Read
         } else {
-            error!("couldn't access storage");
-            return Box::pin(ready(Err(actix_web::Error::from(ApiError::Internal))));
-        };
-        let Some(key_set) = req.app_data::<web::Data<KeySet>>() else {
-            error!("couldn't access key set");
-            return Box::pin(ready(Err(actix_web::Error::from(ApiError::Internal))));
-        };
-        /*let token = match req.headers().get("Authorization") {
-            Some(raw_header) => {
-                if let Ok(token) = raw_header.to_str() {
-                    if token.starts_with("Bearer ") {
-                        &token[7..token.len()]
-                    } else {
-                        return Box::pin(ready(Err(actix_web::Error::from(ApiError::Jwt))));
-                    }
-                } else {
-                    return Box::pin(ready(Err(actix_web::Error::from(ApiError::Internal))));
-                }
-            }
-            None => return Box::pin(ready(Err(actix_web::Error::from(ApiError::Jwt)))),
-        };*/
-        let token = match req.cookie("jwt") {
-            Some(auth) => auth.value().to_string(),
-            None => {
-                return Box::pin(ready(Err(actix_web::Error::from(ApiError::Jwt))));
-            }
+            TokenScope::Write
         };
-
-        let mut refresh_cookie = false; // booooooh blatant side effect boooooooh
-
-        let claims = match JWTInternal::validate_jwt(&mut db, &token, &key_set.decoding) {
-            Ok(claims) => {
-                let needs_refresh = match JWTInternal::needs_refresh(&mut db, &claims) {
-                    Ok(needs_refresh) => {
-                        if needs_refresh {
-                            refresh_cookie = true;
-                        }
-                        needs_refresh
-                    }
-                    Err(e) => {
-                        error!("{e:?}");
-                        return Box::pin(ready(Err(actix_web::Error::from(e))));
-                    }
-                };
-                if needs_refresh {
-                    match JWTInternal::refresh(
-                        &mut db,
-                        &claims.user,
-                        &claims.jti,
-                        &key_set.encoding,
-                        true,
-                    ) {
-                        Ok(refresh) => match JWTInternal::register(&mut db, &refresh) {
-                            Ok(()) => refresh,
-                            Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-                        },
-                        Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-                    }
-                } else {
-                    match JWTInternal::from(&claims, &key_set.encoding) {
-                        Ok(token) => token,
-                        Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-                    }
-                }
-            }
-            Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
+        let (credentials, claims, refresh_cookie) = match authenticate(req.request(), scope) {
+            Ok(authenticated) => authenticated,
+            Err(e) => return Box::pin(ready(Ok(req.error_response(e)))),
         };
-        drop(db);
 
         let srv = Rc::clone(&self.service);
         async move {
             req.extensions_mut().insert::<Claims>(claims.claims);
             let mut resp: ServiceResponse = srv.call(req).await?;
             if refresh_cookie {
-                /*resp.headers_mut().insert(
-                    match HeaderName::from_str("X-Refresh-Token") {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("{e:?}");
-                            return Err(actix_web::Error::from(ApiError::Internal));
-                        }
-                    },
-                    match HeaderValue::from_str(&claims.token) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("{e:?}");
-                            return Err(actix_web::Error::from(ApiError::Internal));
-                        }
-                    },
-                );*/
-                let jwt_cookie = Cookie::build("jwt", &claims.token)
-                    .path("/")
-                    .domain(".localhost.dummy")
-                    .max_age(Duration::weeks(1))
-                    .finish();
-                if let Err(e) = resp.response_mut().add_cookie(&jwt_cookie) {
-                    return Err(actix_web::Error::from(e));
-                }
+                hand_over_token(resp.response_mut(), &credentials, &claims.token)?;
             }
             Ok(resp)
         }
@@ -167,3 +194,60 @@ where
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::test_support;
+
+    #[test]
+    fn tokens_about_to_expire_are_reissued() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (fresh, expiring) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[]);
+            let fresh = test_support::session(&mut db, &key_ring, &alice, None);
+            let expiring = JWTInternal::from(
+                &Claims {
+                    exp: chrono::Utc::now().timestamp() + config::get().tokens.refresh_window / 2,
+                    jti: uuid::Uuid::new_v4().to_string(),
+                    ..fresh.claims.clone()
+                },
+                &key_ring,
+            )
+            .unwrap();
+            JWTInternal::register(&mut db, &expiring).unwrap();
+            (fresh, expiring)
+        };
+        let authenticate = |token: &JWTInternal| {
+            let credentials = Credentials {
+                token: token.token.clone(),
+                source: CredentialSource::Header,
+            };
+            authenticate_credentials(
+                &storage,
+                &key_ring,
+                &credentials,
+                TokenScope::Access,
+                "127.0.0.1",
+            )
+            .unwrap()
+        };
+
+        let (kept, reissue) = authenticate(&fresh);
+        assert!(!reissue);
+        assert_eq!(kept.claims.jti, fresh.claims.jti);
+
+        let (reissued, reissue) = authenticate(&expiring);
+        assert!(reissue);
+        assert_eq!(reissued.claims.family, expiring.claims.family);
+        assert!(reissued.claims.exp > expiring.claims.exp);
+        // the replaced token still goes through until it expires
+        let (_, reissue) = authenticate(&expiring);
+        assert!(reissue);
+        let (_, reissue) = authenticate(&reissued);
+        assert!(!reissue);
+    }
+}
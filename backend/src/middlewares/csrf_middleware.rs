@@ -0,0 +1,198 @@
+use crate::api_error::ApiError;
+use crate::config;
+use crate::credentials::{CredentialSource, Credentials};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, COOKIE, ORIGIN};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use url::Url;
+
+const SEC_FETCH_SITE: HeaderName = HeaderName::from_static("sec-fetch-site");
+/// reverse proxies ask for access with the method of the request they hold, whatever it is.
+/// answering changes nothing
+const UNCHECKED_PATHS: [&str; 1] = ["/auth/forward/"];
+/// the steps of a login, checked without cookies too: another site logging the browser in as
+/// someone else is as bad as acting as the user
+const LOGIN_PATHS: [&str; 6] = [
+    "/auth/",
+    "/auth/mfa/",
+    "/auth/mfa/enroll/",
+    "/auth/login/",
+    "/auth/login/mfa/",
+    "/auth/login/mfa/enroll/",
+];
+
+/// whether the request may change state. requests carrying cookies are checked, whether
+/// they are authenticated by the access cookie, by the refresh cookie alone like
+/// `/auth/refresh/`, or not at all like a logout, and so are logins. a bearer token isn't sent
+/// by the browser on its own so other sites can't make use of it.
+/// the `Origin` browsers send on such requests has to be a trusted one, and without it the
+/// browser has to tell the request comes from rauth itself or from the user. a login that
+/// says neither doesn't come from a browser
+fn is_trusted(req: &ServiceRequest) -> bool {
+    let bearer = matches!(
+        Credentials::from_request(req.request()),
+        Ok(Some(Credentials {
+            source: CredentialSource::Header,
+            ..
+        }))
+    );
+    let carries_cookies = req.headers().contains_key(COOKIE);
+    if req.method().is_safe()
+        || UNCHECKED_PATHS.contains(&req.path())
+        || bearer
+        || !(carries_cookies || LOGIN_PATHS.contains(&req.path()))
+    {
+        return true;
+    }
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    match (header(ORIGIN), header(SEC_FETCH_SITE)) {
+        (Some(origin), _) => {
+            Url::parse(origin).is_ok_and(|origin| config::get().trusts_origin(&origin.origin()))
+        }
+        (None, Some(site)) => matches!(site, "same-origin" | "none"),
+        (None, None) => !carries_cookies,
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if is_trusted(&req) {
+            let srv = self.service.clone();
+            Box::pin(async move {
+                let resp = srv.call(req).await?;
+                Ok(resp)
+            })
+        } else {
+            Box::pin(ready(Ok(req.error_response(ApiError::CrossSiteRequest))))
+        }
+    }
+}
+
+/// refuses the requests other sites make the browser send with the user's cookies, for every
+/// method but the safe ones
+pub struct RequireTrustedOrigin;
+
+impl<S> Transform<S, ServiceRequest> for RequireTrustedOrigin
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn only_trusted_origins_change_state_with_cookies_or_log_in() {
+        config::init_for_tests();
+        let app = test::init_service(
+            App::new()
+                .wrap(RequireTrustedOrigin)
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |req: test::TestRequest| {
+            let app = &app;
+            async move { test::call_service(app, req.to_request()).await.status() }
+        };
+        let post = |path: &str| test::TestRequest::post().uri(path);
+        let with_cookie = |path: &str| post(path).insert_header((COOKIE, "jwt=token"));
+        let trusted = config::get().cors.allowed_origins[0].as_str();
+
+        // a trusted origin, or the browser telling the request is rauth's own
+        let req = with_cookie("/api/users/").insert_header((ORIGIN, trusted));
+        assert_eq!(status(req).await, StatusCode::OK);
+        let req = with_cookie("/api/users/").insert_header((SEC_FETCH_SITE, "same-origin"));
+        assert_eq!(status(req).await, StatusCode::OK);
+
+        // a foreign origin, whatever else the request says
+        for path in ["/api/users/", "/auth/refresh/", "/auth/logout/"] {
+            let req = with_cookie(path)
+                .insert_header((ORIGIN, "https://evil.example.com"))
+                .insert_header((SEC_FETCH_SITE, "same-origin"));
+            assert_eq!(status(req).await, StatusCode::FORBIDDEN, "{path}");
+        }
+        let req = with_cookie("/api/users/").insert_header((SEC_FETCH_SITE, "cross-site"));
+        assert_eq!(status(req).await, StatusCode::FORBIDDEN);
+
+        // cookies with no word on where the request comes from
+        assert_eq!(
+            status(with_cookie("/api/users/")).await,
+            StatusCode::FORBIDDEN
+        );
+
+        // logins are checked without cookies, but clients that aren't browsers say nothing
+        let req = post("/auth/login/").insert_header((ORIGIN, "https://evil.example.com"));
+        assert_eq!(status(req).await, StatusCode::FORBIDDEN);
+        let req = post("/auth/login/mfa/").insert_header((SEC_FETCH_SITE, "cross-site"));
+        assert_eq!(status(req).await, StatusCode::FORBIDDEN);
+        let req = post("/auth/login/").insert_header((ORIGIN, trusted));
+        assert_eq!(status(req).await, StatusCode::OK);
+        assert_eq!(status(post("/auth/")).await, StatusCode::OK);
+        let req = post("/oidc/token/").insert_header((ORIGIN, "https://evil.example.com"));
+        assert_eq!(status(req).await, StatusCode::OK);
+
+        // bearer tokens aren't sent by browsers on their own, cookies or not
+        let req = with_cookie("/api/users/")
+            .insert_header((AUTHORIZATION, "Bearer token"))
+            .insert_header((ORIGIN, "https://evil.example.com"));
+        assert_eq!(status(req).await, StatusCode::OK);
+        let req = with_cookie("/api/users/")
+            .insert_header((AUTHORIZATION, "Basic dXNlcjpwYXNz"))
+            .insert_header((ORIGIN, "https://evil.example.com"));
+        assert_eq!(status(req).await, StatusCode::FORBIDDEN);
+
+        // reverse proxies asking for access, and safe methods
+        let req = with_cookie("/auth/forward/").insert_header((ORIGIN, "https://evil.example.com"));
+        assert_eq!(status(req).await, StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/api/users/")
+            .insert_header((COOKIE, "jwt=token"))
+            .insert_header((ORIGIN, "https://evil.example.com"));
+        assert_eq!(status(req).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::credentials::Credentials;
+use crate::helpers::{client_ip, try_get_connection};
+use crate::key_ring::KeyRing;
+use crate::models::api_token_model::ApiToken;
+use crate::models::audit_log_model::{AuditAction, AuditEntry};
+use crate::models::jwt_model::{Actor, Claims, JWTInternal};
+use crate::routes::forward_auth_routes::FORWARD_AUTH_PATH;
+use crate::StorageState;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::web;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use log::error;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// the admin behind the request's token when it is a live impersonation token, after
+/// recording the request in the audit log. forward auth records the page it is asked about
+/// instead. any other token is left to the routes to judge
+fn impersonated_by(req: &ServiceRequest) -> Option<Actor> {
+    let storage = req.app_data::<web::Data<StorageState>>()?;
+    let key_ring = req.app_data::<web::Data<KeyRing>>()?;
+    let credentials = Credentials::from_request(req.request()).ok()??;
+    if ApiToken::is_api_token(&credentials.token) {
+        return None;
+    }
+    key_ring.decode::<Claims>(&credentials.token).ok()?.act?;
+    let mut db = try_get_connection(storage).ok()?;
+    let claims = JWTInternal::validate_jwt(&mut db, &credentials.token, key_ring).ok()?;
+    let actor = claims.act?;
+    if req.path() == FORWARD_AUTH_PATH {
+        return Some(actor);
+    }
+    if let Err(e) = AuditEntry::record(
+        &mut db,
+        &actor,
+        &claims.user,
+        AuditAction::ImpersonatedRequest,
+        &format!("{} {}", req.method(), req.path()),
+        &client_ip(req.request()),
+    ) {
+        error!(
+            "couldn't audit request of {} as {}: {e}",
+            actor.sub, claims.user.id
+        );
+    }
+    Some(actor)
+}
+
+pub struct ImpersonationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S> Service<ServiceRequest> for ImpersonationMiddleware<S>
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let actor = impersonated_by(&req);
+        let srv = Rc::clone(&self.service);
+        async move {
+            let result = srv.call(req).await;
+            let Some(actor) = actor else {
+                return result;
+            };
+            let value = HeaderValue::from_str(&actor.login)
+                .unwrap_or_else(|_| HeaderValue::from(actor.sub));
+            let header = HeaderName::from_static("x-impersonated-by");
+            match result {
+                Ok(mut resp) => {
+                    resp.headers_mut().insert(header, value);
+                    Ok(resp)
+                }
+                // errors are answered further out, they carry the marked response along
+                Err(e) => {
+                    let mut response = e.error_response();
+                    response.headers_mut().insert(header, value);
+                    Err(InternalError::from_response(e, response).into())
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// marks every response to an impersonation token with `X-Impersonated-By: <admin login>`,
+/// and records the request in the audit log
+pub struct MarkImpersonation;
+
+impl<S> Transform<S, ServiceRequest> for MarkImpersonation
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = ImpersonationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ImpersonationMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_error::ApiError;
+    use crate::config;
+    use crate::models::audit_log_model::AuditQuery;
+    use crate::models::session_model::ClientInfo;
+    use crate::models::user_model::{NewUser, User};
+    use crate::test_support;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::{test, App, HttpResponse};
+    use futures::future::Either;
+
+    /// a token of root acting as a fresh user
+    fn impersonation_token(storage: &web::Data<StorageState>, key_ring: &KeyRing) -> String {
+        let mut db = try_get_connection(storage).unwrap();
+        let root = User::root(&mut db).unwrap();
+        let new_user = NewUser {
+            login: "alice".to_string(),
+            hash: "Alic3-pass-w0rd!xyz".to_string(),
+            email: None,
+        };
+        let alice = User::create(&mut db, &config::get().passwords.policy, &new_user).unwrap();
+        let client = ClientInfo {
+            ip: "127.0.0.1".to_string(),
+            user_agent: String::new(),
+            oidc_client_id: None,
+            scope: None,
+        };
+        JWTInternal::impersonate(&mut db, &root, &alice, key_ring, &client)
+            .unwrap()
+            .token
+    }
+
+    #[actix_web::test]
+    async fn responses_and_errors_are_marked() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let token = impersonation_token(&storage, &key_ring);
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .wrap_fn(|req, srv| {
+                    if req.path() == "/fails/" {
+                        return Either::Left(ready(Err(ApiError::Internal.into())));
+                    }
+                    Either::Right(srv.call(req))
+                })
+                .wrap(MarkImpersonation)
+                .route("/ok/", web::get().to(HttpResponse::Ok))
+                .route("/fails/", web::get().to(HttpResponse::Ok))
+                .route(FORWARD_AUTH_PATH, web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |path: &str, token: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("/ok/", &token)).await;
+        assert_eq!(resp.headers().get("x-impersonated-by").unwrap(), "root");
+        // forward auth records the page it is asked about, not its own path
+        let resp = test::call_service(&app, request(FORWARD_AUTH_PATH, &token)).await;
+        assert_eq!(resp.headers().get("x-impersonated-by").unwrap(), "root");
+        let entries = {
+            let mut db = try_get_connection(&storage).unwrap();
+            AuditEntry::list(
+                &mut db,
+                &AuditQuery {
+                    actor: None,
+                    user: None,
+                    before: None,
+                    limit: None,
+                },
+            )
+            .unwrap()
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].detail, "GET /ok/");
+
+        let Err(e) = test::try_call_service(&app, request("/fails/", &token)).await else {
+            panic!("the error went through");
+        };
+        let resp = e.error_response();
+        assert_eq!(resp.status(), 500);
+        assert_eq!(resp.headers().get("x-impersonated-by").unwrap(), "root");
+
+        let Err(e) = test::try_call_service(&app, request("/fails/", "not-a-token")).await else {
+            panic!("the error went through");
+        };
+        assert!(e
+            .error_response()
+            .headers()
+            .get("x-impersonated-by")
+            .is_none());
+    }
+}
@@ -1,3 +1,5 @@
 pub(crate) mod authentication_middleware;
+pub(crate) mod csrf_middleware;
+pub(crate) mod impersonation_middleware;
 pub(crate) mod super_user;
 pub(crate) mod target_user_or_super_user_middleware;
@@ -8,6 +8,14 @@ use std::future::{ready, Ready};
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
+fn is_super_user(req: &ServiceRequest) -> Result<bool, ApiError> {
+    let binding = req.extensions();
+    let Some(claims) = binding.get::<Claims>() else {
+        return Err(ApiError::Jwt);
+    };
+    Role::superior_to(&claims.role, &Role::from("user")?)
+}
+
 pub struct RequireSuperUserMiddleware<S> {
     service: Rc<S>,
 }
@@ -29,29 +37,16 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let binding = req.extensions();
-        let Some(claims) = binding.get::<Claims>() else {
-            return Box::pin(ready(Err(actix_web::Error::from(ApiError::Jwt))));
-        };
-        let is_super = match Role::superior_to(
-            &claims.clone().role,
-            &match Role::from("user") {
-                Ok(r) => r,
-                Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-            },
-        ) {
-            Ok(is_super) => is_super,
-            Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-        };
-        drop(binding);
-        if is_super {
-            let srv = self.service.clone();
-            Box::pin(async move {
-                let resp = srv.call(req).await?;
-                Ok(resp)
-            })
-        } else {
-            Box::pin(ready(Err(actix_web::Error::from(ApiError::Jwt))))
+        match is_super_user(&req) {
+            Ok(true) => {
+                let srv = self.service.clone();
+                Box::pin(async move {
+                    let resp = srv.call(req).await?;
+                    Ok(resp)
+                })
+            }
+            Ok(false) => Box::pin(ready(Ok(req.error_response(ApiError::Jwt)))),
+            Err(e) => Box::pin(ready(Ok(req.error_response(e)))),
         }
     }
 }
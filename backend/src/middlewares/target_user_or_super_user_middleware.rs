@@ -10,6 +10,34 @@ use std::future::{ready, Ready};
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
+fn is_target_user_or_super_user(req: &ServiceRequest) -> Result<bool, ApiError> {
+    let binding = req.extensions();
+    let Some(claims) = binding.get::<Claims>() else {
+        return Err(ApiError::Jwt);
+    };
+    let pattern_path_split = match req.match_pattern() {
+        Some(path) => path
+            .split('/')
+            .map(ToString::to_string)
+            .collect::<Vec<String>>(),
+        None => return Err(ApiError::Internal),
+    };
+    let uri_path_split = req.path().split('/').collect::<Vec<&str>>();
+    let Some(user_pos_in_pattern) = pattern_path_split.iter().position(|elem| *elem == "{user}")
+    else {
+        return Err(ApiError::Internal);
+    };
+    let user_id = match uri_path_split.get(user_pos_in_pattern) {
+        Some(raw_user_id) => raw_user_id.parse::<i32>().map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?,
+        None => return Err(ApiError::Internal),
+    };
+    let is_super = Role::superior_to(&claims.role, &Role::from("user")?)?;
+    Ok(is_super || claims.user.id == user_id)
+}
+
 pub struct TargetUserOrSuperUserMiddleware<S> {
     service: Rc<S>,
 }
@@ -31,57 +59,18 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let binding = req.extensions();
-        let claims = match binding.get::<Claims>() {
-            Some(claims) => claims.clone(),
-            None => return Box::pin(ready(Err(actix_web::Error::from(ApiError::Jwt)))),
-        };
-        let pattern_path_split = match req.match_pattern() {
-            Some(path) => path
-                .split('/')
-                .map(ToString::to_string)
-                .collect::<Vec<String>>(),
-            None => return Box::pin(ready(Err(actix_web::Error::from(ApiError::Internal)))),
-        };
-        let uri_path_split = req.path().split('/').collect::<Vec<&str>>();
-        let Some(user_pos_in_pattern) =
-            pattern_path_split.iter().position(|elem| *elem == "{user}")
-        else {
-            return Box::pin(ready(Err(actix_web::Error::from(ApiError::Internal))));
-        };
-        let user_id = match uri_path_split.get(user_pos_in_pattern) {
-            Some(raw_user_id) => match raw_user_id.parse::<i32>() {
-                Ok(user_id) => user_id,
-                Err(e) => {
-                    error!("{e:?}");
-                    return Box::pin(ready(Err(actix_web::Error::from(ApiError::Internal))));
+        match is_target_user_or_super_user(&req) {
+            Ok(true) => {
+                let srv = self.service.clone();
+                async move {
+                    let resp = srv.call(req).await?;
+                    Ok(resp)
                 }
-            },
-            None => return Box::pin(ready(Err(actix_web::Error::from(ApiError::Internal)))),
-        };
-        let claims = claims.clone();
-        let is_super = match Role::superior_to(
-            &claims.role,
-            &match Role::from("user") {
-                Ok(r) => r,
-                Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-            },
-        ) {
-            Ok(is_super) => is_super,
-            Err(e) => return Box::pin(ready(Err(actix_web::Error::from(e)))),
-        };
-        drop(binding);
-        let is_user = claims.user.id == user_id;
-        return if is_super || is_user {
-            let srv = self.service.clone();
-            async move {
-                let resp = srv.call(req).await?;
-                Ok(resp)
+                .boxed_local()
             }
-            .boxed_local()
-        } else {
-            return Box::pin(ready(Err(actix_web::Error::from(ApiError::Jwt))));
-        };
+            Ok(false) => Box::pin(ready(Ok(req.error_response(ApiError::Jwt)))),
+            Err(e) => Box::pin(ready(Ok(req.error_response(e)))),
+        }
     }
 }
 
@@ -0,0 +1,418 @@
+use crate::api_error::ApiError;
+use crate::config;
+use crate::helpers::{random_token, sha256_hex};
+use crate::models::jwt_model::Claims;
+use crate::models::role_user_model::RoleUser;
+use crate::models::user_model::User;
+use diesel::{
+    insert_into, BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// api tokens are told apart from jwts by this prefix, which no jwt can start with
+const API_TOKEN_PREFIX: &str = "rauth_";
+const API_TOKEN_LENGTH: usize = 40;
+/// characters of the token kept in clear, the prefix included
+const DISPLAYED_PREFIX_LENGTH: usize = 12;
+/// claims resolved from an api token carry this family, it matches no session
+pub(crate) const API_TOKEN_FAMILY_PREFIX: &str = "api_token:";
+/// `last_used_at` is only written again once it is older than this
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// what an api token may be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TokenScope {
+    /// safe (`GET`, `HEAD`, `OPTIONS`) requests on the api
+    Read,
+    /// any request on the api, reading included
+    Write,
+    /// access checks, `/auth/has_access/`
+    Access,
+    /// the openid connect userinfo endpoint, the only use of access tokens issued to
+    /// clients. api tokens that can read are good for it, it can't be asked for
+    #[serde(skip)]
+    UserInfo,
+}
+
+impl TokenScope {
+    fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Write => "write",
+            TokenScope::Access => "access",
+            TokenScope::UserInfo => "userinfo",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(TokenScope::Read),
+            "write" => Some(TokenScope::Write),
+            "access" => Some(TokenScope::Access),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+struct StoredApiToken {
+    id: i32,
+    user_id: i32,
+    name: String,
+    prefix: String,
+    scopes: String,
+    created_at: i64,
+    expires_at: i64,
+    last_used_at: Option<i64>,
+}
+
+/// a named token a user created for scripts, only its hash is stored
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ApiToken {
+    pub(crate) id: i32,
+    pub(crate) user_id: i32,
+    pub(crate) name: String,
+    pub(crate) prefix: String,
+    pub(crate) scopes: Vec<TokenScope>,
+    pub(crate) created_at: i64,
+    pub(crate) expires_at: i64,
+    pub(crate) last_used_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NewApiToken {
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<TokenScope>,
+    /// in seconds, defaults to and can't exceed `tokens.api_token_max_lifetime`
+    pub(crate) expires_in: Option<i64>,
+}
+
+impl From<StoredApiToken> for ApiToken {
+    fn from(stored: StoredApiToken) -> Self {
+        Self {
+            id: stored.id,
+            user_id: stored.user_id,
+            name: stored.name,
+            prefix: stored.prefix,
+            scopes: stored
+                .scopes
+                .split(' ')
+                .filter_map(TokenScope::parse)
+                .collect(),
+            created_at: stored.created_at,
+            expires_at: stored.expires_at,
+            last_used_at: stored.last_used_at,
+        }
+    }
+}
+
+impl ApiToken {
+    pub(crate) fn is_api_token(raw_token: &str) -> bool {
+        raw_token.starts_with(API_TOKEN_PREFIX)
+    }
+
+    fn allows(&self, required: TokenScope) -> bool {
+        let required = match required {
+            TokenScope::UserInfo => TokenScope::Read,
+            required => required,
+        };
+        self.scopes.contains(&required)
+            || (required == TokenScope::Read && self.scopes.contains(&TokenScope::Write))
+    }
+
+    /// creates the token and returns it in clear along with it, it can't be shown again
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        user: &User,
+        new_token: &NewApiToken,
+    ) -> Result<(ApiToken, String), ApiError> {
+        let max_lifetime = config::get().tokens.api_token_max_lifetime;
+        let lifetime = new_token.expires_in.unwrap_or(max_lifetime);
+        if new_token.name.trim().is_empty()
+            || new_token.scopes.is_empty()
+            || !(1..=max_lifetime).contains(&lifetime)
+        {
+            return Err(ApiError::ApiToken);
+        }
+        let mut scopes: Vec<&str> = new_token
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect();
+        scopes.sort_unstable();
+        scopes.dedup();
+        let token = format!("{API_TOKEN_PREFIX}{}", random_token(API_TOKEN_LENGTH));
+        let now = chrono::Utc::now().timestamp();
+        let stored = insert_into(crate::schema::api_tokens::dsl::api_tokens)
+            .values((
+                crate::schema::api_tokens::dsl::user_id.eq(user.id),
+                crate::schema::api_tokens::dsl::name.eq(new_token.name.trim()),
+                crate::schema::api_tokens::dsl::prefix.eq(&token[..DISPLAYED_PREFIX_LENGTH]),
+                crate::schema::api_tokens::dsl::token_hash.eq(sha256_hex(&token)),
+                crate::schema::api_tokens::dsl::scopes.eq(scopes.join(" ")),
+                crate::schema::api_tokens::dsl::created_at.eq(now),
+                crate::schema::api_tokens::dsl::expires_at.eq(now + lifetime),
+            ))
+            .returning(StoredApiToken::as_returning())
+            .get_result(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok((stored.into(), token))
+    }
+
+    pub(crate) fn for_user(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<Vec<ApiToken>, ApiError> {
+        crate::schema::api_tokens::dsl::api_tokens
+            .filter(crate::schema::api_tokens::dsl::user_id.eq(user.id))
+            .order(crate::schema::api_tokens::dsl::created_at.desc())
+            .select(StoredApiToken::as_select())
+            .load(db)
+            .map(|tokens| tokens.into_iter().map(ApiToken::from).collect())
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn get(
+        db: &mut SqliteConnection,
+        user: &User,
+        token_id: i32,
+    ) -> Result<ApiToken, ApiError> {
+        crate::schema::api_tokens::dsl::api_tokens
+            .filter(crate::schema::api_tokens::dsl::id.eq(token_id))
+            .filter(crate::schema::api_tokens::dsl::user_id.eq(user.id))
+            .select(StoredApiToken::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?
+            .map(ApiToken::from)
+            .ok_or(ApiError::ApiTokenNotFound)
+    }
+
+    /// the token presented, unless it is unknown or expired
+    pub(crate) fn find_active(
+        db: &mut SqliteConnection,
+        raw_token: &str,
+    ) -> Result<Option<ApiToken>, ApiError> {
+        crate::schema::api_tokens::dsl::api_tokens
+            .filter(crate::schema::api_tokens::dsl::token_hash.eq(sha256_hex(raw_token)))
+            .filter(crate::schema::api_tokens::dsl::expires_at.ge(chrono::Utc::now().timestamp()))
+            .select(StoredApiToken::as_select())
+            .first(db)
+            .optional()
+            .map(|token| token.map(ApiToken::from))
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    /// scopes as a space separated list, the way oauth spells them
+    pub(crate) fn scope(&self) -> String {
+        self.scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    /// claims carry this as family and jti, no session has it
+    pub(crate) fn family(&self) -> String {
+        format!("{API_TOKEN_FAMILY_PREFIX}{}", self.id)
+    }
+
+    /// resolves the token to the same claims a login would give its user, as long as it
+    /// hasn't expired and has the scope asked for
+    pub(crate) fn claims(
+        db: &mut SqliteConnection,
+        raw_token: &str,
+        required: TokenScope,
+    ) -> Result<Claims, ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        let token = Self::find_active(db, raw_token)?.ok_or(ApiError::Jwt)?;
+        if !token.allows(required) {
+            return Err(ApiError::Jwt);
+        }
+        let user = User::get(db, token.user_id).map_err(|_| ApiError::Jwt)?;
+        diesel::update(crate::schema::api_tokens::dsl::api_tokens)
+            .filter(crate::schema::api_tokens::dsl::id.eq(token.id))
+            .filter(
+                crate::schema::api_tokens::dsl::last_used_at
+                    .is_null()
+                    .or(crate::schema::api_tokens::dsl::last_used_at
+                        .lt(now - LAST_USED_RESOLUTION)),
+            )
+            .set(crate::schema::api_tokens::dsl::last_used_at.eq(now))
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        let family = token.family();
+        Ok(Claims {
+            company: config::get().tokens.company.clone(),
+            exp: token.expires_at,
+            jti: family.clone(),
+            family,
+            role: RoleUser::roles_from_user(db, &user)?,
+            groups: User::get_groups(db, &user)?,
+            user,
+            act: None,
+            aud: None,
+            scope: None,
+        })
+    }
+
+    pub(crate) fn delete(&self, db: &mut SqliteConnection) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::api_tokens::dsl::api_tokens
+                .filter(crate::schema::api_tokens::dsl::id.eq(self.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::api_tokens::dsl::api_tokens
+                .filter(crate::schema::api_tokens::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::try_get_connection;
+    use crate::test_support;
+
+    fn new_token(scopes: &[TokenScope], expires_in: Option<i64>) -> NewApiToken {
+        NewApiToken {
+            name: "deploy script".to_string(),
+            scopes: scopes.to_vec(),
+            expires_in,
+        }
+    }
+
+    #[test]
+    fn scopes_bound_what_tokens_are_good_for() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let (_, read) =
+            ApiToken::create(&mut db, &alice, &new_token(&[TokenScope::Read], None)).unwrap();
+        let (_, write) =
+            ApiToken::create(&mut db, &alice, &new_token(&[TokenScope::Write], None)).unwrap();
+        let (access_token, access) =
+            ApiToken::create(&mut db, &alice, &new_token(&[TokenScope::Access], None)).unwrap();
+        assert!(ApiToken::is_api_token(&read));
+
+        for (token, granted, refused) in [
+            (
+                &read,
+                &[TokenScope::Read, TokenScope::UserInfo][..],
+                &[TokenScope::Write, TokenScope::Access][..],
+            ),
+            (
+                &write,
+                &[TokenScope::Read, TokenScope::Write, TokenScope::UserInfo][..],
+                &[TokenScope::Access][..],
+            ),
+            (
+                &access,
+                &[TokenScope::Access][..],
+                &[TokenScope::Read, TokenScope::Write, TokenScope::UserInfo][..],
+            ),
+        ] {
+            for scope in granted {
+                let claims = ApiToken::claims(&mut db, token, *scope).unwrap();
+                assert_eq!(claims.user.id, alice.id);
+            }
+            for scope in refused {
+                assert!(matches!(
+                    ApiToken::claims(&mut db, token, *scope),
+                    Err(ApiError::Jwt)
+                ));
+            }
+        }
+
+        let claims = ApiToken::claims(&mut db, &access, TokenScope::Access).unwrap();
+        assert_eq!(claims.family, access_token.family());
+        let used = ApiToken::get(&mut db, &alice, access_token.id).unwrap();
+        assert!(used.last_used_at.is_some());
+        assert_eq!(used.scope(), "access");
+    }
+
+    #[test]
+    fn tokens_are_named_scoped_and_expire() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let max_lifetime = config::get().tokens.api_token_max_lifetime;
+        for refused in [
+            NewApiToken {
+                name: " ".to_string(),
+                ..new_token(&[TokenScope::Read], None)
+            },
+            new_token(&[], None),
+            new_token(&[TokenScope::Read], Some(0)),
+            new_token(&[TokenScope::Read], Some(max_lifetime + 1)),
+        ] {
+            assert!(matches!(
+                ApiToken::create(&mut db, &alice, &refused),
+                Err(ApiError::ApiToken)
+            ));
+        }
+
+        let (token, raw_token) = ApiToken::create(
+            &mut db,
+            &alice,
+            &new_token(
+                &[TokenScope::Write, TokenScope::Read, TokenScope::Write],
+                Some(60),
+            ),
+        )
+        .unwrap();
+        assert_eq!(token.scope(), "read write");
+        assert!(ApiToken::claims(&mut db, &raw_token, TokenScope::Write).is_ok());
+        diesel::update(crate::schema::api_tokens::dsl::api_tokens)
+            .filter(crate::schema::api_tokens::dsl::id.eq(token.id))
+            .set(crate::schema::api_tokens::dsl::expires_at.eq(chrono::Utc::now().timestamp() - 1))
+            .execute(&mut *db)
+            .unwrap();
+        assert!(ApiToken::find_active(&mut db, &raw_token)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            ApiToken::claims(&mut db, &raw_token, TokenScope::Read),
+            Err(ApiError::Jwt)
+        ));
+    }
+}
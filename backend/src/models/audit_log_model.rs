@@ -0,0 +1,113 @@
+use crate::api_error::ApiError;
+use crate::models::jwt_model::Actor;
+use crate::models::user_model::User;
+use diesel::{
+    insert_into, ExpressionMethods, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper,
+    SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// entries listed when no limit is asked for
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+/// what an admin did as, or to, another user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditAction {
+    /// an impersonation token was issued
+    Impersonate,
+    /// a request was made with an impersonation token, its detail is the method and path
+    ImpersonatedRequest,
+    /// a super turned off another user's second factor, without a code of it
+    DisableTotp,
+}
+
+impl AuditAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Impersonate => "impersonate",
+            AuditAction::ImpersonatedRequest => "impersonated_request",
+            AuditAction::DisableTotp => "disable_totp",
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = crate::schema::audit_log)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct AuditEntry {
+    pub(crate) id: i32,
+    pub(crate) created_at: i64,
+    pub(crate) actor_id: i32,
+    pub(crate) actor_login: String,
+    pub(crate) user_id: i32,
+    pub(crate) user_login: String,
+    pub(crate) action: String,
+    pub(crate) detail: String,
+    pub(crate) ip: String,
+}
+
+/// newest entries first, `before` pages through older ones
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AuditQuery {
+    pub(crate) actor: Option<i32>,
+    pub(crate) user: Option<i32>,
+    /// id of the oldest entry already seen
+    pub(crate) before: Option<i32>,
+    pub(crate) limit: Option<i64>,
+}
+
+impl AuditEntry {
+    pub(crate) fn record(
+        db: &mut SqliteConnection,
+        actor: &Actor,
+        user: &User,
+        action: AuditAction,
+        detail: &str,
+        ip: &str,
+    ) -> Result<(), ApiError> {
+        insert_into(crate::schema::audit_log::dsl::audit_log)
+            .values((
+                crate::schema::audit_log::dsl::created_at.eq(chrono::Utc::now().timestamp()),
+                crate::schema::audit_log::dsl::actor_id.eq(actor.sub),
+                crate::schema::audit_log::dsl::actor_login.eq(&actor.login),
+                crate::schema::audit_log::dsl::user_id.eq(user.id),
+                crate::schema::audit_log::dsl::user_login.eq(&user.login),
+                crate::schema::audit_log::dsl::action.eq(action.as_str()),
+                crate::schema::audit_log::dsl::detail.eq(detail),
+                crate::schema::audit_log::dsl::ip.eq(ip),
+            ))
+            .execute(db)
+            .map(|_| ())
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn list(
+        db: &mut SqliteConnection,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEntry>, ApiError> {
+        let mut entries = crate::schema::audit_log::dsl::audit_log
+            .order(crate::schema::audit_log::dsl::id.desc())
+            .limit(query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT))
+            .select(AuditEntry::as_select())
+            .into_boxed();
+        if let Some(actor) = query.actor {
+            entries = entries.filter(crate::schema::audit_log::dsl::actor_id.eq(actor));
+        }
+        if let Some(user) = query.user {
+            entries = entries.filter(crate::schema::audit_log::dsl::user_id.eq(user));
+        }
+        if let Some(before) = query.before {
+            entries = entries.filter(crate::schema::audit_log::dsl::id.lt(before));
+        }
+        entries.load(db).map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use crate::api_error::ApiError;
+use crate::models::user_model::User;
+use diesel::result::DatabaseErrorKind;
+use diesel::{
+    insert_into, ExpressionMethods, OptionalExtension, QueryDsl, Queryable, RunQueryDsl,
+    Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// a subject of an upstream provider, logging in as the user it is linked to
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = crate::schema::federated_identities)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct FederatedIdentity {
+    pub(crate) id: i32,
+    /// id of the provider in `federation.providers`
+    pub(crate) provider: String,
+    pub(crate) subject: String,
+    pub(crate) user_id: i32,
+    pub(crate) created_at: i64,
+}
+
+impl FederatedIdentity {
+    /// the user the subject is linked to, if any
+    pub(crate) fn user(
+        db: &mut SqliteConnection,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<User>, ApiError> {
+        let linked = crate::schema::federated_identities::dsl::federated_identities
+            .filter(crate::schema::federated_identities::dsl::provider.eq(provider))
+            .filter(crate::schema::federated_identities::dsl::subject.eq(subject))
+            .select(crate::schema::federated_identities::dsl::user_id)
+            .first::<i32>(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        linked.map(|user_id| User::get(db, user_id)).transpose()
+    }
+
+    /// a subject is linked to one user only, linking it again is refused with
+    /// [`ApiError::Federation`]
+    pub(crate) fn link(
+        db: &mut SqliteConnection,
+        provider: &str,
+        subject: &str,
+        user: &User,
+    ) -> Result<FederatedIdentity, ApiError> {
+        insert_into(crate::schema::federated_identities::dsl::federated_identities)
+            .values((
+                crate::schema::federated_identities::dsl::provider.eq(provider),
+                crate::schema::federated_identities::dsl::subject.eq(subject),
+                crate::schema::federated_identities::dsl::user_id.eq(user.id),
+                crate::schema::federated_identities::dsl::created_at
+                    .eq(chrono::Utc::now().timestamp()),
+            ))
+            .returning(FederatedIdentity::as_returning())
+            .get_result(db)
+            .map_err(|e| match e {
+                diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    ApiError::Federation
+                }
+                e => {
+                    error!("{e:?}");
+                    ApiError::Internal
+                }
+            })
+    }
+
+    pub(crate) fn for_user(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<Vec<FederatedIdentity>, ApiError> {
+        crate::schema::federated_identities::dsl::federated_identities
+            .filter(crate::schema::federated_identities::dsl::user_id.eq(user.id))
+            .order(crate::schema::federated_identities::dsl::created_at)
+            .select(FederatedIdentity::as_select())
+            .load(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn get(
+        db: &mut SqliteConnection,
+        user: &User,
+        identity_id: i32,
+    ) -> Result<FederatedIdentity, ApiError> {
+        crate::schema::federated_identities::dsl::federated_identities
+            .filter(crate::schema::federated_identities::dsl::id.eq(identity_id))
+            .filter(crate::schema::federated_identities::dsl::user_id.eq(user.id))
+            .select(FederatedIdentity::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?
+            .ok_or(ApiError::FederatedIdentityNotFound)
+    }
+
+    pub(crate) fn delete(&self, db: &mut SqliteConnection) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::federated_identities::dsl::federated_identities
+                .filter(crate::schema::federated_identities::dsl::id.eq(self.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::federated_identities::dsl::federated_identities
+                .filter(crate::schema::federated_identities::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::api_error::ApiError;
+use crate::helpers::{random_token, sha256_hex};
+use crate::models::user_model::User;
+use diesel::{
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+
+const STATE_LENGTH: usize = 48;
+const NONCE_LENGTH: usize = 32;
+/// pkce verifiers are 43 to 128 characters long
+const CODE_VERIFIER_LENGTH: usize = 64;
+/// time a user has to log in at the provider
+pub(crate) const STATE_LIFETIME: i64 = 600;
+
+/// a login in progress at an upstream provider, only the hash of its state is stored
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = crate::schema::federation_states)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct FederationState {
+    state_hash: String,
+    pub(crate) provider: String,
+    pub(crate) nonce: String,
+    pub(crate) code_verifier: String,
+    /// checked against the allowed origins before being stored
+    pub(crate) return_to: Option<String>,
+    /// the user linking the provider's subject to their account, none for a login
+    pub(crate) link_user_id: Option<i32>,
+    expires_at: i64,
+}
+
+impl FederationState {
+    /// starts a login at `provider`, returns the state to send along with the pending login
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        provider: &str,
+        return_to: Option<String>,
+        link_user: Option<&User>,
+    ) -> Result<(String, FederationState), ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        if let Err(e) = diesel::delete(crate::schema::federation_states::dsl::federation_states)
+            .filter(crate::schema::federation_states::dsl::expires_at.lt(now))
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        let state = random_token(STATE_LENGTH);
+        let pending = insert_into(crate::schema::federation_states::dsl::federation_states)
+            .values(FederationState {
+                state_hash: sha256_hex(&state),
+                provider: provider.to_string(),
+                nonce: random_token(NONCE_LENGTH),
+                code_verifier: random_token(CODE_VERIFIER_LENGTH),
+                return_to,
+                link_user_id: link_user.map(|user| user.id),
+                expires_at: now + STATE_LIFETIME,
+            })
+            .returning(FederationState::as_returning())
+            .get_result(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok((state, pending))
+    }
+
+    /// takes the pending login out, a state can't come back twice whatever the outcome
+    pub(crate) fn redeem(
+        db: &mut SqliteConnection,
+        state: &str,
+    ) -> Result<Option<FederationState>, ApiError> {
+        let state_hash = sha256_hex(state);
+        let pending = crate::schema::federation_states::dsl::federation_states
+            .filter(crate::schema::federation_states::dsl::state_hash.eq(&state_hash))
+            .select(FederationState::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        if let Err(e) = diesel::delete(crate::schema::federation_states::dsl::federation_states)
+            .filter(crate::schema::federation_states::dsl::state_hash.eq(&state_hash))
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        Ok(pending.filter(|pending| pending.expires_at >= chrono::Utc::now().timestamp()))
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::federation_states::dsl::federation_states
+                .filter(crate::schema::federation_states::dsl::link_user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
@@ -1,18 +1,15 @@
 use crate::api_error::ApiError;
-use crate::helpers::try_get_connection;
+use crate::middlewares::authentication_middleware::access_claims;
 use crate::models::group_user_model::GroupUser;
-use crate::models::jwt_model::JWTInternal;
 use crate::models::user_model::User;
-use crate::{KeySet, StorageState};
 use actix_web::dev::Payload;
-use actix_web::{web, FromRequest, HttpRequest};
+use actix_web::{FromRequest, HttpRequest};
 use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use diesel::{
     insert_into, AsChangeset, Identifiable, Insertable, QueryDsl, Queryable, RunQueryDsl,
     Selectable, SelectableHelper, SqliteConnection,
 };
 use diesel::{BelongingToDsl, ExpressionMethods, JoinOnDsl};
-use futures::future::ok;
 use log::error;
 use serde::{Deserialize, Serialize};
 use std::future::{ready, Future};
@@ -77,6 +74,21 @@ impl Group {
         }
     }
 
+    pub(crate) fn by_name(db: &mut SqliteConnection, group_name: &str) -> Result<Group, ApiError> {
+        match crate::schema::groups::dsl::groups
+            .filter(crate::schema::groups::dsl::name.eq(group_name))
+            .select(Group::as_select())
+            .first(db)
+        {
+            Ok(g) => Ok(g),
+            Err(diesel::result::Error::NotFound) => Err(ApiError::Group),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
     pub(crate) fn update_group(db: &mut SqliteConnection, group: &Group) -> Result<(), ApiError> {
         match diesel::update(crate::schema::groups::dsl::groups)
             .filter(crate::schema::groups::dsl::id.eq(group.id))
@@ -135,75 +147,14 @@ impl FromRequest for Groups {
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let mut db = if let Some(storage) = req.app_data::<web::Data<StorageState>>() {
-            match try_get_connection(storage) {
-                Ok(db) => db,
-                Err(e) => return Box::pin(ready(Err(e))),
-            }
-        } else {
-            error!("couldn't access storage");
-            return Box::pin(ready(Err(ApiError::Internal)));
-        };
-        let Some(key_set) = req.app_data::<web::Data<KeySet>>() else {
-            error!("couldn't access key set");
-            return Box::pin(ready(Err(ApiError::Internal)));
-        };
-        let req = req.clone();
-        match req.cookie("jwt")/*headers().get("Authorization")*/ {
-            Some(jwt) => {
-                /*let jwt = match jwt.value() {
-                    Ok(jwt) => {
-                        if jwt.starts_with("Bearer ") {
-                            &jwt[7..jwt.len()]
-                        } else {
-                            return Box::pin(ready(Err(ApiError::Jwt)));
-                        }
-                    }
-                    Err(e) => {
-                        error!("{e:?}");
-                        return Box::pin(ready(Err(ApiError::Internal)));
-                    }
-                };*/
-                let jwt = jwt.value();
-                let claims = match JWTInternal::validate_jwt(&mut db, jwt, &key_set.decoding) {
-                    Ok(claims) => {
-                        let needs_refresh = match JWTInternal::needs_refresh(&mut db, &claims) {
-                            Ok(needs_refresh) => needs_refresh,
-                            Err(e) => {
-                                error!("{e:?}");
-                                return Box::pin(ready(Err(e)));
-                            }
-                        };
-                        if needs_refresh {
-                            match JWTInternal::refresh(
-                                &mut db,
-                                &claims.user,
-                                &claims.jti,
-                                &key_set.encoding,
-                                false,
-                            ) {
-                                Ok(refresh) => match JWTInternal::register(&mut db, &refresh) {
-                                    Ok(()) => refresh,
-                                    Err(e) => return Box::pin(ready(Err(e))),
-                                },
-                                Err(e) => return Box::pin(ready(Err(e))),
-                            }
-                        } else {
-                            match JWTInternal::from(&claims, &key_set.encoding) {
-                                Ok(token) => token,
-                                Err(e) => return Box::pin(ready(Err(e))),
-                            }
-                        }
-                    }
-                    Err(e) => return Box::pin(ready(Err(e))),
-                };
-                Box::pin(ok(Groups(claims.claims.groups)))
-            }
-            None => Box::pin(ok(Groups(vec![Group {
+        Box::pin(ready(match access_claims(req) {
+            Ok(Some(claims)) => Ok(Groups(claims.groups)),
+            Ok(None) => Ok(Groups(vec![Group {
                 id: 1,
                 name: "public".to_string(),
-            }]))), // TODO make this cleaner
-        }
+            }])), // TODO make this cleaner
+            Err(e) => Err(e),
+        }))
     }
 }
 
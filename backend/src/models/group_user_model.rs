@@ -10,6 +10,7 @@ use diesel::{
 };
 use log::error;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 #[derive(Identifiable, Selectable, Queryable, Associations, Debug, Serialize, Deserialize)]
 #[diesel(belongs_to(Group))]
@@ -39,12 +40,12 @@ impl GroupUser {
                 JWTInternal::refresh_for_user(db, user)?;
                 Ok(())
             }
-            Err(diesel::result::Error::DatabaseError(e, _)) => match e {
+            Err(diesel::result::Error::DatabaseError(
                 DatabaseErrorKind::UniqueViolation
                 | DatabaseErrorKind::NotNullViolation
-                | DatabaseErrorKind::ForeignKeyViolation => Err(ApiError::Group),
-                _ => Err(ApiError::Internal),
-            },
+                | DatabaseErrorKind::ForeignKeyViolation,
+                _,
+            )) => Err(ApiError::Group),
             _ => Err(ApiError::Internal),
         }
     }
@@ -74,6 +75,30 @@ impl GroupUser {
         }
     }
 
+    /// makes the user a member of the `wanted` groups among the `managed` ones, and of none
+    /// of the others. groups outside `managed` are left alone
+    pub(crate) fn sync_managed_groups(
+        db: &mut SqliteConnection,
+        user: &User,
+        managed: &[&str],
+        wanted: &[&str],
+    ) -> Result<(), ApiError> {
+        let current = User::get_groups(db, user)?;
+        for group in Group::get_all(db)? {
+            if !managed.contains(&group.name.as_str()) {
+                continue;
+            }
+            let member = wanted.contains(&group.name.as_str());
+            let joined = current.iter().any(|joined| joined.id == group.id);
+            if member && !joined {
+                Self::add_user_to_group(db, user, &group)?;
+            } else if !member && joined {
+                Self::remove_user_from_group(db, user, &group)?;
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn user_allowed_to_origin(
         db: &mut SqliteConnection,
         origin: &str,
@@ -104,6 +129,25 @@ impl GroupUser {
             Ok(())
         }
     }
+
+    /// access to a page rather than to an origin: url rules are matched against the page's
+    /// origin, then against the page itself without its query
+    pub(crate) fn user_allowed_to_url(
+        db: &mut SqliteConnection,
+        url: &Url,
+        groups: &Vec<i32>,
+    ) -> Result<(), ApiError> {
+        let Some(host) = url.host_str() else {
+            return Err(ApiError::Group);
+        };
+        let mut page = url.clone();
+        page.set_query(None);
+        page.set_fragment(None);
+        match Self::user_allowed_to_origin(db, &url.origin().ascii_serialization(), host, groups) {
+            Err(ApiError::Group) => Self::user_allowed_to_origin(db, page.as_str(), host, groups),
+            allowed => allowed,
+        }
+    }
 }
 #[derive(Insertable, Serialize, Deserialize, Debug)]
 #[diesel(table_name = crate::schema::groups_users)]
@@ -1,31 +1,131 @@
 use crate::api_error::ApiError;
+use crate::config;
+use crate::key_ring::KeyRing;
+use crate::models::api_token_model::ApiToken;
 use crate::models::group_model::Group;
+use crate::models::oidc_code_model::SUPPORTED_SCOPES;
 use crate::models::role_model::Role;
 use crate::models::role_user_model::RoleUser;
+use crate::models::session_model::{ClientInfo, Session};
 use crate::models::user_model::User;
 use diesel::{
-    insert_into, ExpressionMethods, Insertable, QueryDsl, Queryable, RunQueryDsl, Selectable,
-    SqliteConnection,
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
 };
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use log::error;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// a refresh token replayed less than this many seconds after its rotation is treated as a
+/// client race (two tabs refreshing at once) and rejected without revoking its family
+const REFRESH_REUSE_LEEWAY: i64 = 10;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct Claims {
     pub(crate) company: String,
     pub(crate) exp: i64,
     pub(crate) jti: String,
+    pub(crate) family: String,
     pub(crate) user: User,
     pub(crate) role: Role,
     pub(crate) groups: Vec<Group>,
+    /// set when an admin impersonates the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) act: Option<Actor>,
+    /// openid connect client the token was issued to. such tokens are only good for the
+    /// userinfo endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) aud: Option<String>,
+    /// scopes granted to that client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) scope: Option<String>,
+}
+
+/// the admin acting as the token's user, `act` claim of rfc 8693
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct Actor {
+    pub(crate) sub: i32,
+    pub(crate) login: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct RefreshClaims {
+    pub(crate) exp: i64,
+    pub(crate) jti: String,
+    pub(crate) family: String,
+    pub(crate) sub: i32,
+}
+
+/// what an openid connect client learns about the user, in the id token and from the
+/// userinfo endpoint. claims beyond `sub` depend on the granted scopes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct UserInfo {
+    pub(crate) sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) groups: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct IdTokenClaims {
+    pub(crate) iss: String,
+    /// client id of the relying party
+    pub(crate) aud: String,
+    pub(crate) exp: i64,
+    pub(crate) iat: i64,
+    pub(crate) auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) nonce: Option<String>,
+    #[serde(flatten)]
+    pub(crate) user_info: UserInfo,
+}
+
+/// what the introspection endpoint tells about a token, rfc 7662. tokens that can't be used
+/// anymore only get `active: false`
+#[derive(Debug, Serialize, Default)]
+pub(crate) struct Introspection {
+    pub(crate) active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) scope: Option<String>,
+    /// openid connect client the token was issued to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) groups: Option<Vec<String>>,
+    /// the admin behind an impersonation token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) act: Option<Actor>,
 }
 
 pub(crate) struct JWTInternal {
     pub(crate) claims: Claims,
     pub(crate) token: String,
 }
+
+pub(crate) struct RefreshToken {
+    pub(crate) claims: RefreshClaims,
+    pub(crate) token: String,
+}
+
 #[allow(clippy::struct_field_names)]
 #[derive(Insertable, Serialize, Deserialize, Selectable, Queryable)]
 #[diesel(table_name = crate::schema::jwt)]
@@ -33,35 +133,117 @@ struct Jwt {
     jwt_id: String,
     needs_refresh: i32,
     user_id: i32,
+    family_id: String,
+    kind: String,
+    used_at: Option<i64>,
+    expires_at: i64,
+}
+
+/// when the session of the family is over at the latest, tokens of the family don't outlive it
+fn session_ends_at(db: &mut SqliteConnection, family: &str) -> Result<i64, ApiError> {
+    Ok(Session::get(db, family)?.map_or(i64::MAX, |session| session.ends_at()))
 }
 
 impl JWTInternal {
-    pub(crate) fn from(claims: &Claims, key: &EncodingKey) -> Result<Self, ApiError> {
-        let token = encode(&Header::new(Algorithm::EdDSA), &claims, key);
-        if let Ok(token) = token {
-            return Ok(JWTInternal {
-                token,
-                claims: claims.clone(),
-            });
-        };
-        Err(ApiError::Internal)
+    pub(crate) fn from(claims: &Claims, keys: &KeyRing) -> Result<Self, ApiError> {
+        Ok(JWTInternal {
+            token: keys.encode(claims)?,
+            claims: claims.clone(),
+        })
     }
-    pub(crate) fn create(
+    fn fresh_claims(
         db: &mut SqliteConnection,
         user: &User,
-        key: &EncodingKey,
-    ) -> Result<Self, ApiError> {
-        let id = Uuid::new_v4();
-        let claims = Claims {
-            company: String::from("I.K.E"),
-            exp: chrono::Utc::now().timestamp() + 3600 * 24 * 7,
-            jti: id.to_string(),
+        family: &str,
+    ) -> Result<Claims, ApiError> {
+        let exp = chrono::Utc::now().timestamp() + config::get().tokens.access_lifetime;
+        let session = Session::get(db, family)?;
+        Ok(Claims {
+            company: config::get().tokens.company.clone(),
+            exp: session
+                .as_ref()
+                .map_or(exp, |session| exp.min(session.ends_at())),
+            jti: Uuid::new_v4().to_string(),
+            family: family.to_string(),
             user: user.clone(),
             role: RoleUser::roles_from_user(db, user)?,
             groups: User::get_groups(db, user)?,
+            act: None,
+            aud: session
+                .as_ref()
+                .and_then(|session| session.oidc_client_id.clone()),
+            scope: session.and_then(|session| session.scope),
+        })
+    }
+
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        user: &User,
+        family: &str,
+        keys: &KeyRing,
+    ) -> Result<Self, ApiError> {
+        Self::from(&Self::fresh_claims(db, user, family)?, keys)
+    }
+
+    /// starts a new token family (a login session) and returns its first access/refresh pair,
+    /// both already registered
+    pub(crate) fn create_session(
+        db: &mut SqliteConnection,
+        user: &User,
+        keys: &KeyRing,
+        client: &ClientInfo,
+    ) -> Result<(JWTInternal, RefreshToken), ApiError> {
+        let access = Self::create_unrefreshable_session(db, user, keys, client)?;
+        let ends_at = session_ends_at(db, &access.claims.family)?;
+        let refresh = RefreshToken::create(user, &access.claims.family, ends_at, keys)?;
+        refresh.register(db)?;
+        Ok((access, refresh))
+    }
+
+    /// starts a token family with a single access token and no refresh token, the session
+    /// ends when the token expires
+    pub(crate) fn create_unrefreshable_session(
+        db: &mut SqliteConnection,
+        user: &User,
+        keys: &KeyRing,
+        client: &ClientInfo,
+    ) -> Result<JWTInternal, ApiError> {
+        Self::purge_expired(db)?;
+        let family = Uuid::new_v4().to_string();
+        Session::create(db, user, &family, client)?;
+        let access = JWTInternal::create(db, user, &family, keys)?;
+        JWTInternal::register(db, &access)?;
+        Ok(access)
+    }
+
+    /// lets `actor` act as `user` until `tokens.impersonation_lifetime` runs out, with a
+    /// session of its own and no refresh token. root can't be impersonated
+    pub(crate) fn impersonate(
+        db: &mut SqliteConnection,
+        actor: &User,
+        user: &User,
+        keys: &KeyRing,
+        client: &ClientInfo,
+    ) -> Result<JWTInternal, ApiError> {
+        if actor.id == user.id || RoleUser::roles_from_user(db, user)? == Role::from("root")? {
+            return Err(ApiError::Impersonation);
+        }
+        Self::purge_expired(db)?;
+        let family = Uuid::new_v4().to_string();
+        Session::create(db, user, &family, client)?;
+        let claims = Claims {
+            exp: chrono::Utc::now().timestamp() + config::get().tokens.impersonation_lifetime,
+            act: Some(Actor {
+                sub: actor.id,
+                login: actor.login.clone(),
+            }),
+            ..Self::fresh_claims(db, user, &family)?
         };
-        Self::from(&claims, key)
+        let access = Self::from(&claims, keys)?;
+        Self::register(db, &access)?;
+        Ok(access)
     }
+
     pub(crate) fn needs_refresh(
         db: &mut SqliteConnection,
         claims: &Claims,
@@ -80,6 +262,19 @@ impl JWTInternal {
         }
     }
 
+    /// whether the token is about to expire while its session goes on. impersonation tokens
+    /// aren't, they don't outlive their time box
+    pub(crate) fn in_refresh_window(
+        db: &mut SqliteConnection,
+        claims: &Claims,
+    ) -> Result<bool, ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        if claims.act.is_some() || claims.exp - now > config::get().tokens.refresh_window {
+            return Ok(false);
+        }
+        Ok(claims.exp < session_ends_at(db, &claims.family)?)
+    }
+
     pub(crate) fn delete(db: &mut SqliteConnection, jti: &str) -> Result<(), ApiError> {
         if let Err(e) = diesel::delete(
             crate::schema::jwt::dsl::jwt.filter(crate::schema::jwt::dsl::jwt_id.eq(jti)),
@@ -92,20 +287,89 @@ impl JWTInternal {
         Ok(())
     }
 
+    /// revokes every access and refresh token of a family
+    pub(crate) fn delete_family(db: &mut SqliteConnection, family: &str) -> Result<(), ApiError> {
+        if let Err(e) = diesel::delete(
+            crate::schema::jwt::dsl::jwt.filter(crate::schema::jwt::dsl::family_id.eq(family)),
+        )
+        .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        Session::delete(db, family)
+    }
+
     pub(crate) fn refresh(
         db: &mut SqliteConnection,
-        user: &User,
-        jti: &str,
-        key: &EncodingKey,
+        claims: &Claims,
+        keys: &KeyRing,
         delete: bool,
     ) -> Result<JWTInternal, ApiError> {
-        let refreshed_jwt = JWTInternal::create(db, user, key)?;
+        let mut refreshed = Self::fresh_claims(db, &claims.user, &claims.family)?;
+        // impersonation doesn't outlive its time box
+        if claims.act.is_some() {
+            refreshed.exp = claims.exp;
+            refreshed.act.clone_from(&claims.act);
+        }
+        let refreshed_jwt = Self::from(&refreshed, keys)?;
         if delete {
-            JWTInternal::delete(db, jti)?;
+            JWTInternal::delete(db, &claims.jti)?;
         }
         Ok(refreshed_jwt)
     }
 
+    /// trades a refresh token for a new access/refresh pair of the same family.
+    /// presenting an already rotated refresh token revokes the whole family, since either the
+    /// legitimate client or an attacker is replaying a stolen token
+    pub(crate) fn rotate(
+        db: &mut SqliteConnection,
+        raw_refresh_token: &str,
+        keys: &KeyRing,
+    ) -> Result<(JWTInternal, RefreshToken), ApiError> {
+        let claims = RefreshToken::validate(raw_refresh_token, keys)?;
+        let stored = crate::schema::jwt::dsl::jwt
+            .filter(crate::schema::jwt::dsl::jwt_id.eq(&claims.jti))
+            .filter(crate::schema::jwt::dsl::kind.eq("refresh"))
+            .select(Jwt::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        let Some(stored) = stored else {
+            return Err(ApiError::Jwt);
+        };
+        let now = chrono::Utc::now().timestamp();
+        if let Some(used_at) = stored.used_at {
+            if now - used_at > REFRESH_REUSE_LEEWAY {
+                warn!(
+                    "refresh token reuse detected for user {}, revoking family {}",
+                    stored.user_id, stored.family_id
+                );
+                Self::delete_family(db, &stored.family_id)?;
+            }
+            return Err(ApiError::Jwt);
+        }
+        let session = Self::live_session(db, &claims.family)?;
+        diesel::update(crate::schema::jwt::dsl::jwt)
+            .filter(crate::schema::jwt::dsl::jwt_id.eq(&claims.jti))
+            .set(crate::schema::jwt::dsl::used_at.eq(now))
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        let user = User::get(db, claims.sub).map_err(|_| ApiError::Jwt)?;
+        Self::purge_expired(db)?;
+        let access = JWTInternal::create(db, &user, &claims.family, keys)?;
+        JWTInternal::register(db, &access)?;
+        let refresh = RefreshToken::create(&user, &claims.family, session.ends_at(), keys)?;
+        refresh.register(db)?;
+        Ok((access, refresh))
+    }
+
     pub(crate) fn refresh_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
         match diesel::update(crate::schema::jwt::dsl::jwt)
             .filter(crate::schema::jwt::dsl::user_id.eq(user.id))
@@ -126,7 +390,38 @@ impl JWTInternal {
         )
         .execute(db)
         {
-            Ok(_) => Ok(()),
+            Ok(_) => Session::delete_for_user(db, user),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    /// revokes every session of the user but the one given, logging them out everywhere else
+    pub(crate) fn invalidate_user_except(
+        db: &mut SqliteConnection,
+        user: &User,
+        family: &str,
+    ) -> Result<(), ApiError> {
+        for session in Session::for_user(db, user)? {
+            if session.id != family {
+                Self::delete_family(db, &session.id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// drops rows of tokens that can't be presented anymore, used refresh tokens included:
+    /// once expired they fail validation before reuse detection is even reached
+    fn purge_expired(db: &mut SqliteConnection) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::jwt::dsl::jwt
+                .filter(crate::schema::jwt::dsl::expires_at.lt(chrono::Utc::now().timestamp())),
+        )
+        .execute(db)
+        {
+            Ok(_) => Session::purge_orphans(db),
             Err(e) => {
                 error!("{e:?}");
                 Err(ApiError::Internal)
@@ -134,9 +429,22 @@ impl JWTInternal {
         }
     }
 
+    /// the session of the family while it lasts. one that is over is revoked with its tokens
+    fn live_session(db: &mut SqliteConnection, family: &str) -> Result<Session, ApiError> {
+        match Session::get(db, family)? {
+            Some(session) if !session.has_ended(chrono::Utc::now().timestamp()) => Ok(session),
+            Some(_) => {
+                Self::delete_family(db, family)?;
+                Err(ApiError::Jwt)
+            }
+            None => Err(ApiError::Jwt),
+        }
+    }
+
     fn is_valid_jti(db: &mut SqliteConnection, jti: &str) -> bool {
         match crate::schema::jwt::dsl::jwt
             .filter(crate::schema::jwt::dsl::jwt_id.eq(jti))
+            .filter(crate::schema::jwt::dsl::kind.eq("access"))
             .count()
             .get_result::<i64>(db)
         {
@@ -148,17 +456,14 @@ impl JWTInternal {
     pub(crate) fn validate_jwt(
         db: &mut SqliteConnection,
         raw_token: &str,
-        key: &DecodingKey,
+        keys: &KeyRing,
     ) -> Result<Claims, ApiError> {
-        let validation = Validation::new(Algorithm::EdDSA);
-        if let Ok(claims) = decode::<Claims>(raw_token, key, &validation) {
-            let claims = claims.claims;
-            if claims.exp < chrono::Utc::now().timestamp() || !Self::is_valid_jti(db, &claims.jti) {
-                return Err(ApiError::Jwt);
-            }
-            return Ok(claims.clone());
+        let claims = keys.decode::<Claims>(raw_token)?;
+        if claims.exp < chrono::Utc::now().timestamp() || !Self::is_valid_jti(db, &claims.jti) {
+            return Err(ApiError::Jwt);
         }
-        Err(ApiError::Jwt)
+        Self::live_session(db, &claims.family)?;
+        Ok(claims)
     }
 
     pub(crate) fn register(db: &mut SqliteConnection, token: &JWTInternal) -> Result<(), ApiError> {
@@ -166,6 +471,10 @@ impl JWTInternal {
             jwt_id: token.claims.jti.clone(),
             needs_refresh: 0,
             user_id: token.claims.user.id,
+            family_id: token.claims.family.clone(),
+            kind: "access".to_string(),
+            used_at: None,
+            expires_at: token.claims.exp,
         };
         insert_into(crate::schema::jwt::dsl::jwt)
             .values(&insertable_jwt)
@@ -177,3 +486,314 @@ impl JWTInternal {
         Ok(())
     }
 }
+
+impl Introspection {
+    fn active(user: &User, role: &Role, groups: &[Group], exp: i64, jti: String) -> Self {
+        Introspection {
+            active: true,
+            username: Some(user.login.clone()),
+            token_type: Some("Bearer"),
+            exp: Some(exp),
+            sub: Some(user.id.to_string()),
+            iss: Some(config::get().oidc.issuer.clone()),
+            jti: Some(jti),
+            role: Some(role.role.clone()),
+            groups: Some(groups.iter().map(|group| group.name.clone()).collect()),
+            ..Default::default()
+        }
+    }
+
+    /// tells whether the access or api token would be accepted right now, and on whose
+    /// behalf. the token is checked against its session and its user, and a token whose
+    /// family was marked for refresh gets the role and groups it would be refreshed with
+    pub(crate) fn of(
+        db: &mut SqliteConnection,
+        raw_token: &str,
+        keys: &KeyRing,
+    ) -> Result<Self, ApiError> {
+        if ApiToken::is_api_token(raw_token) {
+            let Some(token) = ApiToken::find_active(db, raw_token)? else {
+                return Ok(Self::default());
+            };
+            let user = match User::get(db, token.user_id) {
+                Ok(user) => user,
+                Err(ApiError::UserNotFound) => return Ok(Self::default()),
+                Err(e) => return Err(e),
+            };
+            let role = RoleUser::roles_from_user(db, &user)?;
+            let groups = User::get_groups(db, &user)?;
+            return Ok(Introspection {
+                scope: Some(token.scope()),
+                ..Self::active(&user, &role, &groups, token.expires_at, token.family())
+            });
+        }
+
+        let Ok(claims) = keys.decode::<Claims>(raw_token) else {
+            return Ok(Self::default());
+        };
+        if claims.exp < chrono::Utc::now().timestamp()
+            || !JWTInternal::is_valid_jti(db, &claims.jti)
+        {
+            return Ok(Self::default());
+        }
+        // a session that is over is revoked on the way, as when the token is presented
+        let session = match JWTInternal::live_session(db, &claims.family) {
+            Ok(session) => session,
+            Err(ApiError::Jwt) => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let user = match User::get(db, claims.user.id) {
+            Ok(user) => user,
+            Err(ApiError::UserNotFound) => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let (role, groups) = if JWTInternal::needs_refresh(db, &claims)? {
+            (
+                RoleUser::roles_from_user(db, &user)?,
+                User::get_groups(db, &user)?,
+            )
+        } else {
+            (claims.role, claims.groups)
+        };
+        Ok(Introspection {
+            scope: claims.scope,
+            client_id: session.oidc_client_id,
+            act: claims.act,
+            ..Self::active(&user, &role, &groups, claims.exp, claims.jti)
+        })
+    }
+}
+
+impl UserInfo {
+    pub(crate) fn from(user: &User, role: &Role, groups: &[Group], scope: &str) -> Self {
+        let granted = |wanted: &str| scope.split(' ').any(|scope| scope == wanted);
+        UserInfo {
+            sub: user.id.to_string(),
+            preferred_username: granted("profile").then(|| user.login.clone()),
+            email: user.email.clone().filter(|_| granted("email")),
+            role: granted("groups").then(|| role.role.clone()),
+            groups: granted("groups")
+                .then(|| groups.iter().map(|group| group.name.clone()).collect()),
+        }
+    }
+
+    /// what the scopes of the token allow. rauth's own tokens carry none, their user gets
+    /// to see everything
+    pub(crate) fn from_claims(claims: &Claims) -> Self {
+        let scope = claims
+            .scope
+            .clone()
+            .unwrap_or_else(|| SUPPORTED_SCOPES.join(" "));
+        Self::from(&claims.user, &claims.role, &claims.groups, &scope)
+    }
+}
+
+impl IdTokenClaims {
+    /// signed id token for the client, role and groups are read fresh from the database
+    pub(crate) fn sign(
+        db: &mut SqliteConnection,
+        user: &User,
+        client_id: &str,
+        scope: &str,
+        nonce: Option<String>,
+        auth_time: i64,
+        keys: &KeyRing,
+    ) -> Result<String, ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        let role = RoleUser::roles_from_user(db, user)?;
+        let groups = User::get_groups(db, user)?;
+        keys.encode(&IdTokenClaims {
+            iss: config::get().oidc.issuer.clone(),
+            aud: client_id.to_string(),
+            exp: now + config::get().tokens.access_lifetime,
+            iat: now,
+            auth_time,
+            nonce,
+            user_info: UserInfo::from(user, &role, &groups, scope),
+        })
+    }
+}
+
+impl RefreshToken {
+    /// `ends_at` is when the session of the family is over, the token doesn't outlive it
+    pub(crate) fn create(
+        user: &User,
+        family: &str,
+        ends_at: i64,
+        keys: &KeyRing,
+    ) -> Result<Self, ApiError> {
+        let exp = chrono::Utc::now().timestamp() + config::get().tokens.refresh_lifetime;
+        let claims = RefreshClaims {
+            exp: exp.min(ends_at),
+            jti: Uuid::new_v4().to_string(),
+            family: family.to_string(),
+            sub: user.id,
+        };
+        Ok(RefreshToken {
+            token: keys.encode(&claims)?,
+            claims,
+        })
+    }
+
+    /// checks signature and expiry only, whether it was already used is up to
+    /// [`JWTInternal::rotate`]
+    pub(crate) fn validate(raw_token: &str, keys: &KeyRing) -> Result<RefreshClaims, ApiError> {
+        match keys.decode::<RefreshClaims>(raw_token) {
+            Ok(claims) if claims.exp >= chrono::Utc::now().timestamp() => Ok(claims),
+            _ => Err(ApiError::Jwt),
+        }
+    }
+
+    pub(crate) fn register(&self, db: &mut SqliteConnection) -> Result<(), ApiError> {
+        let insertable_jwt = Jwt {
+            jwt_id: self.claims.jti.clone(),
+            needs_refresh: 0,
+            user_id: self.claims.sub,
+            family_id: self.claims.family.clone(),
+            kind: "refresh".to_string(),
+            used_at: None,
+            expires_at: self.claims.exp,
+        };
+        insert_into(crate::schema::jwt::dsl::jwt)
+            .values(&insertable_jwt)
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::try_get_connection;
+    use crate::test_support;
+
+    /// marks the refresh token as rotated that long ago
+    fn used_ago(db: &mut SqliteConnection, refresh: &RefreshToken, seconds: i64) {
+        diesel::update(crate::schema::jwt::dsl::jwt)
+            .filter(crate::schema::jwt::dsl::jwt_id.eq(&refresh.claims.jti))
+            .set(crate::schema::jwt::dsl::used_at.eq(chrono::Utc::now().timestamp() - seconds))
+            .execute(db)
+            .unwrap();
+    }
+
+    #[test]
+    fn rotation_hands_out_a_new_pair_of_the_same_family() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let (access, refresh) =
+            JWTInternal::create_session(&mut db, &alice, &key_ring, &test_support::client())
+                .unwrap();
+
+        let (rotated_access, rotated) =
+            JWTInternal::rotate(&mut db, &refresh.token, &key_ring).unwrap();
+        assert_eq!(rotated_access.claims.family, access.claims.family);
+        assert_eq!(rotated.claims.family, refresh.claims.family);
+        assert_ne!(rotated.claims.jti, refresh.claims.jti);
+        assert!(JWTInternal::validate_jwt(&mut db, &rotated_access.token, &key_ring).is_ok());
+
+        // two tabs refreshing at once: the late one is turned down, the session goes on
+        assert!(matches!(
+            JWTInternal::rotate(&mut db, &refresh.token, &key_ring),
+            Err(ApiError::Jwt)
+        ));
+        assert!(JWTInternal::validate_jwt(&mut db, &rotated_access.token, &key_ring).is_ok());
+        let (latest_access, latest) =
+            JWTInternal::rotate(&mut db, &rotated.token, &key_ring).unwrap();
+
+        // a replay past the leeway is a stolen token, the whole family goes
+        used_ago(&mut db, &rotated, REFRESH_REUSE_LEEWAY + 1);
+        assert!(matches!(
+            JWTInternal::rotate(&mut db, &rotated.token, &key_ring),
+            Err(ApiError::Jwt)
+        ));
+        assert!(JWTInternal::validate_jwt(&mut db, &latest_access.token, &key_ring).is_err());
+        assert!(JWTInternal::rotate(&mut db, &latest.token, &key_ring).is_err());
+    }
+
+    #[test]
+    fn expired_refresh_tokens_are_refused() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let (access, _) =
+            JWTInternal::create_session(&mut db, &alice, &key_ring, &test_support::client())
+                .unwrap();
+        let expired = RefreshToken::create(
+            &alice,
+            &access.claims.family,
+            chrono::Utc::now().timestamp() - 1,
+            &key_ring,
+        )
+        .unwrap();
+        expired.register(&mut db).unwrap();
+        assert!(matches!(
+            JWTInternal::rotate(&mut db, &expired.token, &key_ring),
+            Err(ApiError::Jwt)
+        ));
+    }
+
+    /// moves the session back in time so that it is over in `seconds`
+    fn session_ends_in(db: &mut SqliteConnection, family: &str, seconds: i64) {
+        let created_at =
+            chrono::Utc::now().timestamp() - config::get().tokens.session_lifetime + seconds;
+        diesel::update(crate::schema::sessions::dsl::sessions)
+            .filter(crate::schema::sessions::dsl::id.eq(family))
+            .set(crate::schema::sessions::dsl::created_at.eq(created_at))
+            .execute(db)
+            .unwrap();
+    }
+
+    #[test]
+    fn access_tokens_do_not_outlive_their_session() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let access = test_support::session(&mut db, &key_ring, &alice, None);
+        let family = access.claims.family;
+        session_ends_in(&mut db, &family, 60);
+
+        let capped = JWTInternal::create(&mut db, &alice, &family, &key_ring).unwrap();
+        let ends_at = Session::get(&mut db, &family).unwrap().unwrap().ends_at();
+        assert_eq!(capped.claims.exp, ends_at);
+        // a token expiring with its session has nothing to be refreshed into
+        assert!(!JWTInternal::in_refresh_window(&mut db, &capped.claims).unwrap());
+    }
+
+    #[test]
+    fn ended_sessions_introspect_as_inactive() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let access = test_support::session(&mut db, &key_ring, &alice, None);
+
+        let introspection = Introspection::of(&mut db, &access.token, &key_ring).unwrap();
+        assert!(introspection.active);
+        assert_eq!(introspection.username.as_deref(), Some("alice"));
+
+        // idle for too long
+        diesel::update(crate::schema::sessions::dsl::sessions)
+            .filter(crate::schema::sessions::dsl::id.eq(&access.claims.family))
+            .set(
+                crate::schema::sessions::dsl::last_seen
+                    .eq(chrono::Utc::now().timestamp() - config::get().tokens.idle_timeout),
+            )
+            .execute(&mut *db)
+            .unwrap();
+        let introspection = Introspection::of(&mut db, &access.token, &key_ring).unwrap();
+        assert!(!introspection.active);
+        assert!(introspection.username.is_none());
+        // and revoked on the way
+        assert!(Session::get(&mut db, &access.claims.family)
+            .unwrap()
+            .is_none());
+    }
+}
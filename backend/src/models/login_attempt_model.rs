@@ -0,0 +1,340 @@
+use crate::api_error::ApiError;
+use crate::models::user_model::User;
+use diesel::{
+    insert_into, AsChangeset, ExpressionMethods, Insertable, OptionalExtension, QueryDsl,
+    Queryable, RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+/// failures allowed before every new attempt has to wait
+const BACKOFF_THRESHOLD: i32 = 3;
+/// wait after the first failure past the threshold, doubled on each further failure
+const BACKOFF_BASE: i64 = 1;
+const BACKOFF_MAX: i64 = 60 * 5;
+/// a counter with no failure for this long starts over
+const FAILURES_WINDOW: i64 = 60 * 15;
+const LOCKOUT_DURATION: i64 = 60 * 15;
+const LOGIN_LOCKOUT_THRESHOLD: i32 = 10;
+/// an address can be shared by many users (NAT, proxies), it gets more room than a login
+const IP_LOCKOUT_THRESHOLD: i32 = 50;
+
+/// what failed attempts are counted against
+pub(crate) enum AttemptKey<'a> {
+    Login(&'a str),
+    Ip(&'a str),
+}
+
+impl AttemptKey<'_> {
+    fn as_key(&self) -> String {
+        match self {
+            AttemptKey::Login(login) => format!("login:{login}"),
+            AttemptKey::Ip(ip) => format!("ip:{ip}"),
+        }
+    }
+
+    fn lockout_threshold(&self) -> i32 {
+        match self {
+            AttemptKey::Login(_) => LOGIN_LOCKOUT_THRESHOLD,
+            AttemptKey::Ip(_) => IP_LOCKOUT_THRESHOLD,
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug)]
+#[diesel(table_name = crate::schema::login_attempts)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct LoginAttempt {
+    pub(crate) attempt_key: String,
+    pub(crate) failures: i32,
+    pub(crate) last_failure: i64,
+    pub(crate) locked_until: i64,
+}
+
+/// a failure `reserve` counted ahead of the verification of an attempt
+pub(crate) struct Reservation {
+    attempt_key: String,
+    lockout_threshold: i32,
+    /// last failure of the key before the reservation
+    previous_failure: i64,
+    reserved_at: i64,
+}
+
+impl LoginAttempt {
+    fn get(db: &mut SqliteConnection, key: &str) -> Result<Option<LoginAttempt>, ApiError> {
+        crate::schema::login_attempts::dsl::login_attempts
+            .filter(crate::schema::login_attempts::dsl::attempt_key.eq(key))
+            .select(LoginAttempt::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    /// seconds to wait after the last failure before a new attempt is considered
+    fn backoff(failures: i32) -> i64 {
+        if failures < BACKOFF_THRESHOLD {
+            return 0;
+        }
+        let exponent = u32::try_from(failures - BACKOFF_THRESHOLD).unwrap_or(u32::MAX);
+        BACKOFF_BASE
+            .checked_shl(exponent)
+            .map_or(BACKOFF_MAX, |wait| wait.min(BACKOFF_MAX))
+    }
+
+    /// refuses the attempt while the key is locked out or still backing off
+    pub(crate) fn check(db: &mut SqliteConnection, key: &AttemptKey) -> Result<(), ApiError> {
+        let Some(attempt) = Self::get(db, &key.as_key())? else {
+            return Ok(());
+        };
+        let now = chrono::Utc::now().timestamp();
+        if attempt.locked_until > now
+            || now < attempt.last_failure + Self::backoff(attempt.failures)
+        {
+            return Err(ApiError::TooManyAttempts);
+        }
+        Ok(())
+    }
+
+    /// checks the keys and counts the attempt as failed before it is verified, in one go, so
+    /// parallel guesses can't all pass the check before any failure is recorded. a right
+    /// attempt gives its reservations back with `release`, or `clear`s the key
+    pub(crate) fn reserve(
+        db: &mut SqliteConnection,
+        keys: &[&AttemptKey],
+    ) -> Result<Vec<Reservation>, ApiError> {
+        for key in keys {
+            Self::check(db, key)?;
+        }
+        let mut reservations = Vec::with_capacity(keys.len());
+        for key in keys {
+            let previous_failure =
+                Self::get(db, &key.as_key())?.map_or(0, |attempt| attempt.last_failure);
+            let attempt = Self::count_failure(db, key)?;
+            reservations.push(Reservation {
+                attempt_key: attempt.attempt_key,
+                lockout_threshold: key.lockout_threshold(),
+                previous_failure,
+                reserved_at: attempt.last_failure,
+            });
+        }
+        Ok(reservations)
+    }
+
+    /// undoes the failures `reserve` counted, for an attempt that turned out right or couldn't
+    /// be verified at all. the last failure goes back to what it was, unless another attempt
+    /// failed since
+    pub(crate) fn release(
+        db: &mut SqliteConnection,
+        reservations: &[Reservation],
+    ) -> Result<(), ApiError> {
+        for reservation in reservations {
+            let Some(attempt) = Self::get(db, &reservation.attempt_key)? else {
+                continue;
+            };
+            let failures = (attempt.failures - 1).max(0);
+            let locked_until = if failures < reservation.lockout_threshold {
+                0
+            } else {
+                attempt.locked_until
+            };
+            let last_failure = if attempt.last_failure == reservation.reserved_at {
+                reservation.previous_failure
+            } else {
+                attempt.last_failure
+            };
+            diesel::update(crate::schema::login_attempts::dsl::login_attempts)
+                .filter(crate::schema::login_attempts::dsl::attempt_key.eq(&attempt.attempt_key))
+                .set((
+                    crate::schema::login_attempts::dsl::failures.eq(failures),
+                    crate::schema::login_attempts::dsl::last_failure.eq(last_failure),
+                    crate::schema::login_attempts::dsl::locked_until.eq(locked_until),
+                ))
+                .execute(db)
+                .map_err(|e| {
+                    error!("{e:?}");
+                    ApiError::Internal
+                })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record_failure(
+        db: &mut SqliteConnection,
+        key: &AttemptKey,
+    ) -> Result<(), ApiError> {
+        Self::count_failure(db, key).map(|_| ())
+    }
+
+    fn count_failure(
+        db: &mut SqliteConnection,
+        key: &AttemptKey,
+    ) -> Result<LoginAttempt, ApiError> {
+        let attempt_key = key.as_key();
+        let now = chrono::Utc::now().timestamp();
+        let previous = Self::get(db, &attempt_key)?
+            .filter(|attempt| now - attempt.last_failure <= FAILURES_WINDOW);
+        let failures = previous.as_ref().map_or(0, |attempt| attempt.failures) + 1;
+        let locked_until = if failures >= key.lockout_threshold() {
+            warn!("locking out {attempt_key} after {failures} failed logins");
+            now + LOCKOUT_DURATION
+        } else {
+            previous.map_or(0, |attempt| attempt.locked_until)
+        };
+        let attempt = LoginAttempt {
+            attempt_key,
+            failures,
+            last_failure: now,
+            locked_until,
+        };
+        insert_into(crate::schema::login_attempts::dsl::login_attempts)
+            .values(&attempt)
+            .on_conflict(crate::schema::login_attempts::dsl::attempt_key)
+            .do_update()
+            .set(&attempt)
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(attempt)
+    }
+
+    pub(crate) fn clear(db: &mut SqliteConnection, key: &AttemptKey) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::login_attempts::dsl::login_attempts
+                .filter(crate::schema::login_attempts::dsl::attempt_key.eq(key.as_key())),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    pub(crate) fn for_user(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<Option<LoginAttempt>, ApiError> {
+        Self::get(db, &AttemptKey::Login(&user.login).as_key())
+    }
+
+    /// every key currently locked out or backing off
+    pub(crate) fn get_active(db: &mut SqliteConnection) -> Result<Vec<LoginAttempt>, ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        crate::schema::login_attempts::dsl::login_attempts
+            .filter(crate::schema::login_attempts::dsl::last_failure.ge(now - FAILURES_WINDOW))
+            .or_filter(crate::schema::login_attempts::dsl::locked_until.gt(now))
+            .select(LoginAttempt::as_select())
+            .load(db)
+            .map(|attempts| {
+                attempts
+                    .into_iter()
+                    .filter(|attempt| {
+                        attempt.locked_until > now
+                            || now < attempt.last_failure + Self::backoff(attempt.failures)
+                    })
+                    .collect()
+            })
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::try_get_connection;
+    use crate::test_support;
+
+    fn locked(db: &mut SqliteConnection, key: &AttemptKey) -> bool {
+        LoginAttempt::get(db, &key.as_key())
+            .unwrap()
+            .is_some_and(|attempt| attempt.locked_until > chrono::Utc::now().timestamp())
+    }
+
+    /// failures made long enough ago that their backoff is over, but still counted
+    fn failed_before(db: &mut SqliteConnection, key: &AttemptKey, failures: i32, ago: i64) {
+        insert_into(crate::schema::login_attempts::dsl::login_attempts)
+            .values(LoginAttempt {
+                attempt_key: key.as_key(),
+                failures,
+                last_failure: chrono::Utc::now().timestamp() - ago,
+                locked_until: 0,
+            })
+            .execute(db)
+            .unwrap();
+    }
+
+    #[test]
+    fn logins_lock_after_ten_failures_and_addresses_after_fifty() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        for (key, threshold) in [
+            (AttemptKey::Login("alice"), LOGIN_LOCKOUT_THRESHOLD),
+            (AttemptKey::Ip("10.1.2.3"), IP_LOCKOUT_THRESHOLD),
+        ] {
+            for _ in 1..threshold {
+                LoginAttempt::record_failure(&mut db, &key).unwrap();
+            }
+            assert!(!locked(&mut db, &key), "{} locked early", key.as_key());
+            LoginAttempt::record_failure(&mut db, &key).unwrap();
+            assert!(locked(&mut db, &key), "{} not locked", key.as_key());
+            assert!(matches!(
+                LoginAttempt::check(&mut db, &key),
+                Err(ApiError::TooManyAttempts)
+            ));
+        }
+    }
+
+    #[test]
+    fn released_reservations_leave_the_counter_as_it_was() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let key = AttemptKey::Login("alice");
+        failed_before(&mut db, &key, 2, 400);
+        let before = LoginAttempt::get(&mut db, &key.as_key()).unwrap().unwrap();
+
+        let reservations = LoginAttempt::reserve(&mut db, &[&key]).unwrap();
+        let reserved = LoginAttempt::get(&mut db, &key.as_key()).unwrap().unwrap();
+        assert_eq!(reserved.failures, 3);
+        LoginAttempt::release(&mut db, &reservations).unwrap();
+        let released = LoginAttempt::get(&mut db, &key.as_key()).unwrap().unwrap();
+        assert_eq!(released.failures, before.failures);
+        assert_eq!(released.last_failure, before.last_failure);
+        assert_eq!(released.locked_until, 0);
+        // no backoff is left from the reservation
+        assert!(LoginAttempt::check(&mut db, &key).is_ok());
+    }
+
+    #[test]
+    fn a_reservation_holds_back_parallel_attempts() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let login = AttemptKey::Login("alice");
+        let ip = AttemptKey::Ip("10.1.2.3");
+        failed_before(&mut db, &login, LOGIN_LOCKOUT_THRESHOLD - 1, 400);
+
+        // the last guess allowed is in flight, a parallel one can't pass the check meanwhile
+        let in_flight = LoginAttempt::reserve(&mut db, &[&login, &ip]).unwrap();
+        assert!(matches!(
+            LoginAttempt::reserve(&mut db, &[&login, &ip]),
+            Err(ApiError::TooManyAttempts)
+        ));
+        // the refused attempt counted nothing against the address
+        let ip_attempt = LoginAttempt::get(&mut db, &ip.as_key()).unwrap().unwrap();
+        assert_eq!(ip_attempt.failures, 1);
+
+        // it turned out right
+        LoginAttempt::release(&mut db, &in_flight).unwrap();
+        assert!(!locked(&mut db, &login));
+        assert!(LoginAttempt::reserve(&mut db, &[&login, &ip]).is_ok());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::api_error::ApiError;
+use crate::models::totp_model::Totp;
+use crate::models::user_model::User;
+use diesel::{
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use uuid::Uuid;
+
+const MFA_CHALLENGE_LIFETIME: i64 = 60 * 5;
+const MFA_CHALLENGE_MAX_ATTEMPTS: i32 = 5;
+
+/// pending second step of a login whose password was already checked
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = crate::schema::mfa_challenges)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct MfaChallenge {
+    pub(crate) id: String,
+    pub(crate) user_id: i32,
+    pub(crate) attempts: i32,
+    pub(crate) expires_at: i64,
+}
+
+impl MfaChallenge {
+    pub(crate) fn create(db: &mut SqliteConnection, user: &User) -> Result<Self, ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        if let Err(e) = diesel::delete(
+            crate::schema::mfa_challenges::dsl::mfa_challenges
+                .filter(crate::schema::mfa_challenges::dsl::expires_at.lt(now)),
+        )
+        .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        let challenge = MfaChallenge {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id,
+            attempts: 0,
+            expires_at: now + MFA_CHALLENGE_LIFETIME,
+        };
+        insert_into(crate::schema::mfa_challenges::dsl::mfa_challenges)
+            .values(&challenge)
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(challenge)
+    }
+
+    fn delete(db: &mut SqliteConnection, challenge_id: &str) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::mfa_challenges::dsl::mfa_challenges
+                .filter(crate::schema::mfa_challenges::dsl::id.eq(challenge_id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    /// user a still valid challenge was issued for
+    pub(crate) fn pending_user(
+        db: &mut SqliteConnection,
+        challenge_id: &str,
+    ) -> Result<Option<User>, ApiError> {
+        let user_id = crate::schema::mfa_challenges::dsl::mfa_challenges
+            .filter(crate::schema::mfa_challenges::dsl::id.eq(challenge_id))
+            .filter(
+                crate::schema::mfa_challenges::dsl::expires_at.ge(chrono::Utc::now().timestamp()),
+            )
+            .select(crate::schema::mfa_challenges::dsl::user_id)
+            .first::<i32>(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        match user_id {
+            Some(user_id) => Ok(User::get(db, user_id).ok()),
+            None => Ok(None),
+        }
+    }
+
+    /// counts an attempt at a still valid challenge, which is dropped once it had too many.
+    /// returns the user it was issued for
+    fn attempt(db: &mut SqliteConnection, challenge_id: &str) -> Result<User, ApiError> {
+        let challenge = crate::schema::mfa_challenges::dsl::mfa_challenges
+            .filter(crate::schema::mfa_challenges::dsl::id.eq(challenge_id))
+            .filter(
+                crate::schema::mfa_challenges::dsl::expires_at.ge(chrono::Utc::now().timestamp()),
+            )
+            .select(MfaChallenge::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?
+            .ok_or(ApiError::Mfa)?;
+        if challenge.attempts >= MFA_CHALLENGE_MAX_ATTEMPTS {
+            Self::delete(db, &challenge.id)?;
+            return Err(ApiError::Mfa);
+        }
+        diesel::update(crate::schema::mfa_challenges::dsl::mfa_challenges)
+            .filter(crate::schema::mfa_challenges::dsl::id.eq(&challenge.id))
+            .set(crate::schema::mfa_challenges::dsl::attempts.eq(challenge.attempts + 1))
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        User::get(db, challenge.user_id).map_err(|_| ApiError::Mfa)
+    }
+
+    /// checks the second factor against the challenge, which is consumed on success and
+    /// dropped after too many wrong codes
+    pub(crate) fn resolve(
+        db: &mut SqliteConnection,
+        challenge_id: &str,
+        code: &str,
+    ) -> Result<User, ApiError> {
+        let user = Self::attempt(db, challenge_id)?;
+        if Totp::verify(db, &user, code)? {
+            Self::delete(db, challenge_id)?;
+            Ok(user)
+        } else {
+            Err(ApiError::Mfa)
+        }
+    }
+
+    /// confirms the enrollment a login began for a user whose role requires a second factor,
+    /// with a code of the new secret. the challenge is consumed on success, and the recovery
+    /// codes handed out along with the user. challenges of users already enrolled are refused
+    pub(crate) fn enroll(
+        db: &mut SqliteConnection,
+        challenge_id: &str,
+        code: &str,
+    ) -> Result<(User, Vec<String>), ApiError> {
+        let user = Self::attempt(db, challenge_id)?;
+        if Totp::is_enabled(db, &user)? {
+            return Err(ApiError::Mfa);
+        }
+        let recovery_codes = Totp::confirm_enrollment(db, &user, code)?;
+        Self::delete(db, challenge_id)?;
+        Ok((user, recovery_codes))
+    }
+}
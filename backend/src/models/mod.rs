@@ -1,8 +1,23 @@
+pub(crate) mod api_token_model;
+pub(crate) mod audit_log_model;
 pub(crate) mod domain_rule_model;
+pub(crate) mod federated_identity_model;
+pub(crate) mod federation_state_model;
 pub(crate) mod group_model;
 pub(crate) mod group_user_model;
 pub(crate) mod jwt_model;
+pub(crate) mod login_attempt_model;
+pub(crate) mod mfa_challenge_model;
+pub(crate) mod oidc_client_model;
+pub(crate) mod oidc_code_model;
+pub(crate) mod password_history_model;
+pub(crate) mod password_reset_model;
+pub(crate) mod recovery_code_model;
+pub(crate) mod role_mfa_model;
 pub(crate) mod role_model;
 pub(crate) mod role_user_model;
+pub(crate) mod service_account_model;
+pub(crate) mod session_model;
+pub(crate) mod totp_model;
 pub(crate) mod url_rule_model;
 pub(crate) mod user_model;
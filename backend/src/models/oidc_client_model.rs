@@ -0,0 +1,283 @@
+use crate::api_error::{ApiError, OAuthError};
+use crate::helpers::{random_token, sha256_hex};
+use crate::models::jwt_model::JWTInternal;
+use crate::models::oidc_code_model::OidcCode;
+use crate::models::session_model::Session;
+use diesel::{
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+
+const CLIENT_SECRET_LENGTH: usize = 48;
+
+/// an application delegating its logins to rauth through openid connect
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::oidc_clients)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct OidcClient {
+    pub(crate) client_id: String,
+    pub(crate) name: String,
+    secret_hash: Option<String>,
+    pub(crate) created_at: i64,
+}
+
+/// what a registered redirect uri may be used for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RedirectKind {
+    /// `redirect_uri` of the authorization request
+    Login,
+    /// `post_logout_redirect_uri` of the end session request
+    Logout,
+}
+
+impl RedirectKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            RedirectKind::Login => "login",
+            RedirectKind::Logout => "logout",
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::oidc_redirect_uris)]
+struct NewRedirectUri<'a> {
+    client_id: &'a str,
+    uri: &'a str,
+    kind: &'a str,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NewOidcClient {
+    pub(crate) name: String,
+    pub(crate) redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub(crate) post_logout_redirect_uris: Vec<String>,
+    /// public clients (single page or native apps) get no secret and have to use pkce
+    #[serde(default)]
+    pub(crate) public: bool,
+}
+
+/// redirect uris are compared as exact strings when used, here they only have to be
+/// absolute http(s) urls without fragment
+fn validate_redirect_uri(uri: &str) -> Result<(), ApiError> {
+    match Url::parse(uri) {
+        Ok(url)
+            if matches!(url.scheme(), "http" | "https")
+                && url.has_host()
+                && url.fragment().is_none()
+                && url.username().is_empty()
+                && url.password().is_none() =>
+        {
+            Ok(())
+        }
+        _ => Err(ApiError::OidcClient),
+    }
+}
+
+impl OidcClient {
+    /// registers the client and returns its secret in clear, it isn't stored and can't be
+    /// shown again
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        new_client: &NewOidcClient,
+    ) -> Result<(OidcClient, Option<String>), ApiError> {
+        if new_client.name.trim().is_empty() || new_client.redirect_uris.is_empty() {
+            return Err(ApiError::OidcClient);
+        }
+        for uri in new_client
+            .redirect_uris
+            .iter()
+            .chain(&new_client.post_logout_redirect_uris)
+        {
+            validate_redirect_uri(uri)?;
+        }
+        let secret = (!new_client.public).then(|| random_token(CLIENT_SECRET_LENGTH));
+        let client = OidcClient {
+            client_id: Uuid::new_v4().to_string(),
+            name: new_client.name.clone(),
+            secret_hash: secret.as_deref().map(sha256_hex),
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        insert_into(crate::schema::oidc_clients::dsl::oidc_clients)
+            .values(&client)
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        client.set_redirect_uris(db, RedirectKind::Login, &new_client.redirect_uris)?;
+        client.set_redirect_uris(
+            db,
+            RedirectKind::Logout,
+            &new_client.post_logout_redirect_uris,
+        )?;
+        Ok((client, secret))
+    }
+
+    pub(crate) fn get_all(db: &mut SqliteConnection) -> Result<Vec<OidcClient>, ApiError> {
+        crate::schema::oidc_clients::dsl::oidc_clients
+            .order(crate::schema::oidc_clients::dsl::created_at)
+            .select(OidcClient::as_select())
+            .load(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn get(db: &mut SqliteConnection, client_id: &str) -> Result<OidcClient, ApiError> {
+        crate::schema::oidc_clients::dsl::oidc_clients
+            .filter(crate::schema::oidc_clients::dsl::client_id.eq(client_id))
+            .select(OidcClient::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?
+            .ok_or(ApiError::OidcClientNotFound)
+    }
+
+    pub(crate) fn is_public(&self) -> bool {
+        self.secret_hash.is_none()
+    }
+
+    /// checks the credentials a client presents to the token endpoint. confidential clients
+    /// need their secret, public ones must not send any
+    pub(crate) fn authenticate(
+        db: &mut SqliteConnection,
+        client_id: &str,
+        secret: Option<&str>,
+    ) -> Result<OidcClient, ApiError> {
+        let invalid_client = ApiError::OAuth {
+            error: OAuthError::InvalidClient,
+        };
+        let client = match Self::get(db, client_id) {
+            Ok(client) => client,
+            Err(ApiError::OidcClientNotFound) => return Err(invalid_client),
+            Err(e) => return Err(e),
+        };
+        match (&client.secret_hash, secret) {
+            (Some(secret_hash), Some(secret)) if *secret_hash == sha256_hex(secret) => Ok(client),
+            (None, None) => Ok(client),
+            _ => Err(invalid_client),
+        }
+    }
+
+    pub(crate) fn redirect_uris(
+        &self,
+        db: &mut SqliteConnection,
+        kind: RedirectKind,
+    ) -> Result<Vec<String>, ApiError> {
+        crate::schema::oidc_redirect_uris::dsl::oidc_redirect_uris
+            .filter(crate::schema::oidc_redirect_uris::dsl::client_id.eq(&self.client_id))
+            .filter(crate::schema::oidc_redirect_uris::dsl::kind.eq(kind.as_str()))
+            .order(crate::schema::oidc_redirect_uris::dsl::id)
+            .select(crate::schema::oidc_redirect_uris::dsl::uri)
+            .load(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    /// whether the uri is one registered for the client, compared as is
+    pub(crate) fn allows_redirect(
+        &self,
+        db: &mut SqliteConnection,
+        kind: RedirectKind,
+        uri: &str,
+    ) -> Result<bool, ApiError> {
+        Ok(self
+            .redirect_uris(db, kind)?
+            .iter()
+            .any(|registered| registered == uri))
+    }
+
+    /// replaces the redirect uris of that kind
+    pub(crate) fn set_redirect_uris(
+        &self,
+        db: &mut SqliteConnection,
+        kind: RedirectKind,
+        uris: &[String],
+    ) -> Result<(), ApiError> {
+        for uri in uris {
+            validate_redirect_uri(uri)?;
+        }
+        if let Err(e) = diesel::delete(crate::schema::oidc_redirect_uris::dsl::oidc_redirect_uris)
+            .filter(crate::schema::oidc_redirect_uris::dsl::client_id.eq(&self.client_id))
+            .filter(crate::schema::oidc_redirect_uris::dsl::kind.eq(kind.as_str()))
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        let mut uris = uris.to_vec();
+        uris.sort();
+        uris.dedup();
+        insert_into(crate::schema::oidc_redirect_uris::dsl::oidc_redirect_uris)
+            .values(
+                uris.iter()
+                    .map(|uri| NewRedirectUri {
+                        client_id: &self.client_id,
+                        uri,
+                        kind: kind.as_str(),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(())
+    }
+
+    pub(crate) fn rename(&mut self, db: &mut SqliteConnection, name: &str) -> Result<(), ApiError> {
+        if name.trim().is_empty() {
+            return Err(ApiError::OidcClient);
+        }
+        diesel::update(crate::schema::oidc_clients::dsl::oidc_clients)
+            .filter(crate::schema::oidc_clients::dsl::client_id.eq(&self.client_id))
+            .set(crate::schema::oidc_clients::dsl::name.eq(name))
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        self.name = name.to_string();
+        Ok(())
+    }
+
+    /// unregisters the client, logging out every session opened through it
+    pub(crate) fn delete(&self, db: &mut SqliteConnection) -> Result<(), ApiError> {
+        for session in Session::for_oidc_client(db, &self.client_id)? {
+            JWTInternal::delete_family(db, &session.id)?;
+        }
+        OidcCode::delete_for_client(db, &self.client_id)?;
+        if let Err(e) = diesel::delete(crate::schema::oidc_redirect_uris::dsl::oidc_redirect_uris)
+            .filter(crate::schema::oidc_redirect_uris::dsl::client_id.eq(&self.client_id))
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        match diesel::delete(
+            crate::schema::oidc_clients::dsl::oidc_clients
+                .filter(crate::schema::oidc_clients::dsl::client_id.eq(&self.client_id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
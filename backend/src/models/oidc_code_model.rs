@@ -0,0 +1,187 @@
+use crate::api_error::ApiError;
+use crate::config;
+use crate::helpers::{random_token, sha256_hex};
+use crate::models::session_model::ClientInfo;
+use crate::models::user_model::User;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use diesel::{
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const CODE_LENGTH: usize = 48;
+/// scopes rauth knows about, others are ignored as the spec asks
+pub(crate) const SUPPORTED_SCOPES: [&str; 4] = ["openid", "profile", "email", "groups"];
+
+/// query of the authorization endpoint
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct AuthorizeRequest {
+    pub(crate) client_id: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) response_type: Option<String>,
+    pub(crate) scope: Option<String>,
+    pub(crate) state: Option<String>,
+    pub(crate) nonce: Option<String>,
+    pub(crate) code_challenge: Option<String>,
+    pub(crate) code_challenge_method: Option<String>,
+    /// `none` or `login`, others are treated as if absent
+    pub(crate) prompt: Option<String>,
+}
+
+impl AuthorizeRequest {
+    /// supported scopes among the requested ones, none when `openid` wasn't asked for
+    pub(crate) fn granted_scope(&self) -> Option<String> {
+        let requested: Vec<&str> = self
+            .scope
+            .as_deref()
+            .unwrap_or_default()
+            .split(' ')
+            .collect();
+        if !requested.contains(&"openid") {
+            return None;
+        }
+        Some(
+            SUPPORTED_SCOPES
+                .into_iter()
+                .filter(|scope| requested.contains(scope))
+                .collect::<Vec<&str>>()
+                .join(" "),
+        )
+    }
+}
+
+/// a pending authorization code, only its hash is stored
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = crate::schema::oidc_codes)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct OidcCode {
+    code_hash: String,
+    pub(crate) client_id: String,
+    pub(crate) user_id: i32,
+    pub(crate) redirect_uri: String,
+    pub(crate) scope: String,
+    pub(crate) nonce: Option<String>,
+    /// s256 challenge, the verifier has to be sent along with the code
+    code_challenge: Option<String>,
+    /// when the user logged in, the session the code was issued from was opened then
+    pub(crate) auth_time: i64,
+    pub(crate) ip: String,
+    pub(crate) user_agent: String,
+    expires_at: i64,
+}
+
+impl OidcCode {
+    /// issues a code for the request, the browser the user authorized from is recorded so the
+    /// session opened at exchange shows it instead of the client's server
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        user: &User,
+        request: &AuthorizeRequest,
+        scope: &str,
+        auth_time: i64,
+        browser: &ClientInfo,
+    ) -> Result<String, ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        if let Err(e) = diesel::delete(crate::schema::oidc_codes::dsl::oidc_codes)
+            .filter(crate::schema::oidc_codes::dsl::expires_at.lt(now))
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        let code = random_token(CODE_LENGTH);
+        insert_into(crate::schema::oidc_codes::dsl::oidc_codes)
+            .values(OidcCode {
+                code_hash: sha256_hex(&code),
+                client_id: request.client_id.clone(),
+                user_id: user.id,
+                redirect_uri: request.redirect_uri.clone(),
+                scope: scope.to_string(),
+                nonce: request.nonce.clone(),
+                code_challenge: request.code_challenge.clone(),
+                auth_time,
+                ip: browser.ip.clone(),
+                user_agent: browser.user_agent.clone(),
+                expires_at: now + config::get().oidc.code_lifetime,
+            })
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(code)
+    }
+
+    /// takes the code out, it can't be exchanged twice whatever the outcome
+    pub(crate) fn redeem(
+        db: &mut SqliteConnection,
+        code: &str,
+    ) -> Result<Option<OidcCode>, ApiError> {
+        let code_hash = sha256_hex(code);
+        let pending = crate::schema::oidc_codes::dsl::oidc_codes
+            .filter(crate::schema::oidc_codes::dsl::code_hash.eq(&code_hash))
+            .select(OidcCode::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        if let Err(e) = diesel::delete(crate::schema::oidc_codes::dsl::oidc_codes)
+            .filter(crate::schema::oidc_codes::dsl::code_hash.eq(&code_hash))
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        Ok(pending.filter(|pending| pending.expires_at >= chrono::Utc::now().timestamp()))
+    }
+
+    /// pkce check, a code issued without challenge must come without verifier
+    pub(crate) fn verifies(&self, code_verifier: Option<&str>) -> bool {
+        match (&self.code_challenge, code_verifier) {
+            (Some(challenge), Some(verifier)) => {
+                URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) == *challenge
+            }
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::oidc_codes::dsl::oidc_codes
+                .filter(crate::schema::oidc_codes::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    pub(crate) fn delete_for_client(
+        db: &mut SqliteConnection,
+        client_id: &str,
+    ) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::oidc_codes::dsl::oidc_codes
+                .filter(crate::schema::oidc_codes::dsl::client_id.eq(client_id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
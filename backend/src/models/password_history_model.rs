@@ -0,0 +1,152 @@
+use crate::api_error::ApiError;
+use crate::models::user_model::User;
+use crate::password_hasher::PasswordHasher;
+use diesel::{
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, RunQueryDsl,
+    SqliteConnection,
+};
+use log::error;
+
+/// hashes kept per user, whatever the policy asks for
+const PASSWORD_HISTORY_MAX: i64 = 24;
+
+/// hashes a user had before the current one
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::password_history)]
+pub(crate) struct PasswordHistory {
+    pub(crate) user_id: i32,
+    pub(crate) hash: String,
+    pub(crate) created_at: i64,
+}
+
+impl PasswordHistory {
+    /// stores the hash being replaced and forgets the oldest ones past the limit
+    pub(crate) fn record(
+        db: &mut SqliteConnection,
+        user: &User,
+        previous_hash: &str,
+    ) -> Result<(), ApiError> {
+        insert_into(crate::schema::password_history::dsl::password_history)
+            .values(PasswordHistory {
+                user_id: user.id,
+                hash: previous_hash.to_string(),
+                created_at: chrono::Utc::now().timestamp(),
+            })
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        let oldest_kept = crate::schema::password_history::dsl::password_history
+            .filter(crate::schema::password_history::dsl::user_id.eq(user.id))
+            .order(crate::schema::password_history::dsl::id.desc())
+            .offset(PASSWORD_HISTORY_MAX - 1)
+            .select(crate::schema::password_history::dsl::id)
+            .first::<i32>(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        if let Some(oldest_kept) = oldest_kept {
+            diesel::delete(crate::schema::password_history::dsl::password_history)
+                .filter(crate::schema::password_history::dsl::user_id.eq(user.id))
+                .filter(crate::schema::password_history::dsl::id.lt(oldest_kept))
+                .execute(db)
+                .map_err(|e| {
+                    error!("{e:?}");
+                    ApiError::Internal
+                })?;
+        }
+        Ok(())
+    }
+
+    /// whether the password matches the current hash or one of the `depth - 1` before it
+    pub(crate) fn is_reused(
+        db: &mut SqliteConnection,
+        user: &User,
+        password: &str,
+        depth: usize,
+    ) -> Result<bool, ApiError> {
+        if depth == 0 {
+            return Ok(false);
+        }
+        let previous = crate::schema::password_history::dsl::password_history
+            .filter(crate::schema::password_history::dsl::user_id.eq(user.id))
+            .order(crate::schema::password_history::dsl::id.desc())
+            .limit(i64::try_from(depth - 1).unwrap_or(PASSWORD_HISTORY_MAX))
+            .select(crate::schema::password_history::dsl::hash)
+            .load::<String>(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(std::iter::once(&user.hash)
+            .chain(previous.iter())
+            .any(|hash| PasswordHasher::verify(password, hash)))
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::password_history::dsl::password_history
+                .filter(crate::schema::password_history::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::helpers::try_get_connection;
+    use crate::password_policy::{PasswordPolicy, PolicyViolation};
+    use crate::test_support;
+
+    fn change_password(db: &mut SqliteConnection, user: &User, password: &str) -> User {
+        let policy = &config::get().passwords.policy;
+        let changed = User {
+            hash: User::hash_new_password(db, policy, user, password).unwrap(),
+            ..user.clone()
+        };
+        User::update_user(db, &changed).unwrap();
+        changed
+    }
+
+    #[test]
+    fn the_last_passwords_of_the_policy_can_not_be_reused() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let policy = PasswordPolicy {
+            history: 2,
+            ..PasswordPolicy::default()
+        };
+        let reused =
+            |db: &mut SqliteConnection, user: &User, password: &str| match User::hash_new_password(
+                db, &policy, user, password,
+            ) {
+                Err(ApiError::PasswordPolicy { violations }) => {
+                    violations == [PolicyViolation::Reused { history: 2 }]
+                }
+                Ok(_) => false,
+                Err(e) => panic!("{e:?}"),
+            };
+
+        assert!(reused(&mut db, &alice, test_support::PASSWORD));
+        let alice = change_password(&mut db, &alice, "S3cond-pass-w0rd");
+        assert!(reused(&mut db, &alice, test_support::PASSWORD));
+        assert!(reused(&mut db, &alice, "S3cond-pass-w0rd"));
+        let alice = change_password(&mut db, &alice, "Th1rd-pass-w0rd");
+        // out of the history the policy looks at
+        assert!(!reused(&mut db, &alice, test_support::PASSWORD));
+        assert!(reused(&mut db, &alice, "S3cond-pass-w0rd"));
+    }
+}
@@ -0,0 +1,110 @@
+use crate::api_error::ApiError;
+use crate::helpers::{random_token, sha256_hex};
+use crate::models::user_model::User;
+use diesel::{
+    insert_into, BoolExpressionMethods, ExpressionMethods, Insertable, OptionalExtension, QueryDsl,
+    RunQueryDsl, SqliteConnection,
+};
+use log::error;
+
+pub(crate) const PASSWORD_RESET_LIFETIME: i64 = 60 * 30;
+/// a new token isn't mailed before this delay, so the endpoint can't be used to flood a mailbox
+const PASSWORD_RESET_INTERVAL: i64 = 60;
+const PASSWORD_RESET_TOKEN_LENGTH: usize = 48;
+
+/// pending reset, only the hash of the token mailed to the user is stored
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::password_resets)]
+pub(crate) struct PasswordReset {
+    pub(crate) user_id: i32,
+    pub(crate) token_hash: String,
+    pub(crate) expires_at: i64,
+}
+
+impl PasswordReset {
+    /// issues a new token for the user, replacing any pending one, and returns it in clear.
+    /// nothing is issued when the previous token is too recent
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<Option<String>, ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        let recent = crate::schema::password_resets::dsl::password_resets
+            .filter(crate::schema::password_resets::dsl::user_id.eq(user.id))
+            .filter(
+                crate::schema::password_resets::dsl::expires_at
+                    .gt(now + PASSWORD_RESET_LIFETIME - PASSWORD_RESET_INTERVAL),
+            )
+            .count()
+            .get_result::<i64>(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        if recent > 0 {
+            return Ok(None);
+        }
+        if let Err(e) = diesel::delete(crate::schema::password_resets::dsl::password_resets)
+            .filter(
+                crate::schema::password_resets::dsl::user_id
+                    .eq(user.id)
+                    .or(crate::schema::password_resets::dsl::expires_at.lt(now)),
+            )
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        }
+        let token = random_token(PASSWORD_RESET_TOKEN_LENGTH);
+        insert_into(crate::schema::password_resets::dsl::password_resets)
+            .values(PasswordReset {
+                user_id: user.id,
+                token_hash: sha256_hex(&token),
+                expires_at: now + PASSWORD_RESET_LIFETIME,
+            })
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(Some(token))
+    }
+
+    /// user a still valid token was issued for, the token is left in place
+    pub(crate) fn pending_user(
+        db: &mut SqliteConnection,
+        token: &str,
+    ) -> Result<Option<User>, ApiError> {
+        let user_id = crate::schema::password_resets::dsl::password_resets
+            .filter(crate::schema::password_resets::dsl::token_hash.eq(sha256_hex(token)))
+            .filter(
+                crate::schema::password_resets::dsl::expires_at.ge(chrono::Utc::now().timestamp()),
+            )
+            .select(crate::schema::password_resets::dsl::user_id)
+            .first::<i32>(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        match user_id {
+            Some(user_id) => Ok(User::get(db, user_id).ok()),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::password_resets::dsl::password_resets
+                .filter(crate::schema::password_resets::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
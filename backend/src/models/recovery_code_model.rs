@@ -0,0 +1,104 @@
+use crate::api_error::ApiError;
+use crate::helpers::{random_token, sha256_hex};
+use crate::models::user_model::User;
+use diesel::{insert_into, ExpressionMethods, Insertable, QueryDsl, RunQueryDsl, SqliteConnection};
+use log::error;
+
+const RECOVERY_CODES_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// single-use second factors for users who lost their authenticator, only stored hashed
+pub(crate) struct RecoveryCode;
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::recovery_codes)]
+struct NewRecoveryCode {
+    user_id: i32,
+    code_hash: String,
+}
+
+/// recovery codes are handed out as `xxxxx-xxxxx`, accept them with any case and separator
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+impl RecoveryCode {
+    /// replaces every code of the user, returns the new ones in clear: they can't be read back
+    pub(crate) fn regenerate(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<Vec<String>, ApiError> {
+        Self::delete_for_user(db, user)?;
+        let codes = (0..RECOVERY_CODES_COUNT)
+            .map(|_| random_token(RECOVERY_CODE_LENGTH).to_ascii_lowercase())
+            .collect::<Vec<String>>();
+        let new_codes = codes
+            .iter()
+            .map(|code| NewRecoveryCode {
+                user_id: user.id,
+                code_hash: sha256_hex(code),
+            })
+            .collect::<Vec<NewRecoveryCode>>();
+        insert_into(crate::schema::recovery_codes::dsl::recovery_codes)
+            .values(&new_codes)
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(codes
+            .iter()
+            .map(|code| format!("{}-{}", &code[..5], &code[5..]))
+            .collect())
+    }
+
+    pub(crate) fn consume(
+        db: &mut SqliteConnection,
+        user: &User,
+        code: &str,
+    ) -> Result<bool, ApiError> {
+        match diesel::update(crate::schema::recovery_codes::dsl::recovery_codes)
+            .filter(crate::schema::recovery_codes::dsl::user_id.eq(user.id))
+            .filter(crate::schema::recovery_codes::dsl::code_hash.eq(sha256_hex(&normalize(code))))
+            .filter(crate::schema::recovery_codes::dsl::used.eq(0))
+            .set(crate::schema::recovery_codes::dsl::used.eq(1))
+            .execute(db)
+        {
+            Ok(n) => Ok(n == 1),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    pub(crate) fn remaining(db: &mut SqliteConnection, user: &User) -> Result<i64, ApiError> {
+        crate::schema::recovery_codes::dsl::recovery_codes
+            .filter(crate::schema::recovery_codes::dsl::user_id.eq(user.id))
+            .filter(crate::schema::recovery_codes::dsl::used.eq(0))
+            .count()
+            .get_result::<i64>(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::recovery_codes::dsl::recovery_codes
+                .filter(crate::schema::recovery_codes::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::api_error::ApiError;
+use crate::models::role_model::Role;
+use diesel::{insert_into, ExpressionMethods, Insertable, QueryDsl, RunQueryDsl, SqliteConnection};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// roles whose members can't log in without a second factor
+#[derive(Insertable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = crate::schema::roles_mfa)]
+pub(crate) struct RoleMfa {
+    pub(crate) role: String,
+}
+
+impl RoleMfa {
+    pub(crate) fn required_for(db: &mut SqliteConnection, role: &Role) -> Result<bool, ApiError> {
+        crate::schema::roles_mfa::dsl::roles_mfa
+            .filter(crate::schema::roles_mfa::dsl::role.eq(&role.role))
+            .count()
+            .get_result::<i64>(db)
+            .map(|n| n > 0)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn get_all(db: &mut SqliteConnection) -> Result<Vec<RoleMfa>, ApiError> {
+        crate::schema::roles_mfa::dsl::roles_mfa
+            .select(crate::schema::roles_mfa::dsl::role)
+            .load::<String>(db)
+            .map(|roles| roles.into_iter().map(|role| RoleMfa { role }).collect())
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn require(db: &mut SqliteConnection, role: &Role) -> Result<(), ApiError> {
+        match insert_into(crate::schema::roles_mfa::dsl::roles_mfa)
+            .values(RoleMfa {
+                role: role.role.clone(),
+            })
+            .on_conflict_do_nothing()
+            .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::CheckViolation,
+                _,
+            )) => Err(ApiError::Role),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    pub(crate) fn drop_requirement(db: &mut SqliteConnection, role: &Role) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::roles_mfa::dsl::roles_mfa
+                .filter(crate::schema::roles_mfa::dsl::role.eq(&role.role)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::api_error::{ApiError, OAuthError};
+use crate::helpers::{random_token, sha256_hex};
+use crate::models::jwt_model::JWTInternal;
+use crate::models::role_model::Role;
+use crate::models::role_user_model::RoleUser;
+use crate::models::user_model::{User, UserKind, NO_PASSWORD};
+use diesel::{
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const CLIENT_SECRET_LENGTH: usize = 48;
+
+/// a caller that isn't a person, such as a script or another server. it is a user of kind
+/// service, so it joins groups and gets rules like anyone, but it only gets tokens through
+/// the client credentials grant
+#[derive(Queryable, Serialize, Deserialize, Debug)]
+pub(crate) struct ServiceAccount {
+    /// id of the user backing the account
+    pub(crate) id: i32,
+    pub(crate) name: String,
+    pub(crate) client_id: String,
+    pub(crate) created_at: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::service_account_credentials)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+struct StoredCredentials {
+    user_id: i32,
+    client_id: String,
+    secret_hash: String,
+    created_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NewServiceAccount {
+    pub(crate) name: String,
+}
+
+impl ServiceAccount {
+    /// creates the account and returns its secret in clear, it isn't stored and can't be
+    /// shown again
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        new_account: &NewServiceAccount,
+    ) -> Result<(ServiceAccount, User, String), ApiError> {
+        let name = new_account.name.trim();
+        if name.is_empty() {
+            return Err(ApiError::ServiceAccount);
+        }
+        let user = insert_into(crate::schema::users::dsl::users)
+            .values((
+                crate::schema::users::dsl::login.eq(name),
+                crate::schema::users::dsl::hash.eq(NO_PASSWORD),
+                crate::schema::users::dsl::kind.eq(UserKind::Service.as_str()),
+            ))
+            .returning(User::as_returning())
+            .get_result(db)
+            .map_err(|_| ApiError::ServiceAccount)?;
+        RoleUser::add_role_to_user(
+            db,
+            &user,
+            &Role {
+                role: "user".to_string(),
+            },
+        )?;
+        let secret = random_token(CLIENT_SECRET_LENGTH);
+        let credentials = StoredCredentials {
+            user_id: user.id,
+            client_id: Uuid::new_v4().to_string(),
+            secret_hash: sha256_hex(&secret),
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        insert_into(crate::schema::service_account_credentials::dsl::service_account_credentials)
+            .values(&credentials)
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        let account = ServiceAccount {
+            id: user.id,
+            name: user.login.clone(),
+            client_id: credentials.client_id,
+            created_at: credentials.created_at,
+        };
+        Ok((account, user, secret))
+    }
+
+    pub(crate) fn get_all(db: &mut SqliteConnection) -> Result<Vec<ServiceAccount>, ApiError> {
+        crate::schema::users::dsl::users
+            .inner_join(
+                crate::schema::service_account_credentials::dsl::service_account_credentials,
+            )
+            .filter(crate::schema::users::dsl::kind.eq(UserKind::Service.as_str()))
+            .order(crate::schema::service_account_credentials::dsl::created_at)
+            .select((
+                crate::schema::users::dsl::id,
+                crate::schema::users::dsl::login,
+                crate::schema::service_account_credentials::dsl::client_id,
+                crate::schema::service_account_credentials::dsl::created_at,
+            ))
+            .load(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn get(db: &mut SqliteConnection, user_id: i32) -> Result<ServiceAccount, ApiError> {
+        crate::schema::users::dsl::users
+            .inner_join(
+                crate::schema::service_account_credentials::dsl::service_account_credentials,
+            )
+            .filter(crate::schema::users::dsl::kind.eq(UserKind::Service.as_str()))
+            .filter(crate::schema::users::dsl::id.eq(user_id))
+            .select((
+                crate::schema::users::dsl::id,
+                crate::schema::users::dsl::login,
+                crate::schema::service_account_credentials::dsl::client_id,
+                crate::schema::service_account_credentials::dsl::created_at,
+            ))
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?
+            .ok_or(ApiError::ServiceAccountNotFound)
+    }
+
+    /// the user behind the client id, when the secret matches. any failure is `invalid_client`
+    pub(crate) fn authenticate(
+        db: &mut SqliteConnection,
+        client_id: &str,
+        secret: Option<&str>,
+    ) -> Result<User, ApiError> {
+        let invalid_client = ApiError::OAuth {
+            error: OAuthError::InvalidClient,
+        };
+        let credentials =
+            crate::schema::service_account_credentials::dsl::service_account_credentials
+                .filter(crate::schema::service_account_credentials::dsl::client_id.eq(client_id))
+                .select(StoredCredentials::as_select())
+                .first(db)
+                .optional()
+                .map_err(|e| {
+                    error!("{e:?}");
+                    ApiError::Internal
+                })?;
+        match (credentials, secret) {
+            (Some(credentials), Some(secret)) if credentials.secret_hash == sha256_hex(secret) => {
+                User::get(db, credentials.user_id).map_err(|_| invalid_client)
+            }
+            _ => Err(invalid_client),
+        }
+    }
+
+    /// replaces the secret and revokes the tokens issued with the previous one
+    pub(crate) fn rotate_secret(&self, db: &mut SqliteConnection) -> Result<String, ApiError> {
+        let secret = random_token(CLIENT_SECRET_LENGTH);
+        diesel::update(
+            crate::schema::service_account_credentials::dsl::service_account_credentials,
+        )
+        .filter(crate::schema::service_account_credentials::dsl::user_id.eq(self.id))
+        .set(crate::schema::service_account_credentials::dsl::secret_hash.eq(sha256_hex(&secret)))
+        .execute(db)
+        .map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?;
+        let user = User::get(db, self.id)?;
+        JWTInternal::invalidate_user(db, &user)?;
+        Ok(secret)
+    }
+
+    pub(crate) fn delete_credentials(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::service_account_credentials::dsl::service_account_credentials
+                .filter(crate::schema::service_account_credentials::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::api_error::ApiError;
+use crate::config;
+use crate::helpers::client_ip;
+use crate::models::user_model::User;
+use actix_web::http::header::USER_AGENT;
+use actix_web::HttpRequest;
+use diesel::{
+    insert_into, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// `last_seen` is only written again once it is older than this, so authenticated requests
+/// don't all end up writing to the database
+pub(crate) const LAST_SEEN_RESOLUTION: i64 = 60;
+
+/// where a login session (a refresh token family) was opened from and last used
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::sessions)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct Session {
+    pub(crate) id: String,
+    pub(crate) user_id: i32,
+    pub(crate) created_at: i64,
+    pub(crate) last_seen: i64,
+    pub(crate) ip: String,
+    pub(crate) user_agent: String,
+    /// openid connect client the session was opened for, none for rauth's own logins
+    pub(crate) oidc_client_id: Option<String>,
+    /// scopes granted to that client, its access tokens are good for nothing else
+    pub(crate) scope: Option<String>,
+}
+
+/// the client a session is opened for
+pub(crate) struct ClientInfo {
+    pub(crate) ip: String,
+    pub(crate) user_agent: String,
+    pub(crate) oidc_client_id: Option<String>,
+    pub(crate) scope: Option<String>,
+}
+
+impl From<&HttpRequest> for ClientInfo {
+    fn from(req: &HttpRequest) -> Self {
+        Self {
+            ip: client_ip(req),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+            oidc_client_id: None,
+            scope: None,
+        }
+    }
+}
+
+impl Session {
+    /// when the session is over at the latest, however active it is
+    pub(crate) fn ends_at(&self) -> i64 {
+        self.created_at + config::get().tokens.session_lifetime
+    }
+
+    /// whether the session is over, having reached its lifetime or been idle for too long
+    pub(crate) fn has_ended(&self, now: i64) -> bool {
+        now >= self.ends_at() || now >= self.last_seen + config::get().tokens.idle_timeout
+    }
+
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        user: &User,
+        family: &str,
+        client: &ClientInfo,
+    ) -> Result<(), ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        insert_into(crate::schema::sessions::dsl::sessions)
+            .values(Session {
+                id: family.to_string(),
+                user_id: user.id,
+                created_at: now,
+                last_seen: now,
+                ip: client.ip.clone(),
+                user_agent: client.user_agent.clone(),
+                oidc_client_id: client.oidc_client_id.clone(),
+                scope: client.scope.clone(),
+            })
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(())
+    }
+
+    pub(crate) fn get(
+        db: &mut SqliteConnection,
+        family: &str,
+    ) -> Result<Option<Session>, ApiError> {
+        crate::schema::sessions::dsl::sessions
+            .filter(crate::schema::sessions::dsl::id.eq(family))
+            .select(Session::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn for_user(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<Vec<Session>, ApiError> {
+        crate::schema::sessions::dsl::sessions
+            .filter(crate::schema::sessions::dsl::user_id.eq(user.id))
+            .order(crate::schema::sessions::dsl::last_seen.desc())
+            .select(Session::as_select())
+            .load(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    /// records activity on the session, from the address it came from
+    pub(crate) fn touch(db: &mut SqliteConnection, family: &str, ip: &str) -> Result<(), ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        diesel::update(crate::schema::sessions::dsl::sessions)
+            .filter(crate::schema::sessions::dsl::id.eq(family))
+            .filter(crate::schema::sessions::dsl::last_seen.lt(now - LAST_SEEN_RESOLUTION))
+            .set((
+                crate::schema::sessions::dsl::last_seen.eq(now),
+                crate::schema::sessions::dsl::ip.eq(ip),
+            ))
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(())
+    }
+
+    /// records activity on the session made by a client on the user's behalf, the address
+    /// stays the user's
+    pub(crate) fn seen(db: &mut SqliteConnection, family: &str) -> Result<(), ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        diesel::update(crate::schema::sessions::dsl::sessions)
+            .filter(crate::schema::sessions::dsl::id.eq(family))
+            .filter(crate::schema::sessions::dsl::last_seen.lt(now - LAST_SEEN_RESOLUTION))
+            .set(crate::schema::sessions::dsl::last_seen.eq(now))
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(db: &mut SqliteConnection, family: &str) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::sessions::dsl::sessions
+                .filter(crate::schema::sessions::dsl::id.eq(family)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    pub(crate) fn delete_for_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        match diesel::delete(
+            crate::schema::sessions::dsl::sessions
+                .filter(crate::schema::sessions::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    /// sessions opened for an openid connect client
+    pub(crate) fn for_oidc_client(
+        db: &mut SqliteConnection,
+        client_id: &str,
+    ) -> Result<Vec<Session>, ApiError> {
+        crate::schema::sessions::dsl::sessions
+            .filter(crate::schema::sessions::dsl::oidc_client_id.eq(client_id))
+            .select(Session::as_select())
+            .load(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    /// drops sessions left without any token, once their last refresh token expired
+    pub(crate) fn purge_orphans(db: &mut SqliteConnection) -> Result<(), ApiError> {
+        match diesel::delete(crate::schema::sessions::dsl::sessions)
+            .filter(
+                crate::schema::sessions::dsl::id.ne_all(
+                    crate::schema::jwt::dsl::jwt.select(crate::schema::jwt::dsl::family_id),
+                ),
+            )
+            .execute(db)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+}
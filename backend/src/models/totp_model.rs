@@ -0,0 +1,272 @@
+use crate::api_error::ApiError;
+use crate::models::recovery_code_model::RecoveryCode;
+use crate::models::user_model::User;
+use diesel::{
+    insert_into, AsChangeset, ExpressionMethods, Insertable, OptionalExtension, QueryDsl,
+    Queryable, RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const TOTP_ISSUER: &str = "rauth";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30;
+/// number of steps accepted before and after the current one, to absorb clock drift
+const TOTP_SKEW: i64 = 1;
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::totp)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub(crate) struct Totp {
+    pub(crate) user_id: i32,
+    pub(crate) secret: String,
+    pub(crate) enabled: i32,
+    pub(crate) last_step: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TotpEnrollment {
+    pub(crate) secret: String,
+    pub(crate) otpauth_url: String,
+}
+
+impl Totp {
+    fn get(db: &mut SqliteConnection, user: &User) -> Result<Option<Totp>, ApiError> {
+        crate::schema::totp::dsl::totp
+            .filter(crate::schema::totp::dsl::user_id.eq(user.id))
+            .select(Totp::as_select())
+            .first(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    pub(crate) fn is_enabled(db: &mut SqliteConnection, user: &User) -> Result<bool, ApiError> {
+        Ok(Self::get(db, user)?.is_some_and(|t| t.enabled == 1))
+    }
+
+    fn as_totp(&self, user: &User) -> Result<TOTP, ApiError> {
+        let secret = Secret::Encoded(self.secret.clone())
+            .to_bytes()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(TOTP::new_unchecked(
+            Algorithm::SHA1,
+            TOTP_DIGITS,
+            1,
+            TOTP_STEP,
+            secret,
+            Some(TOTP_ISSUER.to_string()),
+            user.login.clone(),
+        ))
+    }
+
+    /// returns the step the code was generated for, refusing steps already used so a code
+    /// can't be replayed within its validity window
+    fn matching_step(&self, user: &User, code: &str) -> Result<Option<i64>, ApiError> {
+        let totp = self.as_totp(user)?;
+        let current_step = chrono::Utc::now().timestamp() / TOTP_STEP.cast_signed();
+        let code = code.trim();
+        Ok(
+            ((current_step - TOTP_SKEW)..=(current_step + TOTP_SKEW)).find(|step| {
+                *step > self.last_step
+                    && totp
+                        .generate(step.cast_unsigned() * TOTP_STEP)
+                        .as_bytes()
+                        .eq(code.as_bytes())
+            }),
+        )
+    }
+
+    /// generates a new secret, not enforced at login until confirmed with a valid code
+    pub(crate) fn begin_enrollment(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<TotpEnrollment, ApiError> {
+        if Self::is_enabled(db, user)? {
+            return Err(ApiError::Mfa);
+        }
+        let secret = match Secret::generate_secret().to_encoded() {
+            Secret::Encoded(secret) => secret,
+            Secret::Raw(_) => return Err(ApiError::Internal),
+        };
+        let pending = Totp {
+            user_id: user.id,
+            secret,
+            enabled: 0,
+            last_step: 0,
+        };
+        insert_into(crate::schema::totp::dsl::totp)
+            .values(&pending)
+            .on_conflict(crate::schema::totp::dsl::user_id)
+            .do_update()
+            .set(&pending)
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        Ok(TotpEnrollment {
+            otpauth_url: pending.as_totp(user)?.get_url(),
+            secret: pending.secret,
+        })
+    }
+
+    /// the enrollment begun but not confirmed yet, to show its secret again
+    pub(crate) fn pending_enrollment(
+        db: &mut SqliteConnection,
+        user: &User,
+    ) -> Result<Option<TotpEnrollment>, ApiError> {
+        match Self::get(db, user)?.filter(|t| t.enabled == 0) {
+            Some(pending) => Ok(Some(TotpEnrollment {
+                otpauth_url: pending.as_totp(user)?.get_url(),
+                secret: pending.secret,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// enables the pending secret and hands out a fresh set of recovery codes
+    pub(crate) fn confirm_enrollment(
+        db: &mut SqliteConnection,
+        user: &User,
+        code: &str,
+    ) -> Result<Vec<String>, ApiError> {
+        let Some(pending) = Self::get(db, user)?.filter(|t| t.enabled == 0) else {
+            return Err(ApiError::Mfa);
+        };
+        let Some(step) = pending.matching_step(user, code)? else {
+            return Err(ApiError::Mfa);
+        };
+        diesel::update(crate::schema::totp::dsl::totp)
+            .filter(crate::schema::totp::dsl::user_id.eq(user.id))
+            .set((
+                crate::schema::totp::dsl::enabled.eq(1),
+                crate::schema::totp::dsl::last_step.eq(step),
+            ))
+            .execute(db)
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        RecoveryCode::regenerate(db, user)
+    }
+
+    pub(crate) fn disable(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        diesel::delete(
+            crate::schema::totp::dsl::totp.filter(crate::schema::totp::dsl::user_id.eq(user.id)),
+        )
+        .execute(db)
+        .map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?;
+        RecoveryCode::delete_for_user(db, user)
+    }
+
+    /// checks a second factor: either the current totp code or an unused recovery code,
+    /// which is burnt on success
+    pub(crate) fn verify(
+        db: &mut SqliteConnection,
+        user: &User,
+        code: &str,
+    ) -> Result<bool, ApiError> {
+        let Some(enrolled) = Self::get(db, user)?.filter(|t| t.enabled == 1) else {
+            return Ok(false);
+        };
+        if let Some(step) = enrolled.matching_step(user, code)? {
+            diesel::update(crate::schema::totp::dsl::totp)
+                .filter(crate::schema::totp::dsl::user_id.eq(user.id))
+                .set(crate::schema::totp::dsl::last_step.eq(step))
+                .execute(db)
+                .map_err(|e| {
+                    error!("{e:?}");
+                    ApiError::Internal
+                })?;
+            return Ok(true);
+        }
+        RecoveryCode::consume(db, user, code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::try_get_connection;
+    use crate::test_support;
+
+    /// the code of the user's secret, `steps` away from the current one
+    fn code(db: &mut SqliteConnection, user: &User, steps: i64) -> String {
+        let totp = Totp::get(db, user).unwrap().unwrap().as_totp(user).unwrap();
+        let step = chrono::Utc::now().timestamp() / TOTP_STEP.cast_signed() + steps;
+        totp.generate(step.cast_unsigned() * TOTP_STEP)
+    }
+
+    #[test]
+    fn enrollment_is_confirmed_by_a_code_of_the_new_secret() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+
+        Totp::begin_enrollment(&mut db, &alice).unwrap();
+        let current = code(&mut db, &alice, 0);
+        assert!(!Totp::is_enabled(&mut db, &alice).unwrap());
+        // a pending secret isn't a second factor yet
+        assert!(!Totp::verify(&mut db, &alice, &current).unwrap());
+        assert!(Totp::confirm_enrollment(&mut db, &alice, "000000x").is_err());
+
+        let recovery_codes = Totp::confirm_enrollment(&mut db, &alice, &current).unwrap();
+        assert!(!recovery_codes.is_empty());
+        assert!(Totp::is_enabled(&mut db, &alice).unwrap());
+        assert!(Totp::pending_enrollment(&mut db, &alice).unwrap().is_none());
+        assert!(Totp::begin_enrollment(&mut db, &alice).is_err());
+    }
+
+    #[test]
+    fn codes_are_accepted_once_within_their_window() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        Totp::begin_enrollment(&mut db, &alice).unwrap();
+        let previous = code(&mut db, &alice, -1);
+        let current = code(&mut db, &alice, 0);
+        let next = code(&mut db, &alice, 1);
+        Totp::confirm_enrollment(&mut db, &alice, &current).unwrap();
+
+        // the code confirming the enrollment was used, and so were the steps before it
+        assert!(!Totp::verify(&mut db, &alice, &current).unwrap());
+        assert!(!Totp::verify(&mut db, &alice, &previous).unwrap());
+        // drifting clocks get a step of room
+        assert!(Totp::verify(&mut db, &alice, &next).unwrap());
+        assert!(!Totp::verify(&mut db, &alice, &next).unwrap());
+        let far = code(&mut db, &alice, 3);
+        assert!(!Totp::verify(&mut db, &alice, &far).unwrap());
+    }
+
+    #[test]
+    fn recovery_codes_work_once_and_go_with_the_secret() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        Totp::begin_enrollment(&mut db, &alice).unwrap();
+        let current = code(&mut db, &alice, 0);
+        let recovery_codes = Totp::confirm_enrollment(&mut db, &alice, &current).unwrap();
+        let issued = RecoveryCode::remaining(&mut db, &alice).unwrap();
+
+        assert!(Totp::verify(&mut db, &alice, &recovery_codes[0]).unwrap());
+        assert!(!Totp::verify(&mut db, &alice, &recovery_codes[0]).unwrap());
+        assert_eq!(
+            RecoveryCode::remaining(&mut db, &alice).unwrap(),
+            issued - 1
+        );
+
+        Totp::disable(&mut db, &alice).unwrap();
+        assert!(!Totp::verify(&mut db, &alice, &recovery_codes[1]).unwrap());
+        assert_eq!(RecoveryCode::remaining(&mut db, &alice).unwrap(), 0);
+    }
+}
@@ -1,21 +1,35 @@
 use crate::api_error::ApiError;
+use crate::authenticator::AuthBackend;
+use crate::models::api_token_model::ApiToken;
+use crate::models::federated_identity_model::FederatedIdentity;
+use crate::models::federation_state_model::FederationState;
 use crate::models::group_model::Group;
 use crate::models::group_user_model::GroupUser;
+use crate::models::oidc_code_model::OidcCode;
+use crate::models::password_history_model::PasswordHistory;
+use crate::models::password_reset_model::PasswordReset;
 use crate::models::role_model::Role;
 use crate::models::role_user_model::RoleUser;
+use crate::models::service_account_model::ServiceAccount;
+use crate::models::totp_model::Totp;
+use crate::password_hasher::PasswordHasher;
+use crate::password_policy::{PasswordPolicy, PolicyViolation};
 use crate::schema;
 use crate::schema::groups;
 use crate::schema::users::dsl::users;
-use crate::schema::users::{id, login};
+use crate::schema::users::{backend, email, id, kind, login};
 use diesel::result::{DatabaseErrorKind, Error as DieselError};
-use diesel::ExpressionMethods;
 use diesel::{
     insert_into, AsChangeset, Identifiable, Insertable, JoinOnDsl, QueryDsl, Queryable,
     RunQueryDsl, Selectable, SelectableHelper, SqliteConnection,
 };
-use log::error;
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 
+/// hash of users without password, such as service accounts, no password matches it
+pub(crate) const NO_PASSWORD: &str = "!";
+
 #[derive(
     Identifiable,
     Queryable,
@@ -29,34 +43,57 @@ use serde::{Deserialize, Serialize};
 )]
 #[diesel(table_name = crate::schema::users)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(treat_none_as_null = true)]
 pub(crate) struct User {
     pub(crate) id: i32,
     pub(crate) login: String,
+    /// never serialized, users end up in token claims and api responses
+    #[serde(skip)]
     pub(crate) hash: String,
+    pub(crate) email: Option<String>,
+}
+/// people log in with their password, service accounts only through the client
+/// credentials grant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UserKind {
+    User,
+    Service,
+}
+
+impl UserKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            UserKind::User => "user",
+            UserKind::Service => "service",
+        }
+    }
 }
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct SafeUser {
     pub(crate) id: i32,
     pub(crate) login: String,
+    pub(crate) email: Option<String>,
 }
 impl User {
-    pub(crate) fn create(db: &mut SqliteConnection, u: &NewUser) -> Result<User, ApiError> {
-        if u.hash.len() < 4 {
-            return Err(ApiError::User);
+    pub(crate) fn create(
+        db: &mut SqliteConnection,
+        policy: &PasswordPolicy,
+        u: &NewUser,
+    ) -> Result<User, ApiError> {
+        let violations = policy.violations(&u.login, &u.hash);
+        if !violations.is_empty() {
+            return Err(ApiError::PasswordPolicy { violations });
         }
         let hashed_new_user = NewUser {
             login: u.login.clone(),
-            hash: match bcrypt::hash(&u.hash, 12) {
-                Err(e) => {
-                    error!("{e:?}");
-                    return Err(ApiError::Internal);
-                }
-                Ok(h) => h,
-            },
+            hash: PasswordHasher::get().hash(&u.hash)?,
+            email: u.email.clone(),
         };
         match insert_into(users)
             .values(hashed_new_user)
-            .get_results::<User>(db)
+            .returning(User::as_returning())
+            .get_results(db)
         {
             Ok(mut res) => match res.pop() {
                 Some(created_user) => {
@@ -75,8 +112,13 @@ impl User {
         }
     }
 
+    /// every user but service accounts, listed on their own by [`ServiceAccount::get_all`]
     pub(crate) fn get_all(db: &mut SqliteConnection) -> Result<Vec<User>, ApiError> {
-        match users.select(User::as_select()).load(db) {
+        match users
+            .filter(kind.eq(UserKind::User.as_str()))
+            .select(User::as_select())
+            .load(db)
+        {
             Ok(all_users) => Ok(all_users),
             Err(e) => {
                 error!("1{e:?}");
@@ -100,47 +142,228 @@ impl User {
         }
     }
 
-    pub(crate) fn lookup(
+    /// user with this login, or this email address. only users whose password rauth stores
+    /// are looked up
+    pub(crate) fn find(db: &mut SqliteConnection, login_or_email: &str) -> Result<User, ApiError> {
+        match users
+            .filter(kind.eq(UserKind::User.as_str()))
+            .filter(backend.eq(AuthBackend::Local.as_str()))
+            .filter(login.eq(login_or_email).or(email.eq(login_or_email)))
+            .select(User::as_select())
+            .first(db)
+        {
+            Ok(found_user) => Ok(found_user),
+            Err(diesel::NotFound) => Err(ApiError::UserNotFound),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    /// user with this login, whatever their kind or backend
+    pub(crate) fn by_login(db: &mut SqliteConnection, user_login: &str) -> Result<User, ApiError> {
+        match users
+            .filter(login.eq(user_login))
+            .select(User::as_select())
+            .first(db)
+        {
+            Ok(found_user) => Ok(found_user),
+            Err(diesel::NotFound) => Err(ApiError::UserNotFound),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    /// the user with the root role, created by the first migration
+    pub(crate) fn root(db: &mut SqliteConnection) -> Result<User, ApiError> {
+        match users
+            .inner_join(schema::roles_users::table)
+            .filter(schema::roles_users::dsl::role.eq("root"))
+            .select(User::as_select())
+            .first(db)
+        {
+            Ok(root) => Ok(root),
+            Err(diesel::NotFound) => Err(ApiError::UserNotFound),
+            Err(e) => {
+                error!("{e:?}");
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    /// the user with that login whose password rauth stores. the others are none: service
+    /// accounts go through the client credentials grant and directory users through their
+    /// backend
+    pub(crate) fn find_local(
         db: &mut SqliteConnection,
         user_login: &str,
-        user_password: &str,
-    ) -> Result<User, ApiError> {
-        let matching_user = users
+    ) -> Result<Option<User>, ApiError> {
+        users
             .filter(login.eq(user_login))
+            .filter(kind.eq(UserKind::User.as_str()))
+            .filter(backend.eq(AuthBackend::Local.as_str()))
             .select(User::as_select())
             .first(db)
-            .map_err(|_| ApiError::User)?;
-        if let Ok(matching) = bcrypt::verify(user_password, &matching_user.hash) {
-            return if matching {
-                Ok(matching_user)
-            } else {
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+    }
+
+    /// stores a hash of the same password made with the configured settings. the password
+    /// history isn't touched, and the user can log in with the old hash if this fails
+    pub(crate) fn store_rehash(db: &mut SqliteConnection, user: &User) {
+        if let Err(e) = diesel::update(users)
+            .filter(id.eq(user.id))
+            .set(crate::schema::users::dsl::hash.eq(&user.hash))
+            .execute(db)
+        {
+            error!("couldn't rehash password of user {}: {e:?}", user.id);
+        }
+    }
+
+    /// user an external backend vouched for, created on their first login. the email
+    /// address follows the backend's. a login already taken by a user of another backend
+    /// is refused, the backend doesn't get to take it over
+    pub(crate) fn provision(
+        db: &mut SqliteConnection,
+        user_login: &str,
+        user_email: Option<&str>,
+        user_backend: AuthBackend,
+    ) -> Result<User, ApiError> {
+        let existing = users
+            .filter(login.eq(user_login))
+            .select((User::as_select(), kind, backend))
+            .first::<(User, String, String)>(db)
+            .optional()
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })?;
+        match existing {
+            Some((mut user, user_kind, existing_backend))
+                if user_kind == UserKind::User.as_str()
+                    && existing_backend == user_backend.as_str() =>
+            {
+                if user.email.as_deref() != user_email {
+                    user.email = user_email.map(str::to_string);
+                    Self::update_user(db, &user)?;
+                }
+                Ok(user)
+            }
+            Some((user, _, existing_backend)) => {
+                warn!(
+                    "{} login refused for user {}, who belongs to backend {existing_backend}",
+                    user_backend.as_str(),
+                    user.id
+                );
                 Err(ApiError::User)
-            };
+            }
+            None => Self::create_external(db, user_login, user_email, user_backend.as_str()),
         }
-        Err(ApiError::Internal)
     }
 
+    /// a user without password, who logs in through `user_backend`. refused with
+    /// [`ApiError::User`] when the login is taken
+    pub(crate) fn create_external(
+        db: &mut SqliteConnection,
+        user_login: &str,
+        user_email: Option<&str>,
+        user_backend: &str,
+    ) -> Result<User, ApiError> {
+        let user = insert_into(users)
+            .values((
+                login.eq(user_login),
+                crate::schema::users::dsl::hash.eq(NO_PASSWORD),
+                email.eq(user_email),
+                backend.eq(user_backend),
+            ))
+            .returning(User::as_returning())
+            .get_result(db)
+            .map_err(|e| {
+                error!("couldn't provision {user_login}: {e:?}");
+                ApiError::User
+            })?;
+        RoleUser::add_role_to_user(
+            db,
+            &user,
+            &Role {
+                role: "user".to_string(),
+            },
+        )?;
+        let public_group = Group::get(db, 1)?;
+        GroupUser::add_user_to_group(db, &user, &public_group)?;
+        Ok(user)
+    }
+
+    /// checks a new password for an existing user against the policy, including the
+    /// passwords they used before, and hashes it. `user` carries the login it will be saved with
+    pub(crate) fn hash_new_password(
+        db: &mut SqliteConnection,
+        policy: &PasswordPolicy,
+        user: &User,
+        password: &str,
+    ) -> Result<String, ApiError> {
+        let mut violations = policy.violations(&user.login, password);
+        if PasswordHistory::is_reused(db, user, password, policy.history)? {
+            violations.push(PolicyViolation::Reused {
+                history: policy.history,
+            });
+        }
+        if !violations.is_empty() {
+            return Err(ApiError::PasswordPolicy { violations });
+        }
+        PasswordHasher::get().hash(password)
+    }
+
+    /// the replaced hash goes to the password history when the hash changes
     pub(crate) fn update_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+        let previous = Self::get(db, user.id)?;
         match diesel::update(users)
             .filter(id.eq(user.id))
             .set(user)
             .execute(&mut *db)
         {
-            Ok(_) => Ok(()),
-            Err(DieselError::DatabaseError(e, _)) => match e {
-                DatabaseErrorKind::UniqueViolation | DatabaseErrorKind::NotNullViolation => {
-                    Err(ApiError::User)
+            Ok(_) => {}
+            Err(DieselError::DatabaseError(e, _)) => {
+                return match e {
+                    DatabaseErrorKind::UniqueViolation | DatabaseErrorKind::NotNullViolation => {
+                        Err(ApiError::User)
+                    }
+                    _ => Err(ApiError::Internal),
                 }
-                _ => Err(ApiError::Internal),
-            },
-            Err(_) => Err(ApiError::Internal),
+            }
+            Err(_) => return Err(ApiError::Internal),
+        }
+        if previous.hash != user.hash {
+            PasswordHistory::record(db, user, &previous.hash)?;
         }
+        Ok(())
     }
 
     pub(crate) fn delete_user(db: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
         for group in Self::get_groups(db, user)? {
             GroupUser::remove_user_from_group(db, user, &group)?;
         }
+        Totp::disable(db, user)?;
+        PasswordHistory::delete_for_user(db, user)?;
+        PasswordReset::delete_for_user(db, user)?;
+        OidcCode::delete_for_user(db, user)?;
+        ApiToken::delete_for_user(db, user)?;
+        ServiceAccount::delete_credentials(db, user)?;
+        FederatedIdentity::delete_for_user(db, user)?;
+        FederationState::delete_for_user(db, user)?;
+        if let Err(e) = diesel::delete(crate::schema::mfa_challenges::dsl::mfa_challenges)
+            .filter(crate::schema::mfa_challenges::dsl::user_id.eq(user.id))
+            .execute(db)
+        {
+            error!("{e:?}");
+            return Err(ApiError::Internal);
+        };
         if let Err(e) = diesel::delete(crate::schema::roles_users::dsl::roles_users)
             .filter(crate::schema::roles_users::dsl::user_id.eq(user.id))
             .execute(db)
@@ -178,6 +401,7 @@ impl From<User> for SafeUser {
         Self {
             id: unsafe_user.id,
             login: unsafe_user.login.clone(),
+            email: unsafe_user.email,
         }
     }
 }
@@ -187,4 +411,6 @@ impl From<User> for SafeUser {
 pub struct NewUser {
     pub login: String,
     pub hash: String,
+    #[serde(default)]
+    pub email: Option<String>,
 }
@@ -0,0 +1,126 @@
+use crate::api_error::ApiError;
+use crate::config::{self, Argon2Config, PasswordConfig};
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use log::error;
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+/// how new password hashes are made. hashes of the other algorithm are still verified, and
+/// replaced at the next successful login
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HashAlgorithm {
+    /// phc strings, `$argon2id$v=19$m=..,t=..,p=..$salt$hash`
+    Argon2id,
+    /// `$2b$` hashes, what rauth stored before argon2id
+    Bcrypt,
+}
+
+/// the one place passwords are hashed and verified, with the configured algorithm and costs
+pub(crate) struct PasswordHasher {
+    algorithm: HashAlgorithm,
+    argon2: Params,
+    bcrypt_cost: u32,
+}
+
+impl PasswordHasher {
+    /// the configuration is validated at startup, so its argon2 parameters are usable
+    pub(crate) fn from_config(config: &PasswordConfig) -> Self {
+        Self {
+            algorithm: config.algorithm,
+            argon2: argon2_params(&config.argon2).unwrap_or_default(),
+            bcrypt_cost: config.bcrypt_cost,
+        }
+    }
+
+    pub(crate) fn get() -> &'static Self {
+        static HASHER: OnceLock<PasswordHasher> = OnceLock::new();
+        HASHER.get_or_init(|| Self::from_config(&config::get().passwords))
+    }
+
+    fn argon2(&self) -> Argon2<'_> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.argon2.clone())
+    }
+
+    pub(crate) fn hash(&self, password: &str) -> Result<String, ApiError> {
+        match self.algorithm {
+            HashAlgorithm::Argon2id => self
+                .argon2()
+                .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+                .map(|hash| hash.to_string())
+                .map_err(|e| {
+                    error!("{e:?}");
+                    ApiError::Internal
+                }),
+            HashAlgorithm::Bcrypt => bcrypt::hash(password, self.bcrypt_cost).map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            }),
+        }
+    }
+
+    /// false for anything that isn't a hash of the password, users without password included
+    pub(crate) fn verify(password: &str, hash: &str) -> bool {
+        if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).is_ok_and(|parsed| {
+                // the parameters of the hash apply, not the configured ones
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed)
+                    .is_ok()
+            })
+        } else if hash.starts_with("$2") {
+            bcrypt::verify(password, hash).unwrap_or(false)
+        } else {
+            false
+        }
+    }
+
+    /// whether the value is a hash rauth can verify, rather than a placeholder such as the
+    /// one the first migration gives root
+    pub(crate) fn is_hash(hash: &str) -> bool {
+        hash.starts_with("$argon2") || hash.starts_with("$2")
+    }
+
+    /// burns the time a verification takes, when there is no hash to check the password
+    /// against. the timing then doesn't tell whether the login exists
+    pub(crate) fn verify_nothing(&self, password: &str) {
+        static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+        let dummy = DUMMY_HASH.get_or_init(|| self.hash("dummy password").unwrap_or_default());
+        let _ = Self::verify(password, dummy);
+    }
+
+    /// whether the hash was made with another algorithm or other costs than the configured ones
+    pub(crate) fn needs_rehash(&self, hash: &str) -> bool {
+        match self.algorithm {
+            HashAlgorithm::Argon2id => PasswordHash::new(hash).map_or(true, |parsed| {
+                parsed.algorithm != Algorithm::Argon2id.ident()
+                    || Params::try_from(&parsed).map_or(true, |params| {
+                        (params.m_cost(), params.t_cost(), params.p_cost())
+                            != (
+                                self.argon2.m_cost(),
+                                self.argon2.t_cost(),
+                                self.argon2.p_cost(),
+                            )
+                    })
+            }),
+            HashAlgorithm::Bcrypt => hash
+                .split('$')
+                .nth(2)
+                .and_then(|cost| cost.parse::<u32>().ok())
+                .is_none_or(|cost| !hash.starts_with("$2") || cost != self.bcrypt_cost),
+        }
+    }
+}
+
+/// none when argon2 refuses the costs
+pub(crate) fn argon2_params(config: &Argon2Config) -> Option<Params> {
+    Params::new(
+        config.memory_cost,
+        config.time_cost,
+        config.parallelism,
+        None,
+    )
+    .ok()
+}
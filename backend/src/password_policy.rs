@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// most used passwords from public breach corpora, one per line, lowercase
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+/// logins shorter than this are too likely to show up by chance to be refused
+const MIN_LOGIN_LENGTH_CHECKED: usize = 3;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PasswordPolicy {
+    pub(crate) min_length: usize,
+    pub(crate) required_classes: Vec<CharacterClass>,
+    pub(crate) reject_common: bool,
+    pub(crate) reject_login: bool,
+    /// number of previous passwords, the current one included, that can't be reused
+    pub(crate) history: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CharacterClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharacterClass {
+    fn matches(self, c: char) -> bool {
+        match self {
+            CharacterClass::Lowercase => c.is_lowercase(),
+            CharacterClass::Uppercase => c.is_uppercase(),
+            CharacterClass::Digit => c.is_ascii_digit(),
+            CharacterClass::Symbol => !c.is_alphanumeric(),
+        }
+    }
+}
+
+/// one rule of the policy a password breaks, sent back to the client as is
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum PolicyViolation {
+    TooShort { min_length: usize },
+    MissingCharacterClass { class: CharacterClass },
+    Common,
+    ContainsLogin,
+    Reused { history: usize },
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            required_classes: vec![CharacterClass::Lowercase, CharacterClass::Digit],
+            reject_common: true,
+            reject_login: true,
+            history: 5,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// every rule broken by the password, except reuse which needs the stored hashes
+    pub(crate) fn violations(&self, user_login: &str, password: &str) -> Vec<PolicyViolation> {
+        let mut violations = Vec::new();
+        if password.chars().count() < self.min_length {
+            violations.push(PolicyViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        for class in &self.required_classes {
+            if !password.chars().any(|c| class.matches(c)) {
+                violations.push(PolicyViolation::MissingCharacterClass { class: *class });
+            }
+        }
+        if self.reject_common && is_common(password) {
+            violations.push(PolicyViolation::Common);
+        }
+        if self.reject_login
+            && user_login.chars().count() >= MIN_LOGIN_LENGTH_CHECKED
+            && password.to_lowercase().contains(&user_login.to_lowercase())
+        {
+            violations.push(PolicyViolation::ContainsLogin);
+        }
+        violations
+    }
+}
+
+fn common_passwords() -> &'static HashSet<&'static str> {
+    static COMMON: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    COMMON.get_or_init(|| {
+        COMMON_PASSWORDS
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect()
+    })
+}
+
+/// also catches a common password with digits or symbols tacked on, like `monkey2024!`
+fn is_common(password: &str) -> bool {
+    let lowered = password.to_lowercase();
+    let stem = lowered.trim_end_matches(|c: char| !c.is_alphabetic());
+    common_passwords().contains(lowered.as_str())
+        || (!stem.is_empty() && common_passwords().contains(stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn violations_name_every_broken_rule() {
+        let policy = PasswordPolicy::default();
+        assert!(policy
+            .violations("alice", "Corr3ct-horse-battery")
+            .is_empty());
+        assert_eq!(
+            policy.violations("alice", "SHORT"),
+            [
+                PolicyViolation::TooShort { min_length: 10 },
+                PolicyViolation::MissingCharacterClass {
+                    class: CharacterClass::Lowercase
+                },
+                PolicyViolation::MissingCharacterClass {
+                    class: CharacterClass::Digit
+                },
+            ]
+        );
+        // common passwords are caught with their usual suffixes too
+        assert_eq!(
+            policy.violations("alice", "superman2024!"),
+            [PolicyViolation::Common]
+        );
+        assert_eq!(
+            policy.violations("alice", "my-ALICE-is-42x"),
+            [PolicyViolation::ContainsLogin]
+        );
+        assert!(policy.violations("al", "al-is-my-name-42").is_empty());
+
+        let lenient = PasswordPolicy {
+            min_length: 4,
+            required_classes: vec![CharacterClass::Symbol],
+            reject_common: false,
+            reject_login: false,
+            history: 0,
+        };
+        assert!(lenient.violations("alice", "alice!").is_empty());
+        assert_eq!(
+            lenient.violations("alice", "password1"),
+            [PolicyViolation::MissingCharacterClass {
+                class: CharacterClass::Symbol
+            }]
+        );
+    }
+}
@@ -0,0 +1,576 @@
+use crate::access::{self, Access, IDENTITY_HEADER_PREFIX};
+use crate::api_error::ApiError;
+use crate::config::{self, Upstream};
+use crate::credentials::{CredentialSource, Credentials};
+use crate::helpers::client_ip;
+use crate::key_ring::KeyRing;
+use crate::middlewares::authentication_middleware::hand_over_token;
+use crate::StorageState;
+use actix_codec::BytesCodec;
+use actix_web::body::{self, SizedStream};
+use actix_web::dev::Server;
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONNECTION, CONTENT_LENGTH, COOKIE, HOST,
+    PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
+use actix_web::middleware::Logger;
+use actix_web::web::BytesMut;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use awc::Client;
+use futures::{StreamExt, TryStreamExt};
+use log::error;
+use std::time::Duration;
+use url::Url;
+
+/// meant for a single connection, they are never forwarded
+const HOP_BY_HOP: [HeaderName; 8] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// the proxy listener, when upstreams are configured
+pub(crate) fn server(
+    storage: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+) -> std::io::Result<Option<Server>> {
+    let config = config::get();
+    if config.proxy.upstreams.is_empty() {
+        return Ok(None);
+    }
+    let server = HttpServer::new(move || {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(config.proxy.timeout))
+            .disable_redirects()
+            .no_default_headers()
+            .finish();
+        App::new()
+            .app_data(storage.clone())
+            .app_data(key_ring.clone())
+            .app_data(web::Data::new(client))
+            .wrap(Logger::new("%{Host}i %r - %s - %a %{User-Agent}i"))
+            .default_service(web::to(proxy))
+    })
+    .bind(&config.proxy.bind)?
+    .run();
+    Ok(Some(server))
+}
+
+/// headers that aren't to be forwarded: the hop-by-hop ones and those `Connection` names
+fn is_hop_by_hop(headers: &HeaderMap, name: &HeaderName) -> bool {
+    HOP_BY_HOP.contains(name)
+        || headers
+            .get_all(CONNECTION)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|listed| name.as_str().eq_ignore_ascii_case(listed.trim()))
+}
+
+/// the client's headers the upstream gets: no identity the client made up, and none of
+/// rauth's credentials, then the user's identity and where the request came from
+fn upstream_headers(
+    req: &HttpRequest,
+    public_url: &Url,
+    credentials: &Credentials,
+    identity: Vec<(HeaderName, HeaderValue)>,
+) -> Result<HeaderMap, ApiError> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in req.headers() {
+        if is_hop_by_hop(req.headers(), name)
+            || name.as_str().starts_with(IDENTITY_HEADER_PREFIX)
+            || name.as_str().starts_with("x-forwarded-")
+            || [HOST, CONTENT_LENGTH].contains(name)
+            || (name == AUTHORIZATION && credentials.source == CredentialSource::Header)
+        {
+            continue;
+        }
+        if name == COOKIE {
+            let Ok(cookies) = value.to_str() else {
+                continue;
+            };
+            let kept = access::without_session_cookies(cookies);
+            if let Ok(kept) = HeaderValue::from_str(&kept) {
+                if !kept.is_empty() {
+                    headers.append(COOKIE, kept);
+                }
+            }
+            continue;
+        }
+        headers.append(name.clone(), value.clone());
+    }
+    let forwarded = [
+        ("x-forwarded-for", client_ip(req)),
+        ("x-forwarded-proto", public_url.scheme().to_string()),
+        (
+            "x-forwarded-host",
+            match public_url.port() {
+                Some(port) => format!("{}:{port}", public_url.host_str().unwrap_or_default()),
+                None => public_url.host_str().unwrap_or_default().to_string(),
+            },
+        ),
+    ];
+    for (name, value) in forwarded {
+        let value = HeaderValue::from_str(&value).map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?;
+        headers.insert(HeaderName::from_static(name), value);
+    }
+    for (name, value) in identity {
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// the app the request is for, from its `Host`
+fn upstream_of(req: &HttpRequest) -> Option<&'static Upstream> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())?;
+    let url = Url::parse(&format!("http://{host}")).ok()?;
+    config::get().upstream(url.host_str()?)
+}
+
+/// forwards the request to the app it is for once its user may see the page, or sends them
+/// to log in
+async fn proxy(
+    req: HttpRequest,
+    payload: web::Payload,
+    client: web::Data<Client>,
+    storage: web::Data<StorageState>,
+) -> Result<HttpResponse, ApiError> {
+    let Some(upstream) = upstream_of(&req) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let mut public_url = Url::parse(&upstream.origin).map_err(|e| {
+        error!("{e:?}");
+        ApiError::Internal
+    })?;
+    public_url.set_path(req.path());
+    public_url.set_query(req.uri().query());
+    let (credentials, jwt, reissued) = match access::check(&req, &public_url)? {
+        Access::Granted {
+            credentials,
+            jwt,
+            reissued,
+        } => (credentials, jwt, reissued),
+        Access::Unauthenticated => return access::unauthenticated(&req, &public_url, true),
+        Access::Denied => return Ok(HttpResponse::Forbidden().finish()),
+    };
+    access::audit_impersonation(
+        &storage,
+        &jwt.claims,
+        req.method().as_str(),
+        &public_url,
+        &client_ip(&req),
+    )?;
+    let target = format!(
+        "{}{}",
+        upstream.url.trim_end_matches('/'),
+        req.uri()
+            .path_and_query()
+            .map_or(req.path(), |path_and_query| path_and_query.as_str())
+    );
+    let headers = upstream_headers(
+        &req,
+        &public_url,
+        &credentials,
+        access::identity_headers(&jwt.claims)?,
+    )?;
+    let mut response = if req.headers().contains_key(SEC_WEBSOCKET_KEY) {
+        tunnel(&req, payload, &client, &target, headers).await?
+    } else {
+        forward(&req, payload, &client, &target, headers).await?
+    };
+    if reissued {
+        hand_over_token(&mut response, &credentials, &jwt.token)?;
+    }
+    Ok(response)
+}
+
+/// the request body is streamed to the upstream, and its answer back to the client
+async fn forward(
+    req: &HttpRequest,
+    payload: web::Payload,
+    client: &Client,
+    target: &str,
+    headers: HeaderMap,
+) -> Result<HttpResponse, ApiError> {
+    let mut request = client.request(req.method().clone(), target).no_decompress();
+    for (name, value) in &headers {
+        request = request.append_header((name.clone(), value.clone()));
+    }
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    let sent = match length {
+        Some(length) => request.send_body(SizedStream::new(length, payload)).await,
+        None if req.headers().contains_key(TRANSFER_ENCODING) => request.send_stream(payload).await,
+        None => request.send_body(body::None::new()).await,
+    };
+    let upstream_response = match sent {
+        Ok(upstream_response) => upstream_response,
+        Err(e) => {
+            error!("upstream {target} unreachable: {e}");
+            return Ok(HttpResponse::BadGateway().finish());
+        }
+    };
+    let mut response = HttpResponse::build(upstream_response.status());
+    for (name, value) in upstream_response.headers() {
+        if !is_hop_by_hop(upstream_response.headers(), name) && name != CONTENT_LENGTH {
+            response.append_header((name.clone(), value.clone()));
+        }
+    }
+    let length = upstream_response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if let Some(length) = length {
+        response.no_chunking(length);
+    }
+    Ok(response.streaming(upstream_response))
+}
+
+/// websockets are passed through as bytes once both handshakes are done, frames are left to
+/// the client and the upstream
+async fn tunnel(
+    req: &HttpRequest,
+    payload: web::Payload,
+    client: &Client,
+    target: &str,
+    headers: HeaderMap,
+) -> Result<HttpResponse, ApiError> {
+    let Some(key) = req.headers().get(SEC_WEBSOCKET_KEY) else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+    let mut request = client.ws(target);
+    for (name, value) in &headers {
+        if ![SEC_WEBSOCKET_VERSION, SEC_WEBSOCKET_EXTENSIONS].contains(name) {
+            request = request.header(name.clone(), value.clone());
+        }
+    }
+    let (upstream_response, socket) = match request.connect().await {
+        Ok(connected) => connected,
+        Err(e) => {
+            error!("upstream {target} refused the websocket: {e}");
+            return Ok(HttpResponse::BadGateway().finish());
+        }
+    };
+    let mut response = HttpResponse::SwitchingProtocols();
+    response.upgrade("websocket").insert_header((
+        SEC_WEBSOCKET_ACCEPT,
+        actix_http::ws::hash_key(key.as_bytes()).as_slice(),
+    ));
+    if let Some(protocol) = upstream_response.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+        response.insert_header((SEC_WEBSOCKET_PROTOCOL, protocol.clone()));
+    }
+    let (to_upstream, from_upstream) = socket.replace_codec(BytesCodec).split();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = payload
+            .map_err(std::io::Error::other)
+            .forward(to_upstream)
+            .await
+        {
+            error!("websocket to the upstream broken: {e:?}");
+        }
+    });
+    Ok(response.streaming(from_upstream.map_ok(BytesMut::freeze)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::try_get_connection;
+    use crate::models::audit_log_model::{AuditEntry, AuditQuery};
+    use crate::models::domain_rule_model::{DomainRule, NewDomainRule};
+    use crate::models::group_model::{Group, NewGroup};
+    use crate::models::jwt_model::JWTInternal;
+    use crate::models::user_model::User;
+    use crate::test_support::{self, UPSTREAM_ORIGIN};
+    use actix_web::http::header::{ACCEPT, LOCATION};
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+
+    /// tells what it got, the headers lowercased with their values joined
+    async fn echo(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+        let mut headers = BTreeMap::<String, Vec<String>>::new();
+        for (name, value) in req.headers() {
+            headers
+                .entry(name.to_string())
+                .or_default()
+                .push(value.to_str().unwrap_or_default().to_string());
+        }
+        HttpResponse::Created()
+            .insert_header(("x-upstream", "echo"))
+            .json(json!({
+                "method": req.method().as_str(),
+                "path": req.uri().to_string(),
+                "headers": headers
+                    .into_iter()
+                    .map(|(name, values)| (name, values.join("; ")))
+                    .collect::<BTreeMap<String, String>>(),
+                "body": String::from_utf8_lossy(&body),
+            }))
+    }
+
+    /// a user, member of the groups given, and a token of theirs
+    fn user_with_token(
+        storage: &web::Data<StorageState>,
+        key_ring: &KeyRing,
+        login: &str,
+        groups: &[&Group],
+    ) -> String {
+        let mut db = try_get_connection(storage).unwrap();
+        let user = test_support::user(&mut db, login, groups);
+        test_support::session(&mut db, key_ring, &user, None).token
+    }
+
+    #[actix_web::test]
+    async fn forwards_allowed_users_with_their_identity() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let upstream = HttpServer::new(|| App::new().default_service(web::to(echo)))
+            .workers(1)
+            .listen(test_support::upstream_listener().try_clone().unwrap())
+            .unwrap()
+            .run();
+        actix_web::rt::spawn(upstream);
+
+        let devs = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let devs = Group::create_group(
+                &mut db,
+                &NewGroup {
+                    name: "devs".to_string(),
+                },
+            )
+            .unwrap();
+            let domain = Url::parse(UPSTREAM_ORIGIN)
+                .unwrap()
+                .host_str()
+                .unwrap()
+                .to_string();
+            DomainRule::create(
+                &mut db,
+                &NewDomainRule {
+                    domain,
+                    group_id: devs.id,
+                },
+            )
+            .unwrap();
+            devs
+        };
+        let alice = user_with_token(&storage, &key_ring, "alice", &[&devs]);
+        let mallory = user_with_token(&storage, &key_ring, "mallory", &[]);
+
+        let client = Client::builder()
+            .disable_redirects()
+            .no_default_headers()
+            .finish();
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .app_data(web::Data::new(client))
+                .default_service(web::to(proxy)),
+        )
+        .await;
+        let host = Url::parse(UPSTREAM_ORIGIN)
+            .unwrap()
+            .host_str()
+            .unwrap()
+            .to_string();
+        let request = || {
+            test::TestRequest::get()
+                .uri("/page?x=1")
+                .insert_header((HOST, host.as_str()))
+                .peer_addr("10.1.2.3:4567".parse().unwrap())
+        };
+
+        // browsers are sent to log in, other clients are told to
+        let resp = test::call_service(
+            &app,
+            request().insert_header((ACCEPT, "text/html")).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let location = Url::parse(resp.headers().get(LOCATION).unwrap().to_str().unwrap()).unwrap();
+        assert!(location.as_str().starts_with(&config::get().oidc.login_url));
+        assert!(location
+            .query_pairs()
+            .any(|(name, value)| name == "return_to"
+                && value == format!("{UPSTREAM_ORIGIN}/page?x=1")));
+        let resp = test::call_service(&app, request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // unknown hosts and users without a rule go no further
+        let resp = test::call_service(
+            &app,
+            request()
+                .insert_header((HOST, "other.example.com"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(
+            &app,
+            request()
+                .insert_header((AUTHORIZATION, format!("Bearer {mallory}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // the request goes through with the user's identity, minus what the client made up
+        // and rauth's credentials
+        let resp = test::call_service(
+            &app,
+            request()
+                .method(actix_web::http::Method::POST)
+                .insert_header((COOKIE, format!("jwt={alice}; theme=dark")))
+                .insert_header(("remote-user", "root"))
+                .insert_header(("remote-groups", "admins"))
+                .insert_header(("x-forwarded-for", "6.6.6.6"))
+                .insert_header((CONNECTION, "x-secret"))
+                .insert_header(("x-secret", "hop"))
+                .insert_header(("x-app", "kept"))
+                .set_payload("hello upstream")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get("x-upstream").unwrap(), "echo");
+        let seen: Value = test::read_body_json(resp).await;
+        assert_eq!(seen["method"], "POST");
+        assert_eq!(seen["path"], "/base/page?x=1");
+        assert_eq!(seen["body"], "hello upstream");
+        let headers = &seen["headers"];
+        assert_eq!(headers["remote-user"], "alice");
+        assert_eq!(headers["remote-email"], "alice@example.com");
+        assert!(headers["remote-groups"]
+            .as_str()
+            .unwrap()
+            .split(',')
+            .any(|group| group == "devs"));
+        assert!(!headers["remote-groups"]
+            .as_str()
+            .unwrap()
+            .contains("admins"));
+        assert_eq!(headers["cookie"], "theme=dark");
+        assert_eq!(headers["x-forwarded-for"], "10.1.2.3");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], host.as_str());
+        assert_eq!(headers["x-app"], "kept");
+        assert!(headers.get("x-secret").is_none());
+        assert!(headers.get("authorization").is_none());
+
+        // a bearer token is rauth's too
+        let resp = test::call_service(
+            &app,
+            request()
+                .insert_header((AUTHORIZATION, format!("Bearer {alice}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let seen: Value = test::read_body_json(resp).await;
+        assert_eq!(seen["method"], "GET");
+        assert_eq!(seen["headers"]["remote-user"], "alice");
+        assert!(seen["headers"].get("authorization").is_none());
+    }
+
+    #[actix_web::test]
+    async fn audits_and_marks_impersonated_requests() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let upstream = HttpServer::new(|| App::new().default_service(web::to(echo)))
+            .workers(1)
+            .listen(test_support::upstream_listener().try_clone().unwrap())
+            .unwrap()
+            .run();
+        actix_web::rt::spawn(upstream);
+
+        let host = Url::parse(UPSTREAM_ORIGIN)
+            .unwrap()
+            .host_str()
+            .unwrap()
+            .to_string();
+        let (alice, token) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let public = Group::get(&mut db, 1).unwrap();
+            DomainRule::create(
+                &mut db,
+                &NewDomainRule {
+                    domain: host.clone(),
+                    group_id: public.id,
+                },
+            )
+            .unwrap();
+            let root = User::get(&mut db, 1).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[&public]);
+            let token = JWTInternal::impersonate(
+                &mut db,
+                &root,
+                &alice,
+                &key_ring,
+                &test_support::client(),
+            )
+            .unwrap()
+            .token;
+            (alice, token)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .app_data(web::Data::new(Client::default()))
+                .default_service(web::to(proxy)),
+        )
+        .await;
+
+        // the admin's mark can't be made up, and is there for the real thing
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri("/page")
+                .insert_header((HOST, host.as_str()))
+                .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+                .insert_header(("remote-impersonated-by", "nobody"))
+                .peer_addr("10.1.2.3:4567".parse().unwrap())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let seen: Value = test::read_body_json(resp).await;
+        assert_eq!(seen["headers"]["remote-user"], "alice");
+        assert_eq!(seen["headers"]["remote-impersonated-by"], "root");
+
+        let mut db = try_get_connection(&storage).unwrap();
+        let entries = AuditEntry::list(
+            &mut db,
+            &AuditQuery {
+                actor: Some(1),
+                user: Some(alice.id),
+                before: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "impersonated_request");
+        assert_eq!(entries[0].detail, format!("DELETE {UPSTREAM_ORIGIN}/page"));
+        assert_eq!(entries[0].ip, "10.1.2.3");
+    }
+}
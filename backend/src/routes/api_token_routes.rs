@@ -0,0 +1,62 @@
+use crate::api_error::ApiError;
+use crate::helpers::try_get_connection;
+use crate::models::api_token_model::{ApiToken, NewApiToken, API_TOKEN_FAMILY_PREFIX};
+use crate::models::jwt_model::Claims;
+use crate::models::user_model::User;
+use crate::StorageState;
+use actix_web::{web, HttpMessage, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CreatedApiTokenResponse {
+    #[serde(flatten)]
+    api_token: ApiToken,
+    /// shown this once only
+    token: String,
+}
+
+pub(crate) async fn list_api_tokens(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<web::Json<Vec<ApiToken>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    Ok(web::Json(ApiToken::for_user(&mut db, &user)?))
+}
+
+/// api tokens can't create other tokens, or one leaked token would be enough to get
+/// tokens that never expire. neither can admins acting as the user or openid connect
+/// clients, for the same reason
+pub(crate) async fn create_api_token(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+    payload: web::Json<NewApiToken>,
+    req: HttpRequest,
+) -> Result<web::Json<CreatedApiTokenResponse>, ApiError> {
+    let from_api_token = match req.extensions().get::<Claims>() {
+        Some(claims) => {
+            claims.family.starts_with(API_TOKEN_FAMILY_PREFIX)
+                || claims.act.is_some()
+                || claims.aud.is_some()
+        }
+        None => return Err(ApiError::Internal),
+    };
+    if from_api_token {
+        return Err(ApiError::Jwt);
+    }
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    let (api_token, token) = ApiToken::create(&mut db, &user, &payload)?;
+    Ok(web::Json(CreatedApiTokenResponse { api_token, token }))
+}
+
+pub(crate) async fn revoke_api_token(
+    db: web::Data<StorageState>,
+    path: web::Path<(i32, i32)>,
+) -> Result<&'static str, ApiError> {
+    let (user_id, token_id) = path.into_inner();
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, user_id)?;
+    ApiToken::get(&mut db, &user, token_id)?.delete(&mut db)?;
+    Ok("revoked.")
+}
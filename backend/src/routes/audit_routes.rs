@@ -0,0 +1,13 @@
+use crate::api_error::ApiError;
+use crate::helpers::try_get_connection;
+use crate::models::audit_log_model::{AuditEntry, AuditQuery};
+use crate::StorageState;
+use actix_web::web;
+
+pub(crate) async fn list_audit_log(
+    db: web::Data<StorageState>,
+    query: web::Query<AuditQuery>,
+) -> Result<web::Json<Vec<AuditEntry>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    Ok(web::Json(AuditEntry::list(&mut db, &query)?))
+}
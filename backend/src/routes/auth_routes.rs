@@ -1,14 +1,25 @@
+use crate::access;
 use crate::api_error::ApiError;
-use crate::helpers::try_get_connection;
+use crate::authenticator::Authenticators;
+use crate::credentials::Credentials;
+use crate::helpers::{client_ip, escape_html, try_get_connection};
+use crate::key_ring::KeyRing;
 use crate::models::group_model::Groups;
 use crate::models::group_user_model::GroupUser;
-use crate::models::jwt_model::JWTInternal;
+use crate::models::jwt_model::{Introspection, JWTInternal, RefreshToken};
+use crate::models::login_attempt_model::{AttemptKey, LoginAttempt};
+use crate::models::mfa_challenge_model::MfaChallenge;
+use crate::models::role_mfa_model::RoleMfa;
 use crate::models::role_model::Role;
+use crate::models::role_user_model::RoleUser;
+use crate::models::session_model::{ClientInfo, Session};
+use crate::models::totp_model::{Totp, TotpEnrollment};
 use crate::models::user_model::User;
-use crate::{KeySet, StorageState};
-use actix_web::cookie::time::Duration;
-use actix_web::cookie::Cookie;
+use crate::session_cookie::SessionCookie;
+use crate::StorageState;
+use actix_web::http::header::{CACHE_CONTROL, LOCATION};
 use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::SqliteConnection;
 use log::{error, info};
 use serde::{Deserialize, Serialize};
 use url::Url;
@@ -21,6 +32,36 @@ pub(crate) struct AuthPayload {
 #[derive(Serialize)]
 struct AuthResponse {
     jwt: String,
+    refresh_token: String,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RefreshPayload {
+    refresh_token: String,
+}
+
+pub(crate) fn add_session_cookies(
+    response: &mut HttpResponse,
+    access: &JWTInternal,
+    refresh: &RefreshToken,
+) -> Result<(), ApiError> {
+    response
+        .add_cookie(&SessionCookie::Access.build(&access.token))
+        .and_then(|()| response.add_cookie(&SessionCookie::Refresh.build(&refresh.token)))
+        .map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })
+}
+
+/// makes the browser drop the session cookies
+pub(crate) fn remove_session_cookies(response: &mut HttpResponse) -> Result<(), ApiError> {
+    response
+        .add_cookie(&SessionCookie::Access.removal())
+        .and_then(|()| response.add_cookie(&SessionCookie::Refresh.removal()))
+        .map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })
 }
 
 #[inline]
@@ -34,52 +75,324 @@ fn add_close_window_js_script_to_response() -> String {
     .to_string()
 }
 
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MfaPayload {
+    challenge: String,
+    code: String,
+}
+
+/// `action` is where the form is posted to, `/auth/mfa/`
+#[inline]
+pub(crate) fn mfa_form(challenge: &MfaChallenge, action: &str) -> String {
+    format!(
+        "
+    <form method=\"post\" action=\"{action}\">
+    <label for=\"code\">two-factor code or recovery code: </label>
+    <input type=\"text\" name=\"code\" id=\"code\" autocomplete=\"one-time-code\" autofocus />
+    <input type=\"hidden\" name=\"challenge\" value=\"{}\" />
+    <input type=\"submit\" value=\"verify\" />
+    </form>
+    ",
+        challenge.id
+    )
+}
+
+/// the new secret of a user enrolling at login, and the form confirming it with a code.
+/// `action` is where the form is posted to, `/auth/mfa/enroll/`
+pub(crate) fn enrollment_form(
+    challenge: &MfaChallenge,
+    enrollment: &TotpEnrollment,
+    action: &str,
+) -> String {
+    format!(
+        "
+    <p>your role requires two-factor authentication. add this key to your authenticator app:</p>
+    <p><code>{secret}</code></p>
+    <p><a href=\"{url}\">{url}</a></p>
+    <form method=\"post\" action=\"{action}\">
+    <label for=\"code\">code shown by the app: </label>
+    <input type=\"text\" name=\"code\" id=\"code\" autocomplete=\"one-time-code\" autofocus />
+    <input type=\"hidden\" name=\"challenge\" value=\"{challenge}\" />
+    <input type=\"submit\" value=\"enable\" />
+    </form>
+    ",
+        secret = escape_html(&enrollment.secret),
+        url = escape_html(&enrollment.otpauth_url),
+        action = escape_html(action),
+        challenge = escape_html(&challenge.id),
+    )
+}
+
+/// the recovery codes handed out once enrolled, they are shown this once
+pub(crate) fn recovery_codes_list(recovery_codes: &[String]) -> String {
+    format!(
+        "
+    <p>two-factor authentication is enabled. keep these recovery codes, each works once:</p>
+    <ul>{}</ul>
+    ",
+        recovery_codes
+            .iter()
+            .map(|code| format!("<li><code>{}</code></li>", escape_html(code)))
+            .collect::<Vec<String>>()
+            .concat()
+    )
+}
+
+/// where a login stands once the password is right
+pub(crate) enum LoginStep {
+    /// the user has a second factor, the challenge is answered with its code
+    SecondFactor(MfaChallenge),
+    /// the user's role requires a second factor they don't have yet. the challenge only
+    /// confirms the enrollment, with a code of the new secret
+    Enrollment(MfaChallenge, TotpEnrollment),
+    LoggedIn(User),
+}
+
+/// first step of a login, failures count toward the lockout of the login and of `ip`
+pub(crate) async fn check_password(
+    storage: &web::Data<StorageState>,
+    authenticators: web::Data<Authenticators>,
+    login: &str,
+    password: &str,
+    ip: &str,
+) -> Result<LoginStep, ApiError> {
+    let login_key = AttemptKey::Login(login);
+    let ip_key = AttemptKey::Ip(ip);
+    let reservations = {
+        let mut db = try_get_connection(storage)?;
+        LoginAttempt::reserve(&mut db, &[&login_key, &ip_key])?
+    };
+    let authenticated = {
+        let storage = storage.clone();
+        let (login, password) = (login.to_string(), password.to_string());
+        web::block(move || authenticators.authenticate(&storage, &login, &password))
+            .await
+            .map_err(|e| {
+                error!("{e:?}");
+                ApiError::Internal
+            })
+            .and_then(|authenticated| authenticated)
+    };
+    let mut db = try_get_connection(storage)?;
+    let user = match authenticated {
+        Ok(user) => user,
+        // the reserved failures stand
+        Err(ApiError::User) => return Err(ApiError::User),
+        Err(e) => {
+            LoginAttempt::release(&mut db, &reservations)?;
+            return Err(e);
+        }
+    };
+    LoginAttempt::release(&mut db, &reservations)?;
+    // with a second factor pending the counter is kept, so guessing codes still counts
+    if Totp::is_enabled(&mut db, &user)? {
+        return Ok(LoginStep::SecondFactor(MfaChallenge::create(
+            &mut db, &user,
+        )?));
+    }
+    let role = RoleUser::roles_from_user(&mut db, &user)?;
+    if RoleMfa::required_for(&mut db, &role)? {
+        let enrollment = Totp::begin_enrollment(&mut db, &user)?;
+        return Ok(LoginStep::Enrollment(
+            MfaChallenge::create(&mut db, &user)?,
+            enrollment,
+        ));
+    }
+    LoginAttempt::clear(&mut db, &login_key)?;
+    Ok(LoginStep::LoggedIn(user))
+}
+
+/// second step of a login, wrong codes count toward the lockout of the login
+fn second_step<T>(
+    db: &mut SqliteConnection,
+    challenge: &str,
+    resolve: impl FnOnce(&mut SqliteConnection) -> Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    let Some(pending_user) = MfaChallenge::pending_user(db, challenge)? else {
+        return Err(ApiError::Mfa);
+    };
+    let login_key = AttemptKey::Login(&pending_user.login);
+    LoginAttempt::check(db, &login_key)?;
+    match resolve(db) {
+        Ok(resolved) => {
+            LoginAttempt::clear(db, &login_key)?;
+            Ok(resolved)
+        }
+        Err(ApiError::Mfa) => {
+            LoginAttempt::record_failure(db, &login_key)?;
+            Err(ApiError::Mfa)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// the code of a user's second factor
+pub(crate) fn check_mfa(
+    db: &mut SqliteConnection,
+    challenge: &str,
+    code: &str,
+) -> Result<User, ApiError> {
+    second_step(db, challenge, |db| {
+        MfaChallenge::resolve(db, challenge, code)
+    })
+}
+
+/// the first code of the secret a user enrolls at login, returns their recovery codes
+pub(crate) fn check_enrollment(
+    db: &mut SqliteConnection,
+    challenge: &str,
+    code: &str,
+) -> Result<(User, Vec<String>), ApiError> {
+    second_step(db, challenge, |db| {
+        MfaChallenge::enroll(db, challenge, code)
+    })
+}
+
 pub(crate) async fn auth(
     db: web::Data<StorageState>,
+    authenticators: web::Data<Authenticators>,
     payload: web::Form<AuthPayload>,
-    key_set: web::Data<KeySet>,
+    key_ring: web::Data<KeyRing>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
+    let step = check_password(
+        &db,
+        authenticators,
+        &payload.login,
+        &payload.password,
+        &client_ip(&req),
+    )
+    .await?;
     let mut db = try_get_connection(&db)?;
-    let user = User::lookup(&mut db, &payload.login, &payload.password)?;
-    let new_jwt = JWTInternal::create(&mut db, &user, &key_set.encoding)?;
-    JWTInternal::register(&mut db, &new_jwt)?;
-    let jwt_cookie = Cookie::build("jwt", &new_jwt.token)
-        .path("/")
-        .domain(".localhost.dummy")
-        .max_age(Duration::weeks(1))
-        .finish();
-    let mut response = HttpResponse::Ok()
-        .insert_header(actix_web::http::header::ContentType::html())
-        .body(format!(
-            "logged in. {}",
-            add_close_window_js_script_to_response()
-        ));
-    match response.add_cookie(&jwt_cookie) {
-        Ok(()) => Ok(response),
-        Err(e) => {
-            error!("{e:?}");
-            Err(ApiError::Internal)
+    match step {
+        LoginStep::SecondFactor(challenge) => Ok(HttpResponse::Ok()
+            .insert_header(actix_web::http::header::ContentType::html())
+            .body(mfa_form(&challenge, "mfa/"))),
+        LoginStep::Enrollment(challenge, enrollment) => Ok(HttpResponse::Ok()
+            .insert_header(actix_web::http::header::ContentType::html())
+            .body(enrollment_form(&challenge, &enrollment, "mfa/enroll/"))),
+        LoginStep::LoggedIn(user) => {
+            logged_in_response(&mut db, &user, &key_ring, &ClientInfo::from(&req), None)
         }
     }
 }
 
-pub(crate) async fn logout(
+/// second step of the login for users with two-factor authentication enabled
+pub(crate) async fn mfa(
+    db: web::Data<StorageState>,
+    payload: web::Form<MfaPayload>,
+    key_ring: web::Data<KeyRing>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = check_mfa(&mut db, &payload.challenge, &payload.code)?;
+    logged_in_response(&mut db, &user, &key_ring, &ClientInfo::from(&req), None)
+}
+
+/// second step of the login for users whose role requires two-factor authentication but
+/// who had none: enables the secret they were given and shows their recovery codes
+pub(crate) async fn mfa_enroll(
     db: web::Data<StorageState>,
+    payload: web::Form<MfaPayload>,
+    key_ring: web::Data<KeyRing>,
     req: HttpRequest,
-    key_set: web::Data<KeySet>,
 ) -> Result<HttpResponse, ApiError> {
     let mut db = try_get_connection(&db)?;
-    let Some(jwt) = req.cookie("jwt") else {
-        return Err(ApiError::Jwt);
+    let (user, recovery_codes) = check_enrollment(&mut db, &payload.challenge, &payload.code)?;
+    open_session(
+        &mut db,
+        &user,
+        &key_ring,
+        &ClientInfo::from(&req),
+        HttpResponse::Ok()
+            .insert_header(actix_web::http::header::ContentType::html())
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .body(format!(
+                "{}logged in. you can close this window once the codes are saved.",
+                recovery_codes_list(&recovery_codes)
+            )),
+    )
+}
+
+/// opens the session of `user` and sets its cookies on `response`
+pub(crate) fn open_session(
+    db: &mut SqliteConnection,
+    user: &User,
+    key_ring: &KeyRing,
+    client: &ClientInfo,
+    mut response: HttpResponse,
+) -> Result<HttpResponse, ApiError> {
+    let (access, refresh) = JWTInternal::create_session(db, user, key_ring, client)?;
+    add_session_cookies(&mut response, &access, &refresh)?;
+    Ok(response)
+}
+
+/// opens the session and sets its cookies, sending the user on to `return_to` when there is
+/// one. it has to be checked with `access::allows_return_to` already
+pub(crate) fn logged_in_response(
+    db: &mut SqliteConnection,
+    user: &User,
+    key_ring: &KeyRing,
+    client: &ClientInfo,
+    return_to: Option<&str>,
+) -> Result<HttpResponse, ApiError> {
+    let response = match return_to {
+        Some(return_to) => HttpResponse::Found()
+            .insert_header((LOCATION, return_to))
+            .finish(),
+        None => HttpResponse::Ok()
+            .insert_header(actix_web::http::header::ContentType::html())
+            .body(format!(
+                "logged in. {}",
+                add_close_window_js_script_to_response()
+            )),
+    };
+    open_session(db, user, key_ring, client, response)
+}
+
+/// rotates the refresh token, read from the json body or from the refresh token cookie
+pub(crate) async fn refresh(
+    db: web::Data<StorageState>,
+    req: HttpRequest,
+    payload: Option<web::Json<RefreshPayload>>,
+    key_ring: web::Data<KeyRing>,
+) -> Result<HttpResponse, ApiError> {
+    let raw_refresh_token = match (payload, SessionCookie::Refresh.read(&req)) {
+        (Some(payload), _) => payload.into_inner().refresh_token,
+        (None, Some(cookie)) => cookie,
+        (None, None) => return Err(ApiError::Jwt),
     };
-    let claims = match JWTInternal::validate_jwt(&mut db, jwt.value(), &key_set.decoding) {
+    let mut db = try_get_connection(&db)?;
+    let (access, refresh) = JWTInternal::rotate(&mut db, &raw_refresh_token, &key_ring)?;
+    Session::touch(&mut db, &access.claims.family, &client_ip(&req))?;
+    let mut response = HttpResponse::Ok().json(AuthResponse {
+        jwt: access.token.clone(),
+        refresh_token: refresh.token.clone(),
+    });
+    add_session_cookies(&mut response, &access, &refresh)?;
+    Ok(response)
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LogoutQuery {
+    /// where to send the user once logged out, ignored unless `access::allows_return_to` it
+    return_to: Option<String>,
+}
+pub(crate) async fn logout(
+    db: web::Data<StorageState>,
+    credentials: Credentials,
+    key_ring: web::Data<KeyRing>,
+    query: web::Query<LogoutQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let claims = match JWTInternal::validate_jwt(&mut db, &credentials.token, &key_ring) {
         Ok(claims) => claims,
         Err(e) => {
             error!("{e:?}");
             return Err(ApiError::Internal);
         }
     };
-    match JWTInternal::delete(&mut db, &claims.jti) {
+    match JWTInternal::delete_family(&mut db, &claims.family) {
         Ok(()) => (),
         Err(e) => {
             error!("{e:?}");
@@ -87,13 +400,18 @@ pub(crate) async fn logout(
         }
     }
 
-    let mut response = HttpResponse::Ok()
-        .insert_header(actix_web::http::header::ContentType::html())
-        .body(format!(
-            "logged out.{}",
-            add_close_window_js_script_to_response()
-        ));
-    response.del_cookie("jwt");
+    let mut response = match &query.return_to {
+        Some(return_to) if access::allows_return_to(&mut db, return_to)? => HttpResponse::Found()
+            .insert_header((LOCATION, return_to.as_str()))
+            .finish(),
+        _ => HttpResponse::Ok()
+            .insert_header(actix_web::http::header::ContentType::html())
+            .body(format!(
+                "logged out.{}",
+                add_close_window_js_script_to_response()
+            )),
+    };
+    remove_session_cookies(&mut response)?;
     Ok(response)
 }
 
@@ -131,6 +449,164 @@ pub(crate) async fn has_access(
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IntrospectionPayload {
+    token: String,
+    /// accepted as the spec asks, every kind of token is looked up anyway
+    token_type_hint: Option<String>,
+}
+/// token introspection, rfc 7662, for services handed a rauth token that need to know whether
+/// it is still live. unknown, expired and revoked tokens all answer `active: false`
+pub(crate) async fn introspect(
+    db: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+    payload: web::Form<IntrospectionPayload>,
+) -> Result<HttpResponse, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let introspection = Introspection::of(&mut db, &payload.token, &key_ring)?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .json(introspection))
+}
+
 pub(crate) async fn is_auth() -> Result<&'static str, ApiError> {
     Ok("authed")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::domain_rule_model::{DomainRule, NewDomainRule};
+    use crate::models::group_model::Group;
+    use crate::models::oidc_client_model::{NewOidcClient, OidcClient};
+    use crate::models::service_account_model::{NewServiceAccount, ServiceAccount};
+    use crate::password_hasher::PasswordHasher;
+    use crate::test_support;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn has_access_refuses_tokens_of_openid_connect_clients() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (own, client_token) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let public = Group::get(&mut db, 1).unwrap();
+            DomainRule::create(
+                &mut db,
+                &NewDomainRule {
+                    domain: "app.example.com".to_string(),
+                    group_id: public.id,
+                },
+            )
+            .unwrap();
+            let (client, _) = OidcClient::create(
+                &mut db,
+                &NewOidcClient {
+                    name: "wiki".to_string(),
+                    redirect_uris: vec!["https://wiki.example.com/callback".to_string()],
+                    post_logout_redirect_uris: vec![],
+                    public: false,
+                },
+            )
+            .unwrap();
+            let alice = test_support::user(&mut db, "alice", &[&public]);
+            (
+                test_support::session(&mut db, &key_ring, &alice, None).token,
+                test_support::session(&mut db, &key_ring, &alice, Some(&client.client_id)).token,
+            )
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .route("/auth/has_access/", web::get().to(has_access)),
+        )
+        .await;
+        let request = |token: &str| {
+            test::TestRequest::get()
+                .uri("/auth/has_access/?origin=https://app.example.com")
+                .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request(&own)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // the client was handed the token to read the user's claims, not to act as them
+        let resp = test::call_service(&app, request(&client_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn mfa_challenges_take_a_second_factor_once() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let recovery_codes = test_support::enroll_totp(&mut db, &alice);
+        let challenge = MfaChallenge::create(&mut db, &alice).unwrap();
+
+        assert!(matches!(
+            check_mfa(&mut db, &challenge.id, "not-a-code"),
+            Err(ApiError::Mfa)
+        ));
+        // wrong codes count against the login like wrong passwords
+        let attempt = LoginAttempt::for_user(&mut db, &alice).unwrap().unwrap();
+        assert_eq!(attempt.failures, 1);
+
+        let user = check_mfa(&mut db, &challenge.id, &recovery_codes[0]).unwrap();
+        assert_eq!(user.id, alice.id);
+        assert!(LoginAttempt::for_user(&mut db, &alice).unwrap().is_none());
+        // the challenge was consumed with the login
+        assert!(matches!(
+            check_mfa(&mut db, &challenge.id, &recovery_codes[1]),
+            Err(ApiError::Mfa)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn mfa_challenges_are_dropped_after_too_many_wrong_codes() {
+        let storage = test_support::storage();
+        let mut db = try_get_connection(&storage).unwrap();
+        let alice = test_support::user(&mut db, "alice", &[]);
+        let recovery_codes = test_support::enroll_totp(&mut db, &alice);
+        let challenge = MfaChallenge::create(&mut db, &alice).unwrap();
+
+        for _ in 0..5 {
+            assert!(MfaChallenge::resolve(&mut db, &challenge.id, "not-a-code").is_err());
+        }
+        assert!(MfaChallenge::resolve(&mut db, &challenge.id, &recovery_codes[0]).is_err());
+        assert!(MfaChallenge::pending_user(&mut db, &challenge.id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn service_accounts_can_not_log_in_with_a_password() {
+        let storage = test_support::storage();
+        let authenticators = web::Data::new(Authenticators::from_config(crate::config::get()));
+        {
+            let mut db = try_get_connection(&storage).unwrap();
+            let (_, mut user, _) = ServiceAccount::create(
+                &mut db,
+                &NewServiceAccount {
+                    name: "backup-job".to_string(),
+                },
+            )
+            .unwrap();
+            // even with a password set behind rauth's back
+            user.hash = PasswordHasher::get().hash(test_support::PASSWORD).unwrap();
+            User::update_user(&mut db, &user).unwrap();
+        }
+
+        let refused = check_password(
+            &storage,
+            authenticators,
+            "backup-job",
+            test_support::PASSWORD,
+            "127.0.0.1",
+        )
+        .await;
+        assert!(matches!(refused, Err(ApiError::User)));
+    }
+}
@@ -0,0 +1,378 @@
+use crate::access;
+use crate::api_error::ApiError;
+use crate::config::{self, FederationProvider};
+use crate::federation;
+use crate::helpers::try_get_connection;
+use crate::key_ring::KeyRing;
+use crate::models::federated_identity_model::FederatedIdentity;
+use crate::models::federation_state_model::FederationState;
+use crate::models::group_user_model::GroupUser;
+use crate::models::jwt_model::Claims;
+use crate::models::mfa_challenge_model::MfaChallenge;
+use crate::models::role_mfa_model::RoleMfa;
+use crate::models::role_user_model::RoleUser;
+use crate::models::session_model::ClientInfo;
+use crate::models::totp_model::Totp;
+use crate::models::user_model::User;
+use crate::routes::auth_routes::{enrollment_form, logged_in_response, mfa_form};
+use crate::session_cookie::SessionCookie;
+use crate::StorageState;
+use actix_web::http::header::{ContentType, LOCATION};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+/// recorded as backend of the users created on a federated login, they have no password
+const FEDERATION_BACKEND: &str = "federation";
+
+#[derive(Serialize)]
+pub(crate) struct ProviderListing {
+    id: String,
+    name: String,
+    login_url: String,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FederationLoginQuery {
+    /// where to send the user once logged in, ignored unless `access::allows_return_to` it
+    return_to: Option<String>,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FederationCallbackQuery {
+    state: Option<String>,
+    code: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+fn provider(id: &str) -> Result<&'static FederationProvider, ApiError> {
+    config::get()
+        .federation_provider(id)
+        .ok_or(ApiError::FederationProviderNotFound)
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+/// the providers users can log in with, to show them as buttons
+pub(crate) async fn list_providers() -> web::Json<Vec<ProviderListing>> {
+    let config = config::get();
+    web::Json(
+        config
+            .federation
+            .providers
+            .iter()
+            .map(|provider| ProviderListing {
+                id: provider.id.clone(),
+                name: provider.name.clone(),
+                login_url: format!(
+                    "{}/auth/federation/{}/login/",
+                    config.oidc.issuer, provider.id
+                ),
+            })
+            .collect(),
+    )
+}
+
+/// sends the user to the provider, with a pending login to finish at the callback
+async fn start(
+    db: &web::Data<StorageState>,
+    provider: &FederationProvider,
+    return_to: Option<String>,
+    link_user: Option<&User>,
+) -> Result<HttpResponse, ApiError> {
+    let metadata = federation::discover(provider).await?;
+    let mut db = try_get_connection(db)?;
+    let return_to = match return_to {
+        Some(return_to) if access::allows_return_to(&mut db, &return_to)? => Some(return_to),
+        _ => None,
+    };
+    let (state, pending) = FederationState::create(&mut db, &provider.id, return_to, link_user)?;
+    let mut response = redirect(&federation::authorization_url(
+        provider, &metadata, &state, &pending,
+    )?);
+    response
+        .add_cookie(&SessionCookie::FederationState.build(&state))
+        .map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?;
+    Ok(response)
+}
+
+pub(crate) async fn federation_login(
+    db: web::Data<StorageState>,
+    path: web::Path<String>,
+    query: web::Query<FederationLoginQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let provider = provider(&path)?;
+    start(&db, provider, query.into_inner().return_to, None).await
+}
+
+/// links the subject the user logs in as at the provider to their account, so they can log
+/// in with it afterwards. admins acting as the user can't, the link would outlast them
+pub(crate) async fn federation_link(
+    db: web::Data<StorageState>,
+    path: web::Path<String>,
+    query: web::Query<FederationLoginQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let provider = provider(&path)?;
+    let Some(claims) = req.extensions().get::<Claims>().cloned() else {
+        return Err(ApiError::Internal);
+    };
+    if claims.act.is_some() {
+        return Err(ApiError::Jwt);
+    }
+    start(
+        &db,
+        provider,
+        query.into_inner().return_to,
+        Some(&claims.user),
+    )
+    .await
+}
+
+/// where the provider sends the user back. the provider's login counts as the password, a
+/// user with two-factor authentication still gets asked for their code
+pub(crate) async fn federation_callback(
+    db: web::Data<StorageState>,
+    path: web::Path<String>,
+    query: web::Query<FederationCallbackQuery>,
+    key_ring: web::Data<KeyRing>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let provider = provider(&path)?;
+    let mut response = callback(&db, provider, &query, &key_ring, &req).await?;
+    response
+        .add_cookie(&SessionCookie::FederationState.removal())
+        .map_err(|e| {
+            error!("{e:?}");
+            ApiError::Internal
+        })?;
+    Ok(response)
+}
+
+/// `federation_callback`, once the provider is known
+async fn callback(
+    db: &web::Data<StorageState>,
+    provider: &FederationProvider,
+    query: &FederationCallbackQuery,
+    key_ring: &KeyRing,
+    req: &HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let state = query.state.as_deref().unwrap_or_default();
+    // without it, anyone could have a victim's browser finish a login they started themselves
+    if SessionCookie::FederationState.read(req).as_deref() != Some(state) {
+        info!(
+            "federation with {}: state not started by this browser",
+            provider.id
+        );
+        return Err(ApiError::Federation);
+    }
+    let pending = {
+        let mut db = try_get_connection(db)?;
+        FederationState::redeem(&mut db, state)?
+    }
+    .filter(|pending| pending.provider == provider.id)
+    .ok_or(ApiError::Federation)?;
+    let (Some(code), None) = (&query.code, &query.error) else {
+        info!(
+            "federation with {}: {:?} {:?}",
+            provider.id, query.error, query.error_description
+        );
+        return Err(ApiError::Federation);
+    };
+    let metadata = federation::discover(provider).await?;
+    let identity = federation::exchange(provider, &metadata, code, &pending).await?;
+
+    let mut db = try_get_connection(db)?;
+    if let Some(user_id) = pending.link_user_id {
+        let user = User::get(&mut db, user_id)?;
+        FederatedIdentity::link(&mut db, &provider.id, &identity.subject, &user)?;
+        return Ok(match pending.return_to.as_deref() {
+            Some(return_to) => redirect(return_to),
+            None => HttpResponse::Ok().body("linked."),
+        });
+    }
+    let user = match FederatedIdentity::user(&mut db, &provider.id, &identity.subject)? {
+        Some(user) => user,
+        None if provider.provision => {
+            let Some(login) = identity.login.as_deref() else {
+                error!(
+                    "federation with {}: no {} claim to provision {} with",
+                    provider.id, provider.login_claim, identity.subject
+                );
+                return Err(ApiError::Federation);
+            };
+            // a taken login stays its owner's, they can link the subject themselves
+            let user = User::create_external(
+                &mut db,
+                login,
+                identity.email.as_deref(),
+                FEDERATION_BACKEND,
+            )
+            .map_err(|e| {
+                warn!(
+                    "federation with {}: can't provision {login}: {e}",
+                    provider.id
+                );
+                ApiError::Federation
+            })?;
+            FederatedIdentity::link(&mut db, &provider.id, &identity.subject, &user)?;
+            user
+        }
+        None => {
+            info!(
+                "federation with {}: {} isn't linked to a user",
+                provider.id, identity.subject
+            );
+            return Err(ApiError::Federation);
+        }
+    };
+    let managed: Vec<&str> = provider.groups.values().map(String::as_str).collect();
+    let member_of: Vec<&str> = identity
+        .groups
+        .iter()
+        .filter_map(|group| provider.groups.get(group))
+        .map(String::as_str)
+        .collect();
+    GroupUser::sync_managed_groups(&mut db, &user, &managed, &member_of)?;
+
+    if Totp::is_enabled(&mut db, &user)? {
+        let challenge = MfaChallenge::create(&mut db, &user)?;
+        return Ok(HttpResponse::Ok()
+            .insert_header(ContentType::html())
+            .body(mfa_form(
+                &challenge,
+                &format!("{}/auth/mfa/", config::get().oidc.issuer),
+            )));
+    }
+    let role = RoleUser::roles_from_user(&mut db, &user)?;
+    if RoleMfa::required_for(&mut db, &role)? {
+        let enrollment = Totp::begin_enrollment(&mut db, &user)?;
+        let challenge = MfaChallenge::create(&mut db, &user)?;
+        return Ok(HttpResponse::Ok()
+            .insert_header(ContentType::html())
+            .body(enrollment_form(
+                &challenge,
+                &enrollment,
+                &format!("{}/auth/mfa/enroll/", config::get().oidc.issuer),
+            )));
+    }
+    logged_in_response(
+        &mut db,
+        &user,
+        key_ring,
+        &ClientInfo::from(req),
+        pending.return_to.as_deref(),
+    )
+}
+
+pub(crate) async fn list_identities(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<web::Json<Vec<FederatedIdentity>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    Ok(web::Json(FederatedIdentity::for_user(&mut db, &user)?))
+}
+
+pub(crate) async fn unlink_identity(
+    db: web::Data<StorageState>,
+    path: web::Path<(i32, i32)>,
+) -> Result<&'static str, ApiError> {
+    let (user_id, identity_id) = path.into_inner();
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, user_id)?;
+    FederatedIdentity::get(&mut db, &user, identity_id)?.delete(&mut db)?;
+    Ok("deleted.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::test_support::idp::{carol, idp, serve, MockIdp, CODE};
+    use actix_web::cookie::{Cookie, SameSite};
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use diesel::{QueryDsl, RunQueryDsl, SelectableHelper};
+    use url::Url;
+
+    #[actix_web::test]
+    async fn the_state_only_comes_back_from_the_browser_it_was_handed_to() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let discovery_only = serve(MockIdp {
+            discovered_issuer: None,
+            claims: serde_json::Map::new(),
+            symmetric: false,
+            code_verifier: String::new(),
+        });
+
+        let response = start(&storage, &discovery_only, None, None).await.unwrap();
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+        let state = Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .find(|(name, _)| name == "state")
+            .unwrap()
+            .1
+            .into_owned();
+        let cookie = response
+            .cookies()
+            .find(|cookie| cookie.name() == SessionCookie::FederationState.name())
+            .unwrap();
+        assert_eq!(cookie.value(), state);
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.path(), Some("/auth/federation/"));
+
+        let pending = {
+            let mut db = try_get_connection(&storage).unwrap();
+            crate::schema::federation_states::dsl::federation_states
+                .select(FederationState::as_select())
+                .first(&mut *db)
+                .unwrap()
+        };
+        let provider = FederationProvider {
+            provision: true,
+            ..serve(idp(&pending, carol(&pending)))
+        };
+        let query = FederationCallbackQuery {
+            state: Some(state.clone()),
+            code: Some(CODE.to_string()),
+            error: None,
+            error_description: None,
+        };
+        let back = |cookie: Option<&str>| {
+            let mut req = test::TestRequest::get();
+            if let Some(cookie) = cookie {
+                req = req.cookie(Cookie::new(
+                    SessionCookie::FederationState.name(),
+                    cookie.to_string(),
+                ));
+            }
+            req.to_http_request()
+        };
+
+        // a victim lured to the callback with the state of someone else's login
+        for cookie in [None, Some("another-state")] {
+            assert!(matches!(
+                callback(&storage, &provider, &query, &key_ring, &back(cookie)).await,
+                Err(ApiError::Federation)
+            ));
+        }
+        // the browser that started the login finishes it
+        let response = callback(&storage, &provider, &query, &key_ring, &back(Some(&state)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response
+            .cookies()
+            .any(|cookie| cookie.name() == SessionCookie::Access.name()));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::access::{self, Access};
+use crate::api_error::ApiError;
+use crate::helpers::client_ip;
+use crate::middlewares::authentication_middleware::hand_over_token;
+use crate::StorageState;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::info;
+use serde::Deserialize;
+use url::Url;
+
+pub(crate) const FORWARD_AUTH_PATH: &str = "/auth/forward/";
+
+#[derive(Deserialize)]
+pub(crate) struct ForwardAuthQS {
+    /// nginx's `auth_request` only understands 2xx, 401 and 403. with `redirect=false`
+    /// browsers get a 401 too, its `Location` header tells where to send them
+    redirect: Option<bool>,
+}
+
+fn forwarded_header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// the page the proxy is asked about, rebuilt from the `X-Forwarded-*` headers
+fn forwarded_url(req: &HttpRequest) -> Result<Url, ApiError> {
+    let header = |name: &str| forwarded_header(req, name);
+    let (Some(proto), Some(host)) = (header("x-forwarded-proto"), header("x-forwarded-host"))
+    else {
+        info!("forward auth called without x-forwarded-proto or x-forwarded-host");
+        return Err(ApiError::User);
+    };
+    let uri = header("x-forwarded-uri").unwrap_or("/");
+    Url::parse(&format!("{proto}://{host}{uri}")).map_err(|e| {
+        info!("bad forwarded url {e:?} - {proto}://{host}{uri}");
+        ApiError::User
+    })
+}
+
+/// access check for reverse proxies, nginx `auth_request` or traefik `ForwardAuth`. the page
+/// is given by `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Uri`. answers 200
+/// with `Remote-User`, `Remote-Email` and `Remote-Groups`, 403 when the user's groups have no
+/// rule for the page, and 401 without a valid token. browsers are redirected to the login
+/// page instead. rules don't depend on the method, `X-Forwarded-Method` only goes in the
+/// audit log of impersonated requests
+pub(crate) async fn forward_auth(
+    storage: web::Data<StorageState>,
+    req: HttpRequest,
+    query: web::Query<ForwardAuthQS>,
+) -> Result<HttpResponse, ApiError> {
+    let url = forwarded_url(&req)?;
+    match access::check(&req, &url)? {
+        Access::Granted {
+            credentials,
+            jwt,
+            reissued,
+        } => {
+            let method =
+                forwarded_header(&req, "x-forwarded-method").unwrap_or(req.method().as_str());
+            access::audit_impersonation(&storage, &jwt.claims, method, &url, &client_ip(&req))?;
+            let mut response = HttpResponse::Ok();
+            for header in access::identity_headers(&jwt.claims)? {
+                response.insert_header(header);
+            }
+            let mut response = response.finish();
+            if reissued {
+                hand_over_token(&mut response, &credentials, &jwt.token)?;
+            }
+            Ok(response)
+        }
+        Access::Unauthenticated => {
+            access::unauthenticated(&req, &url, query.redirect.unwrap_or(true))
+        }
+        Access::Denied => Ok(HttpResponse::Forbidden().finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::helpers::try_get_connection;
+    use crate::models::audit_log_model::{AuditEntry, AuditQuery};
+    use crate::models::domain_rule_model::{DomainRule, NewDomainRule};
+    use crate::models::group_model::{Group, NewGroup};
+    use crate::models::jwt_model::JWTInternal;
+    use crate::models::user_model::User;
+    use crate::test_support;
+    use actix_web::http::header::{ACCEPT, AUTHORIZATION, LOCATION};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    fn forwarded(uri: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("x-forwarded-proto", "https"))
+            .insert_header(("x-forwarded-host", "app.example.com"))
+            .insert_header(("x-forwarded-uri", "/page?x=1"))
+            .peer_addr("10.1.2.3:4567".parse().unwrap())
+    }
+
+    #[actix_web::test]
+    async fn answers_with_the_identity_of_allowed_users() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (alice, mallory) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let devs = Group::create_group(
+                &mut db,
+                &NewGroup {
+                    name: "devs".to_string(),
+                },
+            )
+            .unwrap();
+            DomainRule::create(
+                &mut db,
+                &NewDomainRule {
+                    domain: "app.example.com".to_string(),
+                    group_id: devs.id,
+                },
+            )
+            .unwrap();
+            let alice = test_support::user(&mut db, "alice", &[&devs]);
+            let mallory = test_support::user(&mut db, "mallory", &[]);
+            (
+                test_support::session(&mut db, &key_ring, &alice, None).token,
+                test_support::session(&mut db, &key_ring, &mallory, None).token,
+            )
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .route(FORWARD_AUTH_PATH, web::route().to(forward_auth)),
+        )
+        .await;
+        let login_url = |resp: &actix_web::dev::ServiceResponse| {
+            Url::parse(resp.headers().get(LOCATION).unwrap().to_str().unwrap()).unwrap()
+        };
+
+        let resp = test::call_service(
+            &app,
+            forwarded(FORWARD_AUTH_PATH)
+                .insert_header((AUTHORIZATION, format!("Bearer {alice}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("remote-user").unwrap(), "alice");
+        assert_eq!(
+            resp.headers().get("remote-email").unwrap(),
+            "alice@example.com"
+        );
+        assert_eq!(resp.headers().get("remote-groups").unwrap(), "devs");
+        assert!(resp.headers().get("remote-impersonated-by").is_none());
+
+        let resp = test::call_service(
+            &app,
+            forwarded(FORWARD_AUTH_PATH)
+                .insert_header((AUTHORIZATION, format!("Bearer {mallory}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(resp.headers().get("remote-user").is_none());
+
+        // browsers are sent to log in and come back to the page, other clients are told to
+        let resp = test::call_service(
+            &app,
+            forwarded(FORWARD_AUTH_PATH)
+                .insert_header((ACCEPT, "text/html"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let location = login_url(&resp);
+        assert!(location.as_str().starts_with(&config::get().oidc.login_url));
+        assert!(location.query_pairs().any(
+            |(name, value)| name == "return_to" && value == "https://app.example.com/page?x=1"
+        ));
+        let resp = test::call_service(
+            &app,
+            forwarded(&format!("{FORWARD_AUTH_PATH}?redirect=false"))
+                .insert_header((ACCEPT, "text/html"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(login_url(&resp), location);
+        let resp = test::call_service(
+            &app,
+            forwarded(FORWARD_AUTH_PATH)
+                .insert_header((AUTHORIZATION, "Bearer not-a-token"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // the page has to be told
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(FORWARD_AUTH_PATH)
+                .insert_header((AUTHORIZATION, format!("Bearer {alice}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn audits_impersonated_requests_with_the_forwarded_method() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (alice, token) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let public = Group::get(&mut db, 1).unwrap();
+            DomainRule::create(
+                &mut db,
+                &NewDomainRule {
+                    domain: "app.example.com".to_string(),
+                    group_id: public.id,
+                },
+            )
+            .unwrap();
+            let root = User::get(&mut db, 1).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[&public]);
+            let token = JWTInternal::impersonate(
+                &mut db,
+                &root,
+                &alice,
+                &key_ring,
+                &test_support::client(),
+            )
+            .unwrap()
+            .token;
+            (alice, token)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .route(FORWARD_AUTH_PATH, web::route().to(forward_auth)),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            forwarded(FORWARD_AUTH_PATH)
+                .insert_header(("x-forwarded-method", "DELETE"))
+                .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("remote-user").unwrap(), "alice");
+        assert_eq!(
+            resp.headers().get("remote-impersonated-by").unwrap(),
+            "root"
+        );
+
+        let mut db = try_get_connection(&storage).unwrap();
+        let entries = AuditEntry::list(
+            &mut db,
+            &AuditQuery {
+                actor: Some(1),
+                user: Some(alice.id),
+                before: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "impersonated_request");
+        assert_eq!(entries[0].detail, "DELETE https://app.example.com/page?x=1");
+        assert_eq!(entries[0].ip, "10.1.2.3");
+    }
+}
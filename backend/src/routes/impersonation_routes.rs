@@ -0,0 +1,63 @@
+use crate::api_error::ApiError;
+use crate::helpers::{client_ip, try_get_connection};
+use crate::key_ring::KeyRing;
+use crate::models::api_token_model::API_TOKEN_FAMILY_PREFIX;
+use crate::models::audit_log_model::{AuditAction, AuditEntry};
+use crate::models::jwt_model::{Actor, Claims, JWTInternal};
+use crate::models::session_model::ClientInfo;
+use crate::models::user_model::User;
+use crate::StorageState;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct ImpersonationResponse {
+    /// to send as bearer token, it can't be refreshed past `exp`
+    jwt: String,
+    exp: i64,
+    act: Actor,
+}
+
+/// hands the admin a token acting as the user. admins acting as someone else, and api
+/// tokens, can't impersonate in turn
+pub(crate) async fn impersonate_user(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+    key_ring: web::Data<KeyRing>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let Some(claims) = req.extensions().get::<Claims>().cloned() else {
+        return Err(ApiError::Internal);
+    };
+    if claims.act.is_some() || claims.family.starts_with(API_TOKEN_FAMILY_PREFIX) {
+        return Err(ApiError::Jwt);
+    }
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    let access = JWTInternal::impersonate(
+        &mut db,
+        &claims.user,
+        &user,
+        &key_ring,
+        &ClientInfo::from(&req),
+    )?;
+    let Some(actor) = access.claims.act.clone() else {
+        return Err(ApiError::Internal);
+    };
+    AuditEntry::record(
+        &mut db,
+        &actor,
+        &user,
+        AuditAction::Impersonate,
+        &access.claims.family,
+        &client_ip(&req),
+    )?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .json(ImpersonationResponse {
+            exp: access.claims.exp,
+            jwt: access.token,
+            act: actor,
+        }))
+}
@@ -0,0 +1,41 @@
+use crate::api_error::ApiError;
+use crate::helpers::try_get_connection;
+use crate::models::login_attempt_model::{AttemptKey, LoginAttempt};
+use crate::models::user_model::User;
+use crate::StorageState;
+use actix_web::web;
+
+pub(crate) async fn list_lockouts(
+    db: web::Data<StorageState>,
+) -> Result<web::Json<Vec<LoginAttempt>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    Ok(web::Json(LoginAttempt::get_active(&mut db)?))
+}
+
+pub(crate) async fn user_lockout(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<web::Json<Option<LoginAttempt>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    Ok(web::Json(LoginAttempt::for_user(&mut db, &user)?))
+}
+
+pub(crate) async fn clear_user_lockout(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<&'static str, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    LoginAttempt::clear(&mut db, &AttemptKey::Login(&user.login))?;
+    Ok("cleared.")
+}
+
+pub(crate) async fn clear_ip_lockout(
+    db: web::Data<StorageState>,
+    path: web::Path<String>,
+) -> Result<&'static str, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    LoginAttempt::clear(&mut db, &AttemptKey::Ip(&path.into_inner()))?;
+    Ok("cleared.")
+}
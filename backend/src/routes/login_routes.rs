@@ -0,0 +1,558 @@
+use crate::access;
+use crate::api_error::ApiError;
+use crate::authenticator::Authenticators;
+use crate::config;
+use crate::helpers::{client_ip, escape_html, try_get_connection};
+use crate::key_ring::KeyRing;
+use crate::middlewares::authentication_middleware::{authenticate, hand_over_token};
+use crate::models::api_token_model::TokenScope;
+use crate::models::mfa_challenge_model::MfaChallenge;
+use crate::models::session_model::ClientInfo;
+use crate::models::totp_model::{Totp, TotpEnrollment};
+use crate::routes::auth_routes::{
+    check_enrollment, check_mfa, check_password, logged_in_response, open_session,
+    recovery_codes_list, LoginStep,
+};
+use crate::StorageState;
+use actix_web::http::header::{ContentType, CACHE_CONTROL, LOCATION, X_FRAME_OPTIONS};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use diesel::SqliteConnection;
+use log::info;
+use serde::Deserialize;
+use url::Url;
+
+/// the page's own look, branding colors it through `--accent`
+const STYLE: &str = "
+    body { margin: 0; padding: 4rem 1rem; display: flex; justify-content: center;
+        font-family: system-ui, sans-serif; background: #f3f4f6; color: #111827; }
+    main { width: 100%; max-width: 22rem; padding: 2rem; border-radius: 0.5rem;
+        background: #fff; box-shadow: 0 1px 3px rgba(0, 0, 0, 0.1); }
+    h1 { margin: 0 0 1.5rem; font-size: 1.5rem; text-align: center; }
+    .logo { display: block; max-height: 4rem; margin: 0 auto 1rem; }
+    label { display: block; margin-bottom: 1rem; }
+    input { box-sizing: border-box; width: 100%; margin-top: 0.25rem; padding: 0.5rem;
+        border: 1px solid #d1d5db; border-radius: 0.25rem; font-size: 1rem; }
+    button, .button { display: block; box-sizing: border-box; width: 100%; margin-top: 0.5rem;
+        padding: 0.6rem; border: 0; border-radius: 0.25rem; background: var(--accent);
+        color: #fff; font-size: 1rem; text-align: center; text-decoration: none; cursor: pointer; }
+    a { color: var(--accent); }
+    .error { margin-bottom: 1rem; padding: 0.75rem; border-radius: 0.25rem;
+        background: #fee2e2; color: #991b1b; }
+    .providers { margin-top: 1.5rem; }
+";
+
+#[derive(Deserialize)]
+pub(crate) struct LoginPageQuery {
+    /// where to send the user once logged in, ignored unless `access::allows_return_to` it
+    return_to: Option<String>,
+}
+#[derive(Deserialize)]
+pub(crate) struct LoginPagePayload {
+    login: String,
+    password: String,
+    return_to: Option<String>,
+}
+#[derive(Deserialize)]
+pub(crate) struct LoginPageMfaPayload {
+    challenge: String,
+    code: String,
+    return_to: Option<String>,
+}
+
+/// what the page asks for
+enum Form<'a> {
+    /// `login` is filled back in after a failure
+    Password {
+        login: &'a str,
+    },
+    /// the challenge the code answers
+    SecondFactor {
+        challenge: &'a str,
+    },
+    /// the secret a user whose role requires a second factor enrolls, confirmed by a code
+    Enrollment {
+        challenge: &'a str,
+        enrollment: &'a TotpEnrollment,
+    },
+    /// shown once, the user then goes on to `destination`
+    Enrolled {
+        recovery_codes: &'a [String],
+        destination: &'a str,
+    },
+    LoggedIn {
+        login: &'a str,
+    },
+}
+
+fn page_url(path: &str) -> String {
+    format!("{}/auth/{path}", config::get().oidc.issuer)
+}
+
+/// `return_to` when users may be sent there, an empty one is none
+fn return_to(
+    db: &mut SqliteConnection,
+    return_to: Option<&str>,
+) -> Result<Option<String>, ApiError> {
+    match return_to.filter(|return_to| !return_to.is_empty()) {
+        Some(return_to) if access::allows_return_to(db, return_to)? => {
+            Ok(Some(return_to.to_string()))
+        }
+        Some(return_to) => {
+            info!("login page: return_to {return_to} isn't allowed");
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// where the user goes once logged in: `return_to`, the configured default, or back to the
+/// page which then tells who they are
+fn destination(return_to: Option<String>) -> String {
+    return_to
+        .or_else(|| config::get().login.default_return_to.clone())
+        .unwrap_or_else(|| page_url("login/"))
+}
+
+/// what the page tells users whose login failed, other errors are rauth's own
+fn login_error(e: ApiError) -> Result<(StatusCode, String), ApiError> {
+    let status = e.status_code();
+    let message = match e {
+        ApiError::User => "Wrong login or password.".to_string(),
+        ApiError::Mfa => "Wrong code.".to_string(),
+        ApiError::TooManyAttempts => e.to_string(),
+        e => return Err(e),
+    };
+    Ok((status, message))
+}
+
+/// a hidden `return_to` input, so it survives each step of the login
+fn return_to_input(return_to: Option<&str>) -> String {
+    return_to.map_or(String::new(), |return_to| {
+        format!(
+            "<input type=\"hidden\" name=\"return_to\" value=\"{}\" />",
+            escape_html(return_to)
+        )
+    })
+}
+
+/// links to log in with the identity providers instead
+fn provider_links(return_to: Option<&str>) -> String {
+    let links: Vec<String> = config::get()
+        .federation
+        .providers
+        .iter()
+        .filter_map(|provider| {
+            let mut url =
+                Url::parse(&page_url(&format!("federation/{}/login/", provider.id))).ok()?;
+            if let Some(return_to) = return_to {
+                url.query_pairs_mut().append_pair("return_to", return_to);
+            }
+            Some(format!(
+                "<a class=\"button\" href=\"{}\">log in with {}</a>",
+                escape_html(url.as_str()),
+                escape_html(&provider.name)
+            ))
+        })
+        .collect();
+    if links.is_empty() {
+        String::new()
+    } else {
+        format!("<div class=\"providers\">{}</div>", links.concat())
+    }
+}
+
+fn form_html(form: &Form, return_to: Option<&str>) -> String {
+    match form {
+        Form::Password { login } => format!(
+            "
+    <form method=\"post\" action=\"{action}\">
+    <label>login <input type=\"text\" name=\"login\" value=\"{login}\" autocomplete=\"username\" required autofocus /></label>
+    <label>password <input type=\"password\" name=\"password\" autocomplete=\"current-password\" required /></label>
+    {return_to_input}
+    <button type=\"submit\">log in</button>
+    </form>
+    {providers}
+    ",
+            action = escape_html(&page_url("login/")),
+            login = escape_html(login),
+            return_to_input = return_to_input(return_to),
+            providers = provider_links(return_to),
+        ),
+        Form::SecondFactor { challenge } => format!(
+            "
+    <form method=\"post\" action=\"{action}\">
+    <label>two-factor code or recovery code <input type=\"text\" name=\"code\" autocomplete=\"one-time-code\" required autofocus /></label>
+    <input type=\"hidden\" name=\"challenge\" value=\"{challenge}\" />
+    {return_to_input}
+    <button type=\"submit\">verify</button>
+    </form>
+    ",
+            action = escape_html(&page_url("login/mfa/")),
+            challenge = escape_html(challenge),
+            return_to_input = return_to_input(return_to),
+        ),
+        Form::Enrollment {
+            challenge,
+            enrollment,
+        } => format!(
+            "
+    <p>your role requires two-factor authentication. add this key to your authenticator app:</p>
+    <p><code>{secret}</code></p>
+    <p><a href=\"{url}\">open in the app</a></p>
+    <form method=\"post\" action=\"{action}\">
+    <label>code shown by the app <input type=\"text\" name=\"code\" autocomplete=\"one-time-code\" required autofocus /></label>
+    <input type=\"hidden\" name=\"challenge\" value=\"{challenge}\" />
+    {return_to_input}
+    <button type=\"submit\">enable</button>
+    </form>
+    ",
+            secret = escape_html(&enrollment.secret),
+            url = escape_html(&enrollment.otpauth_url),
+            action = escape_html(&page_url("login/mfa/enroll/")),
+            challenge = escape_html(challenge),
+            return_to_input = return_to_input(return_to),
+        ),
+        Form::Enrolled {
+            recovery_codes,
+            destination,
+        } => format!(
+            "
+    {recovery_codes}
+    <a class=\"button\" href=\"{destination}\">continue</a>
+    ",
+            recovery_codes = recovery_codes_list(recovery_codes),
+            destination = escape_html(destination),
+        ),
+        Form::LoggedIn { login } => {
+            let mut logout = Url::parse(&page_url("logout/")).ok();
+            if let Some(logout) = &mut logout {
+                logout
+                    .query_pairs_mut()
+                    .append_pair("return_to", &page_url("login/"));
+            }
+            format!(
+                "
+    <p>logged in as {login}.</p>
+    <a class=\"button\" href=\"{logout}\">log out</a>
+    ",
+                login = escape_html(login),
+                logout = escape_html(logout.as_ref().map_or("", Url::as_str)),
+            )
+        }
+    }
+}
+
+/// the whole page with the configured branding, `error` shown above the form
+fn page(
+    status: StatusCode,
+    form: &Form,
+    return_to: Option<&str>,
+    error: Option<&str>,
+) -> HttpResponse {
+    let branding = &config::get().login;
+    let stylesheet = branding
+        .stylesheet_url
+        .as_deref()
+        .map_or(String::new(), |url| {
+            format!("<link rel=\"stylesheet\" href=\"{}\" />", escape_html(url))
+        });
+    let logo = branding.logo_url.as_deref().map_or(String::new(), |url| {
+        format!(
+            "<img class=\"logo\" src=\"{}\" alt=\"\" />",
+            escape_html(url)
+        )
+    });
+    let error = error.map_or(String::new(), |error| {
+        format!(
+            "<p class=\"error\" role=\"alert\">{}</p>",
+            escape_html(error)
+        )
+    });
+    let body = format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\" />
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" />
+<title>{title}</title>
+<style>:root {{ --accent: {accent}; }}{STYLE}</style>
+{stylesheet}
+</head>
+<body>
+<main>
+{logo}
+<h1>{title}</h1>
+{error}
+{form}
+</main>
+</body>
+</html>
+",
+        title = escape_html(&branding.title),
+        accent = escape_html(&branding.accent_color),
+        form = form_html(form, return_to),
+    );
+    HttpResponse::build(status)
+        .insert_header(ContentType::html())
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .insert_header((X_FRAME_OPTIONS, "DENY"))
+        .body(body)
+}
+
+/// the login page. users already logged in are sent on to `return_to` right away, or told who
+/// they are without one
+pub(crate) async fn login_page(
+    db: web::Data<StorageState>,
+    query: web::Query<LoginPageQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let return_to = {
+        let mut db = try_get_connection(&db)?;
+        return_to(&mut db, query.return_to.as_deref())?
+    };
+    match authenticate(&req, TokenScope::Read) {
+        Ok((credentials, jwt, reissued)) => {
+            let mut response = match &return_to {
+                Some(return_to) => HttpResponse::Found()
+                    .insert_header((LOCATION, return_to.as_str()))
+                    .finish(),
+                None => page(
+                    StatusCode::OK,
+                    &Form::LoggedIn {
+                        login: &jwt.claims.user.login,
+                    },
+                    None,
+                    None,
+                ),
+            };
+            if reissued {
+                hand_over_token(&mut response, &credentials, &jwt.token)?;
+            }
+            Ok(response)
+        }
+        Err(ApiError::Jwt) => Ok(page(
+            StatusCode::OK,
+            &Form::Password { login: "" },
+            return_to.as_deref(),
+            None,
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// the password posted from the login page, then the second factor when the user has one
+pub(crate) async fn login(
+    db: web::Data<StorageState>,
+    authenticators: web::Data<Authenticators>,
+    key_ring: web::Data<KeyRing>,
+    payload: web::Form<LoginPagePayload>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let return_to = {
+        let mut db = try_get_connection(&db)?;
+        return_to(&mut db, payload.return_to.as_deref())?
+    };
+    let step = match check_password(
+        &db,
+        authenticators,
+        &payload.login,
+        &payload.password,
+        &client_ip(&req),
+    )
+    .await
+    {
+        Ok(step) => step,
+        Err(e) => {
+            let (status, message) = login_error(e)?;
+            return Ok(page(
+                status,
+                &Form::Password {
+                    login: &payload.login,
+                },
+                return_to.as_deref(),
+                Some(&message),
+            ));
+        }
+    };
+    let mut db = try_get_connection(&db)?;
+    match step {
+        LoginStep::SecondFactor(challenge) => Ok(page(
+            StatusCode::OK,
+            &Form::SecondFactor {
+                challenge: &challenge.id,
+            },
+            return_to.as_deref(),
+            None,
+        )),
+        LoginStep::Enrollment(challenge, enrollment) => Ok(page(
+            StatusCode::OK,
+            &Form::Enrollment {
+                challenge: &challenge.id,
+                enrollment: &enrollment,
+            },
+            return_to.as_deref(),
+            None,
+        )),
+        LoginStep::LoggedIn(user) => logged_in_response(
+            &mut db,
+            &user,
+            &key_ring,
+            &ClientInfo::from(&req),
+            Some(&destination(return_to)),
+        ),
+    }
+}
+
+/// the second factor posted from the login page. once the challenge is gone the password has
+/// to be given again
+pub(crate) async fn login_mfa(
+    db: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+    payload: web::Form<LoginPageMfaPayload>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let return_to = return_to(&mut db, payload.return_to.as_deref())?;
+    match check_mfa(&mut db, &payload.challenge, &payload.code) {
+        Ok(user) => logged_in_response(
+            &mut db,
+            &user,
+            &key_ring,
+            &ClientInfo::from(&req),
+            Some(&destination(return_to)),
+        ),
+        Err(e) => {
+            let (status, message) = login_error(e)?;
+            if MfaChallenge::pending_user(&mut db, &payload.challenge)?.is_some() {
+                Ok(page(
+                    status,
+                    &Form::SecondFactor {
+                        challenge: &payload.challenge,
+                    },
+                    return_to.as_deref(),
+                    Some(&message),
+                ))
+            } else {
+                Ok(page(
+                    status,
+                    &Form::Password { login: "" },
+                    return_to.as_deref(),
+                    Some("The code expired, log in again."),
+                ))
+            }
+        }
+    }
+}
+
+/// the first code of the secret enrolled from the login page. the recovery codes are shown
+/// before the user goes on
+pub(crate) async fn login_enroll(
+    db: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+    payload: web::Form<LoginPageMfaPayload>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let return_to = return_to(&mut db, payload.return_to.as_deref())?;
+    match check_enrollment(&mut db, &payload.challenge, &payload.code) {
+        Ok((user, recovery_codes)) => open_session(
+            &mut db,
+            &user,
+            &key_ring,
+            &ClientInfo::from(&req),
+            page(
+                StatusCode::OK,
+                &Form::Enrolled {
+                    recovery_codes: &recovery_codes,
+                    destination: &destination(return_to),
+                },
+                None,
+                None,
+            ),
+        ),
+        Err(e) => {
+            let (status, message) = login_error(e)?;
+            let enrollment = match MfaChallenge::pending_user(&mut db, &payload.challenge)? {
+                Some(user) => Totp::pending_enrollment(&mut db, &user)?,
+                None => None,
+            };
+            match enrollment {
+                Some(enrollment) => Ok(page(
+                    status,
+                    &Form::Enrollment {
+                        challenge: &payload.challenge,
+                        enrollment: &enrollment,
+                    },
+                    return_to.as_deref(),
+                    Some(&message),
+                )),
+                None => Ok(page(
+                    status,
+                    &Form::Password { login: "" },
+                    return_to.as_deref(),
+                    Some("The code expired, log in again."),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn what_the_page_shows_back_is_escaped() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let authenticators = web::Data::new(Authenticators::from_config(config::get()));
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .app_data(authenticators)
+                .route("/auth/login/", web::get().to(login_page))
+                .route("/auth/login/", web::post().to(login)),
+        )
+        .await;
+        let return_to = "http://localhost:5173/?q=\"><script>alert(1)</script>";
+
+        let mut uri = Url::parse("http://localhost/auth/login/").unwrap();
+        uri.query_pairs_mut().append_pair("return_to", return_to);
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/auth/login/?{}", uri.query().unwrap()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(
+            "value=\"http://localhost:5173/?q=&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;\""
+        ));
+        assert!(!body.contains("<script>"));
+
+        // the login is filled back in after a failure
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/auth/login/")
+                .set_form([
+                    ("login", "\"><img src=x onerror=alert(1)>"),
+                    ("password", "wrong"),
+                    ("return_to", return_to),
+                ])
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("value=\"&quot;&gt;&lt;img src=x onerror=alert(1)&gt;\""));
+        assert!(!body.contains("<img src=x"));
+        assert!(!body.contains("<script>"));
+    }
+}
@@ -1,4 +1,18 @@
+pub(crate) mod api_token_routes;
+pub(crate) mod audit_routes;
 pub(crate) mod auth_routes;
+pub(crate) mod federation_routes;
+pub(crate) mod forward_auth_routes;
 pub(crate) mod group_routes;
+pub(crate) mod impersonation_routes;
+pub(crate) mod lockout_routes;
+pub(crate) mod login_routes;
+pub(crate) mod oidc_client_routes;
+pub(crate) mod oidc_routes;
+pub(crate) mod password_reset_routes;
 pub(crate) mod rules_routes;
+pub(crate) mod service_account_routes;
+pub(crate) mod session_routes;
+pub(crate) mod totp_routes;
 pub(crate) mod user_routes;
+pub(crate) mod well_known_routes;
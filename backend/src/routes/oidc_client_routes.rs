@@ -0,0 +1,107 @@
+use crate::api_error::ApiError;
+use crate::helpers::try_get_connection;
+use crate::models::oidc_client_model::{NewOidcClient, OidcClient, RedirectKind};
+use crate::StorageState;
+use actix_web::web;
+use diesel::SqliteConnection;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct OidcClientResponse {
+    client_id: String,
+    name: String,
+    public: bool,
+    redirect_uris: Vec<String>,
+    post_logout_redirect_uris: Vec<String>,
+    created_at: i64,
+    /// only when the client is registered, it can't be read back afterwards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+}
+
+fn client_response(
+    db: &mut SqliteConnection,
+    client: OidcClient,
+    client_secret: Option<String>,
+) -> Result<OidcClientResponse, ApiError> {
+    Ok(OidcClientResponse {
+        redirect_uris: client.redirect_uris(db, RedirectKind::Login)?,
+        post_logout_redirect_uris: client.redirect_uris(db, RedirectKind::Logout)?,
+        public: client.is_public(),
+        client_id: client.client_id,
+        name: client.name,
+        created_at: client.created_at,
+        client_secret,
+    })
+}
+
+pub(crate) async fn create_oidc_client(
+    db: web::Data<StorageState>,
+    payload: web::Json<NewOidcClient>,
+) -> Result<web::Json<OidcClientResponse>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let (client, client_secret) = OidcClient::create(&mut db, &payload)?;
+    Ok(web::Json(client_response(&mut db, client, client_secret)?))
+}
+
+pub(crate) async fn all_oidc_clients(
+    db: web::Data<StorageState>,
+) -> Result<web::Json<Vec<OidcClientResponse>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let clients = OidcClient::get_all(&mut db)?;
+    Ok(web::Json(
+        clients
+            .into_iter()
+            .map(|client| client_response(&mut db, client, None))
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+pub(crate) async fn one_oidc_client(
+    db: web::Data<StorageState>,
+    path: web::Path<String>,
+) -> Result<web::Json<OidcClientResponse>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let client = OidcClient::get(&mut db, &path.into_inner())?;
+    Ok(web::Json(client_response(&mut db, client, None)?))
+}
+
+#[allow(clippy::struct_field_names)]
+#[derive(Serialize, Deserialize)]
+pub(crate) struct OidcClientUpdatePayload {
+    new_name: Option<String>,
+    new_redirect_uris: Option<Vec<String>>,
+    new_post_logout_redirect_uris: Option<Vec<String>>,
+}
+pub(crate) async fn update_oidc_client(
+    db: web::Data<StorageState>,
+    payload: web::Json<OidcClientUpdatePayload>,
+    path: web::Path<String>,
+) -> Result<web::Json<OidcClientResponse>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let mut client = OidcClient::get(&mut db, &path.into_inner())?;
+    if let Some(new_name) = &payload.new_name {
+        client.rename(&mut db, new_name)?;
+    }
+    if let Some(new_redirect_uris) = &payload.new_redirect_uris {
+        if new_redirect_uris.is_empty() {
+            return Err(ApiError::OidcClient);
+        }
+        client.set_redirect_uris(&mut db, RedirectKind::Login, new_redirect_uris)?;
+    }
+    if let Some(new_post_logout_redirect_uris) = &payload.new_post_logout_redirect_uris {
+        client.set_redirect_uris(&mut db, RedirectKind::Logout, new_post_logout_redirect_uris)?;
+    }
+    Ok(web::Json(client_response(&mut db, client, None)?))
+}
+
+/// logs out everyone who signed in to the client
+pub(crate) async fn delete_oidc_client(
+    db: web::Data<StorageState>,
+    path: web::Path<String>,
+) -> Result<&'static str, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let client = OidcClient::get(&mut db, &path.into_inner())?;
+    client.delete(&mut db)?;
+    Ok("deleted.")
+}
@@ -0,0 +1,548 @@
+use crate::api_error::{ApiError, OAuthError};
+use crate::config;
+use crate::credentials::Credentials;
+use crate::helpers::{client_ip, try_get_connection};
+use crate::key_ring::KeyRing;
+use crate::models::jwt_model::{Claims, IdTokenClaims, JWTInternal, RefreshToken, UserInfo};
+use crate::models::oidc_client_model::{OidcClient, RedirectKind};
+use crate::models::oidc_code_model::{AuthorizeRequest, OidcCode};
+use crate::models::service_account_model::ServiceAccount;
+use crate::models::session_model::{ClientInfo, Session};
+use crate::models::user_model::User;
+use crate::routes::auth_routes::{add_session_cookies, remove_session_cookies};
+use crate::session_cookie::SessionCookie;
+use crate::StorageState;
+use actix_web::http::header::{ContentType, AUTHORIZATION, CACHE_CONTROL, LOCATION, PRAGMA};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use diesel::SqliteConnection;
+use log::error;
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+use url::Url;
+
+/// where the userinfo endpoint is served, the one use of the access tokens clients get
+pub(crate) const USERINFO_PATH: &str = "/auth/oidc/userinfo/";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TokenPayload {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EndSessionQuery {
+    id_token_hint: Option<String>,
+    client_id: Option<String>,
+    post_logout_redirect_uri: Option<String>,
+    state: Option<String>,
+}
+
+fn oauth(error: OAuthError) -> ApiError {
+    ApiError::OAuth { error }
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+/// the client's redirect uri with the response parameters appended, `state` and `iss` included
+fn callback(request: &AuthorizeRequest, params: &[(&str, &str)]) -> Result<String, ApiError> {
+    let mut url = Url::parse(&request.redirect_uri).map_err(|e| {
+        error!("{e:?}");
+        ApiError::Internal
+    })?;
+    {
+        let mut pairs = url.query_pairs_mut();
+        pairs.extend_pairs(params);
+        if let Some(state) = &request.state {
+            pairs.append_pair("state", state);
+        }
+        pairs.append_pair("iss", &config::get().oidc.issuer);
+    }
+    Ok(url.to_string())
+}
+
+fn error_redirect(request: &AuthorizeRequest, error: &str) -> Result<HttpResponse, ApiError> {
+    Ok(redirect(&callback(request, &[("error", error)])?))
+}
+
+/// sends the user to the login page, to come back to this very request once logged in.
+/// `prompt` is dropped from it or `prompt=login` would never let them through
+fn login_redirect(req: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let config = config::get();
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(
+            form_urlencoded::parse(req.query_string().as_bytes())
+                .filter(|(name, _)| name != "prompt"),
+        )
+        .finish();
+    let mut login_url = Url::parse(&config.oidc.login_url).map_err(|e| {
+        error!("{e:?}");
+        ApiError::Internal
+    })?;
+    login_url.query_pairs_mut().append_pair(
+        "return_to",
+        &format!("{}{}?{query}", config.oidc.issuer, req.path()),
+    );
+    Ok(redirect(login_url.as_str()))
+}
+
+/// the rauth session the browser is logged in with. an expired access token is renewed from
+/// the refresh token cookie, the new pair then has to be set on the response
+struct BrowserSession {
+    claims: Claims,
+    rotated: Option<(JWTInternal, RefreshToken)>,
+}
+
+fn browser_session(
+    db: &mut SqliteConnection,
+    req: &HttpRequest,
+    key_ring: &KeyRing,
+) -> Result<Option<BrowserSession>, ApiError> {
+    if let Ok(Some(credentials)) = Credentials::from_request(req) {
+        match JWTInternal::validate_jwt(db, &credentials.token, key_ring) {
+            Ok(claims) => {
+                return Ok(Some(BrowserSession {
+                    claims,
+                    rotated: None,
+                }))
+            }
+            Err(ApiError::Jwt) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    let Some(cookie) = SessionCookie::Refresh.read(req) else {
+        return Ok(None);
+    };
+    match JWTInternal::rotate(db, &cookie, key_ring) {
+        Ok((access, refresh)) => {
+            Session::touch(db, &access.claims.family, &client_ip(req))?;
+            Ok(Some(BrowserSession {
+                claims: access.claims.clone(),
+                rotated: Some((access, refresh)),
+            }))
+        }
+        Err(ApiError::Jwt) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// authorization endpoint, code flow only. pkce (s256) is required from public clients and
+/// optional for confidential ones
+pub(crate) async fn authorize(
+    db: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+    query: web::Query<AuthorizeRequest>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let request = query.into_inner();
+    let mut db = try_get_connection(&db)?;
+    // until the redirect uri is known to be the client's, errors are shown to the user
+    // rather than sent to it
+    let client = match OidcClient::get(&mut db, &request.client_id) {
+        Ok(client) => client,
+        Err(ApiError::OidcClientNotFound) => return Err(ApiError::OidcClient),
+        Err(e) => return Err(e),
+    };
+    if !client.allows_redirect(&mut db, RedirectKind::Login, &request.redirect_uri)? {
+        return Err(ApiError::OidcClient);
+    }
+    if request.response_type.as_deref() != Some("code") {
+        return error_redirect(&request, "unsupported_response_type");
+    }
+    let Some(scope) = request.granted_scope() else {
+        return error_redirect(&request, "invalid_scope");
+    };
+    match (
+        &request.code_challenge,
+        request.code_challenge_method.as_deref(),
+    ) {
+        (Some(_), Some("S256")) => (),
+        (None, None) if !client.is_public() => (),
+        _ => return error_redirect(&request, "invalid_request"),
+    }
+
+    let prompt = request.prompt.as_deref();
+    // an impersonation would outlive its time box in the client's session, and a client's
+    // token isn't the user logging in
+    let session = if prompt == Some("login") {
+        None
+    } else {
+        browser_session(&mut db, &req, &key_ring)?
+            .filter(|session| session.claims.act.is_none() && session.claims.aud.is_none())
+    };
+    let Some(session) = session else {
+        if prompt == Some("none") {
+            return error_redirect(&request, "login_required");
+        }
+        return login_redirect(&req);
+    };
+    let user = User::get(&mut db, session.claims.user.id)?;
+    let auth_time = Session::get(&mut db, &session.claims.family)?.map_or_else(
+        || chrono::Utc::now().timestamp(),
+        |session| session.created_at,
+    );
+    let code = OidcCode::create(
+        &mut db,
+        &user,
+        &request,
+        &scope,
+        auth_time,
+        &ClientInfo::from(&req),
+    )?;
+    let mut response = redirect(&callback(&request, &[("code", &code)])?);
+    if let Some((access, refresh)) = &session.rotated {
+        add_session_cookies(&mut response, access, refresh)?;
+    }
+    Ok(response)
+}
+
+/// client id and secret, from basic authentication or the form. the ids and secrets rauth
+/// hands out are alphanumeric so they need no url decoding. the scheme is case insensitive
+fn client_credentials(
+    req: &HttpRequest,
+    payload: &TokenPayload,
+) -> Result<(String, Option<String>), ApiError> {
+    let Some(header) = req.headers().get(AUTHORIZATION) else {
+        return match &payload.client_id {
+            Some(client_id) => Ok((client_id.clone(), payload.client_secret.clone())),
+            None => Err(oauth(OAuthError::InvalidClient)),
+        };
+    };
+    if payload.client_secret.is_some() {
+        return Err(oauth(OAuthError::InvalidRequest));
+    }
+    header
+        .to_str()
+        .ok()
+        .and_then(|header| header.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| {
+            decoded
+                .split_once(':')
+                .map(|(id, secret)| (id.to_string(), Some(secret.to_string())))
+        })
+        .filter(|(id, _)| {
+            payload
+                .client_id
+                .as_ref()
+                .is_none_or(|client_id| client_id == id)
+        })
+        .ok_or(oauth(OAuthError::InvalidClient))
+}
+
+fn token_response(response: &TokenResponse) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .insert_header((PRAGMA, "no-cache"))
+        .json(response)
+}
+
+/// token endpoint, trades an authorization code or a refresh token. a code opens a new rauth
+/// session tied to the client, only that client can refresh it. its access tokens are meant
+/// for the client and the granted scopes, rauth only takes them at the userinfo endpoint.
+/// service accounts use the client credentials grant instead, with their own id and secret,
+/// and get a single access token
+pub(crate) async fn token(
+    db: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+    payload: web::Form<TokenPayload>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let (client_id, client_secret) = client_credentials(&req, &payload)?;
+    let mut db = try_get_connection(&db)?;
+    if payload.grant_type == "client_credentials" {
+        let user = ServiceAccount::authenticate(&mut db, &client_id, client_secret.as_deref())?;
+        let access = JWTInternal::create_unrefreshable_session(
+            &mut db,
+            &user,
+            &key_ring,
+            &ClientInfo::from(&req),
+        )?;
+        return Ok(token_response(&TokenResponse {
+            access_token: access.token,
+            token_type: "Bearer",
+            expires_in: config::get().tokens.access_lifetime,
+            refresh_token: None,
+            id_token: None,
+            scope: None,
+        }));
+    }
+    let client = OidcClient::authenticate(&mut db, &client_id, client_secret.as_deref())?;
+    match payload.grant_type.as_str() {
+        "authorization_code" => {
+            let (Some(code), Some(redirect_uri)) = (&payload.code, &payload.redirect_uri) else {
+                return Err(oauth(OAuthError::InvalidRequest));
+            };
+            let Some(pending) = OidcCode::redeem(&mut db, code)? else {
+                return Err(oauth(OAuthError::InvalidGrant));
+            };
+            if pending.client_id != client.client_id
+                || pending.redirect_uri != *redirect_uri
+                || !pending.verifies(payload.code_verifier.as_deref())
+            {
+                return Err(oauth(OAuthError::InvalidGrant));
+            }
+            let user =
+                User::get(&mut db, pending.user_id).map_err(|_| oauth(OAuthError::InvalidGrant))?;
+            let (access, refresh) = JWTInternal::create_session(
+                &mut db,
+                &user,
+                &key_ring,
+                &ClientInfo {
+                    ip: pending.ip.clone(),
+                    user_agent: pending.user_agent.clone(),
+                    oidc_client_id: Some(client.client_id.clone()),
+                    scope: Some(pending.scope.clone()),
+                },
+            )?;
+            let id_token = IdTokenClaims::sign(
+                &mut db,
+                &user,
+                &client.client_id,
+                &pending.scope,
+                pending.nonce.clone(),
+                pending.auth_time,
+                &key_ring,
+            )?;
+            Ok(token_response(&TokenResponse {
+                access_token: access.token,
+                token_type: "Bearer",
+                expires_in: config::get().tokens.access_lifetime,
+                refresh_token: Some(refresh.token),
+                id_token: Some(id_token),
+                scope: Some(pending.scope),
+            }))
+        }
+        "refresh_token" => {
+            let Some(raw_refresh_token) = &payload.refresh_token else {
+                return Err(oauth(OAuthError::InvalidRequest));
+            };
+            let claims = RefreshToken::validate(raw_refresh_token, &key_ring)
+                .map_err(|_| oauth(OAuthError::InvalidGrant))?;
+            match Session::get(&mut db, &claims.family)? {
+                Some(session) if session.oidc_client_id.as_ref() == Some(&client.client_id) => (),
+                _ => return Err(oauth(OAuthError::InvalidGrant)),
+            }
+            let (access, refresh) = JWTInternal::rotate(&mut db, raw_refresh_token, &key_ring)
+                .map_err(|e| match e {
+                    ApiError::Jwt => oauth(OAuthError::InvalidGrant),
+                    e => e,
+                })?;
+            // the client refreshing is the user being active with it
+            Session::seen(&mut db, &claims.family)?;
+            Ok(token_response(&TokenResponse {
+                // the last access token of a session expires with it
+                expires_in: access.claims.exp - chrono::Utc::now().timestamp(),
+                access_token: access.token,
+                token_type: "Bearer",
+                refresh_token: Some(refresh.token),
+                id_token: None,
+                scope: None,
+            }))
+        }
+        _ => Err(oauth(OAuthError::UnsupportedGrantType)),
+    }
+}
+
+pub(crate) async fn userinfo(req: HttpRequest) -> Result<web::Json<UserInfo>, ApiError> {
+    let claims = match req.extensions().get::<Claims>() {
+        Some(claims) => claims.clone(),
+        None => return Err(ApiError::Internal),
+    };
+    Ok(web::Json(UserInfo::from_claims(&claims)))
+}
+
+/// rp-initiated logout: ends the browser's rauth session and, given an id token, the
+/// sessions of that user with its client. the user is only sent back to a post logout
+/// uri registered for the client
+pub(crate) async fn end_session(
+    db: web::Data<StorageState>,
+    key_ring: web::Data<KeyRing>,
+    query: web::Query<EndSessionQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let hint = match &query.id_token_hint {
+        Some(hint) => Some(
+            key_ring
+                .decode_hint::<IdTokenClaims>(hint)
+                .map_err(|_| ApiError::OidcClient)?,
+        ),
+        None => None,
+    };
+    let client_id = match (&hint, &query.client_id) {
+        (Some(hint), Some(client_id)) if hint.aud != *client_id => {
+            return Err(ApiError::OidcClient)
+        }
+        (Some(hint), _) => Some(hint.aud.clone()),
+        (None, client_id) => client_id.clone(),
+    };
+    let client = match client_id {
+        Some(client_id) => {
+            Some(OidcClient::get(&mut db, &client_id).map_err(|_| ApiError::OidcClient)?)
+        }
+        None => None,
+    };
+    let location = match (&query.post_logout_redirect_uri, &client) {
+        (Some(uri), Some(client)) => {
+            if !client.allows_redirect(&mut db, RedirectKind::Logout, uri)? {
+                return Err(ApiError::OidcClient);
+            }
+            let mut url = Url::parse(uri).map_err(|_| ApiError::OidcClient)?;
+            if let Some(state) = &query.state {
+                url.query_pairs_mut().append_pair("state", state);
+            }
+            Some(url.to_string())
+        }
+        (Some(_), None) => return Err(ApiError::OidcClient),
+        (None, _) => None,
+    };
+
+    if let Ok(Some(credentials)) = Credentials::from_request(&req) {
+        if let Ok(claims) = JWTInternal::validate_jwt(&mut db, &credentials.token, &key_ring) {
+            JWTInternal::delete_family(&mut db, &claims.family)?;
+        }
+    }
+    if let Some(cookie) = SessionCookie::Refresh.read(&req) {
+        if let Ok(claims) = RefreshToken::validate(&cookie, &key_ring) {
+            JWTInternal::delete_family(&mut db, &claims.family)?;
+        }
+    }
+    if let (Some(hint), Some(client)) = (&hint, &client) {
+        if let Ok(user) = User::get(&mut db, hint.user_info.sub.parse().unwrap_or_default()) {
+            for session in Session::for_user(&mut db, &user)? {
+                if session.oidc_client_id.as_ref() == Some(&client.client_id) {
+                    JWTInternal::delete_family(&mut db, &session.id)?;
+                }
+            }
+        }
+    }
+
+    let mut response = match location {
+        Some(location) => redirect(&location),
+        None => HttpResponse::Ok()
+            .insert_header(ContentType::html())
+            .body("logged out."),
+    };
+    remove_session_cookies(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::service_account_model::NewServiceAccount;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn service_accounts_trade_their_secret_for_a_single_access_token() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (account, secret) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let (account, _, secret) = ServiceAccount::create(
+                &mut db,
+                &NewServiceAccount {
+                    name: "backup-job".to_string(),
+                },
+            )
+            .unwrap();
+            (account, secret)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .route("/auth/oidc/token/", web::post().to(token)),
+        )
+        .await;
+        let basic = |secret: &str| {
+            format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{secret}", account.client_id))
+            )
+        };
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/auth/oidc/token/")
+                .insert_header((AUTHORIZATION, basic(&secret)))
+                .set_form([("grant_type", "client_credentials")])
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let granted: Value = test::read_body_json(resp).await;
+        assert_eq!(granted["token_type"], "Bearer");
+        assert!(granted.get("refresh_token").is_none());
+        let claims = {
+            let mut db = try_get_connection(&storage).unwrap();
+            JWTInternal::validate_jwt(
+                &mut db,
+                granted["access_token"].as_str().unwrap(),
+                &key_ring,
+            )
+            .unwrap()
+        };
+        assert_eq!(claims.user.id, account.id);
+        assert!(claims.aud.is_none());
+
+        // the form works as well as basic authentication
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/auth/oidc/token/")
+                .set_form([
+                    ("grant_type", "client_credentials"),
+                    ("client_id", account.client_id.as_str()),
+                    ("client_secret", secret.as_str()),
+                ])
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for refused in [
+            test::TestRequest::post()
+                .insert_header((AUTHORIZATION, basic("not-the-secret")))
+                .set_form([("grant_type", "client_credentials")]),
+            test::TestRequest::post().set_form([
+                ("grant_type", "client_credentials"),
+                ("client_id", account.client_id.as_str()),
+            ]),
+        ] {
+            let resp =
+                test::call_service(&app, refused.uri("/auth/oidc/token/").to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            let error: Value = test::read_body_json(resp).await;
+            assert_eq!(error["error"], "invalid_client");
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::api_error::ApiError;
+use crate::config;
+use crate::helpers::{client_ip, try_get_connection};
+use crate::mailer::{Mail, Mailer};
+use crate::models::api_token_model::ApiToken;
+use crate::models::jwt_model::JWTInternal;
+use crate::models::login_attempt_model::{AttemptKey, LoginAttempt};
+use crate::models::password_reset_model::{PasswordReset, PASSWORD_RESET_LIFETIME};
+use crate::models::user_model::User;
+use crate::password_policy::PasswordPolicy;
+use crate::StorageState;
+use actix_web::{web, HttpRequest};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PasswordResetRequestPayload {
+    /// login or email address
+    login: String,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PasswordResetConfirmPayload {
+    token: String,
+    new_password: String,
+}
+
+fn reset_mail(to: String, token: &str) -> Mail {
+    let url = &config::get().mail.password_reset_url;
+    Mail {
+        to,
+        subject: "Password reset".to_string(),
+        body: format!(
+            "A password reset was requested for your account.\n\n\
+            Follow this link to choose a new password, it expires in {} minutes:\n{url}?token={token}\n\n\
+            If you didn't ask for it, you can ignore this mail.",
+            PASSWORD_RESET_LIFETIME / 60
+        ),
+    }
+}
+
+/// answers the same whether the account exists or not, and the mail is sent in the
+/// background so the timing doesn't tell either. every request counts against the address
+/// as a failed attempt, so it can't go through accounts or mailboxes unhindered
+pub(crate) async fn request_password_reset(
+    db: web::Data<StorageState>,
+    mailer: web::Data<dyn Mailer>,
+    payload: web::Json<PasswordResetRequestPayload>,
+    req: HttpRequest,
+) -> Result<&'static str, ApiError> {
+    let mail = {
+        let mut db = try_get_connection(&db)?;
+        LoginAttempt::reserve(&mut db, &[&AttemptKey::Ip(&client_ip(&req))])?;
+        match User::find(&mut db, &payload.login) {
+            Ok(user) => match user.email.clone() {
+                Some(to) => {
+                    PasswordReset::create(&mut db, &user)?.map(|token| reset_mail(to, &token))
+                }
+                None => None,
+            },
+            Err(ApiError::UserNotFound) => None,
+            Err(e) => return Err(e),
+        }
+    };
+    if let Some(mail) = mail {
+        actix_web::rt::spawn(async move {
+            if let Err(e) = web::block(move || mailer.send(&mail)).await {
+                error!("{e:?}");
+            }
+        });
+    }
+    Ok("requested.")
+}
+
+/// sets the new password and logs the user out everywhere, their api tokens going with their
+/// sessions. the token is kept if the password is refused so another one can be tried
+pub(crate) async fn confirm_password_reset(
+    db: web::Data<StorageState>,
+    policy: web::Data<PasswordPolicy>,
+    payload: web::Json<PasswordResetConfirmPayload>,
+    req: HttpRequest,
+) -> Result<&'static str, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let ip = client_ip(&req);
+    let ip_key = AttemptKey::Ip(&ip);
+    LoginAttempt::check(&mut db, &ip_key)?;
+    let Some(mut user) = PasswordReset::pending_user(&mut db, &payload.token)? else {
+        LoginAttempt::record_failure(&mut db, &ip_key)?;
+        return Err(ApiError::PasswordReset);
+    };
+    user.hash = User::hash_new_password(&mut db, &policy, &user, &payload.new_password)?;
+    User::update_user(&mut db, &user)?;
+    PasswordReset::delete_for_user(&mut db, &user)?;
+    JWTInternal::invalidate_user(&mut db, &user)?;
+    ApiToken::delete_for_user(&mut db, &user)?;
+    LoginAttempt::clear(&mut db, &AttemptKey::Login(&user.login))?;
+    Ok("reset.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::api_token_model::{NewApiToken, TokenScope};
+    use crate::models::password_reset_model::PasswordReset;
+    use crate::password_hasher::PasswordHasher;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const NEW_PASSWORD: &str = "N3w-pass-w0rd!abc";
+
+    /// keeps the mails instead of sending them
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<Mail>>);
+
+    impl Mailer for Outbox {
+        fn send(&self, mail: &Mail) -> Result<(), ApiError> {
+            self.0.lock().unwrap().push(Mail {
+                to: mail.to.clone(),
+                subject: mail.subject.clone(),
+                body: mail.body.clone(),
+            });
+            Ok(())
+        }
+    }
+
+    fn ip_failures(storage: &web::Data<StorageState>) -> i32 {
+        let mut db = try_get_connection(storage).unwrap();
+        crate::schema::login_attempts::dsl::login_attempts
+            .filter(crate::schema::login_attempts::dsl::attempt_key.eq("ip:10.1.2.3"))
+            .select(crate::schema::login_attempts::dsl::failures)
+            .first(&mut *db)
+            .unwrap_or(0)
+    }
+
+    #[actix_web::test]
+    async fn a_mailed_token_resets_the_password_once_and_logs_out_everywhere() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let outbox = Arc::new(Outbox::default());
+        let (alice, session, api_token) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[]);
+            let session = test_support::session(&mut db, &key_ring, &alice, None).token;
+            let (_, api_token) = ApiToken::create(
+                &mut db,
+                &alice,
+                &NewApiToken {
+                    name: "ci".to_string(),
+                    scopes: vec![TokenScope::Read],
+                    expires_in: None,
+                },
+            )
+            .unwrap();
+            (alice, session, api_token)
+        };
+        let mailer: Arc<dyn Mailer> = outbox.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(web::Data::from(mailer))
+                .app_data(web::Data::new(config::get().passwords.policy.clone()))
+                .route("/reset/", web::post().to(request_password_reset))
+                .route("/reset/confirm/", web::post().to(confirm_password_reset)),
+        )
+        .await;
+        let post = |path: &str, body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri(path)
+                .peer_addr("10.1.2.3:4567".parse().unwrap())
+                .set_json(body)
+                .to_request()
+        };
+
+        // unknown accounts get the same answer, every request counts against the address
+        for login in ["nobody", "alice@example.com"] {
+            let resp = test::call_service(&app, post("/reset/", json!({ "login": login }))).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        assert_eq!(ip_failures(&storage), 2);
+        let mut mail = None;
+        for _ in 0..100 {
+            mail = outbox.0.lock().unwrap().pop();
+            if mail.is_some() {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mail = mail.expect("reset mail sent");
+        assert_eq!(mail.to, "alice@example.com");
+        let token = mail.body.split("?token=").nth(1).unwrap();
+        let token = token.split_whitespace().next().unwrap().to_string();
+
+        let confirm = |token: &str| {
+            post(
+                "/reset/confirm/",
+                json!({ "token": token, "new_password": NEW_PASSWORD }),
+            )
+        };
+        let resp = test::call_service(&app, confirm(&token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        {
+            let mut db = try_get_connection(&storage).unwrap();
+            let user = User::get(&mut db, alice.id).unwrap();
+            assert!(PasswordHasher::verify(NEW_PASSWORD, &user.hash));
+            assert!(JWTInternal::validate_jwt(&mut db, &session, &key_ring).is_err());
+            assert!(ApiToken::claims(&mut db, &api_token, TokenScope::Read).is_err());
+        }
+
+        // used up
+        let resp = test::call_service(&app, confirm(&token)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn expired_tokens_are_refused_and_counted() {
+        let storage = test_support::storage();
+        let token = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[]);
+            let token = PasswordReset::create(&mut db, &alice).unwrap().unwrap();
+            diesel::update(crate::schema::password_resets::dsl::password_resets)
+                .set(
+                    crate::schema::password_resets::dsl::expires_at
+                        .eq(chrono::Utc::now().timestamp() - 1),
+                )
+                .execute(&mut *db)
+                .unwrap();
+            token
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(web::Data::new(config::get().passwords.policy.clone()))
+                .route("/reset/confirm/", web::post().to(confirm_password_reset)),
+        )
+        .await;
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/reset/confirm/")
+                .peer_addr("10.1.2.3:4567".parse().unwrap())
+                .set_json(json!({ "token": token, "new_password": NEW_PASSWORD }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(ip_failures(&storage), 1);
+    }
+}
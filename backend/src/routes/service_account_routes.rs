@@ -0,0 +1,74 @@
+use crate::api_error::ApiError;
+use crate::helpers::try_get_connection;
+use crate::models::group_model::Group;
+use crate::models::group_user_model::GroupUser;
+use crate::models::jwt_model::JWTInternal;
+use crate::models::service_account_model::{NewServiceAccount, ServiceAccount};
+use crate::models::user_model::User;
+use crate::StorageState;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ServiceAccountSecretResponse {
+    #[serde(flatten)]
+    service_account: ServiceAccount,
+    /// can't be read back afterwards
+    client_secret: String,
+}
+
+/// the account joins the public group, like users do
+pub(crate) async fn create_service_account(
+    db: web::Data<StorageState>,
+    payload: web::Json<NewServiceAccount>,
+) -> Result<web::Json<ServiceAccountSecretResponse>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let (service_account, user, client_secret) = ServiceAccount::create(&mut db, &payload)?;
+    let public_group = &Group::get(&mut db, 1)?;
+    GroupUser::add_user_to_group(&mut db, &user, public_group)?;
+    Ok(web::Json(ServiceAccountSecretResponse {
+        service_account,
+        client_secret,
+    }))
+}
+
+pub(crate) async fn all_service_accounts(
+    db: web::Data<StorageState>,
+) -> Result<web::Json<Vec<ServiceAccount>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    Ok(web::Json(ServiceAccount::get_all(&mut db)?))
+}
+
+pub(crate) async fn one_service_account(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<web::Json<ServiceAccount>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    Ok(web::Json(ServiceAccount::get(&mut db, path.into_inner())?))
+}
+
+/// the tokens issued with the previous secret are revoked
+pub(crate) async fn rotate_service_account_secret(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<web::Json<ServiceAccountSecretResponse>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let service_account = ServiceAccount::get(&mut db, path.into_inner())?;
+    let client_secret = service_account.rotate_secret(&mut db)?;
+    Ok(web::Json(ServiceAccountSecretResponse {
+        service_account,
+        client_secret,
+    }))
+}
+
+pub(crate) async fn delete_service_account(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<&'static str, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let service_account = ServiceAccount::get(&mut db, path.into_inner())?;
+    let user = User::get(&mut db, service_account.id)?;
+    JWTInternal::invalidate_user(&mut db, &user)?;
+    User::delete_user(&mut db, &user)?;
+    Ok("deleted.")
+}
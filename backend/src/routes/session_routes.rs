@@ -0,0 +1,188 @@
+use crate::api_error::ApiError;
+use crate::helpers::try_get_connection;
+use crate::models::jwt_model::{Claims, JWTInternal};
+use crate::models::session_model::Session;
+use crate::models::user_model::User;
+use crate::StorageState;
+use actix_web::{web, HttpMessage, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SessionResponse {
+    #[serde(flatten)]
+    session: Session,
+    /// whether it is the session the request was made with
+    current: bool,
+}
+
+fn request_claims(req: &HttpRequest) -> Result<Claims, ApiError> {
+    match req.extensions().get::<Claims>() {
+        Some(claims) => Ok(claims.clone()),
+        None => Err(ApiError::Internal),
+    }
+}
+
+pub(crate) async fn list_sessions(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<SessionResponse>>, ApiError> {
+    let claims = request_claims(&req)?;
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    Ok(web::Json(
+        Session::for_user(&mut db, &user)?
+            .into_iter()
+            .map(|session| SessionResponse {
+                current: session.id == claims.family,
+                session,
+            })
+            .collect(),
+    ))
+}
+
+pub(crate) async fn revoke_session(
+    db: web::Data<StorageState>,
+    path: web::Path<(i32, String)>,
+) -> Result<&'static str, ApiError> {
+    let (user_id, session_id) = path.into_inner();
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, user_id)?;
+    match Session::get(&mut db, &session_id)? {
+        Some(session) if session.user_id == user.id => {
+            JWTInternal::delete_family(&mut db, &session.id)?;
+            Ok("revoked.")
+        }
+        _ => Err(ApiError::SessionNotFound),
+    }
+}
+
+/// logs the user out everywhere but the session the request was made with, when they are
+/// the one asking
+pub(crate) async fn revoke_other_sessions(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+    req: HttpRequest,
+) -> Result<&'static str, ApiError> {
+    let claims = request_claims(&req)?;
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    if claims.user.id == user.id {
+        JWTInternal::invalidate_user_except(&mut db, &user, &claims.family)?;
+    } else {
+        JWTInternal::invalidate_user(&mut db, &user)?;
+    }
+    Ok("revoked.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middlewares::authentication_middleware::RequireAuth;
+    use crate::middlewares::target_user_or_super_user_middleware::TargetUserOrSuperUser;
+    use crate::test_support;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn users_see_and_end_their_sessions() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (alice, laptop, phone, tablet, mallory, root) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[]);
+            let mallory = test_support::user(&mut db, "mallory", &[]);
+            let root = User::get(&mut db, 1).unwrap();
+            (
+                alice.id,
+                test_support::session(&mut db, &key_ring, &alice, None),
+                test_support::session(&mut db, &key_ring, &alice, None),
+                test_support::session(&mut db, &key_ring, &alice, None),
+                test_support::session(&mut db, &key_ring, &mallory, None),
+                test_support::session(&mut db, &key_ring, &root, None),
+            )
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(key_ring.clone())
+                .service(
+                    web::scope("/users/{user}/sessions")
+                        .wrap(TargetUserOrSuperUser)
+                        .wrap(RequireAuth)
+                        .service(
+                            web::resource("/")
+                                .route(web::get().to(list_sessions))
+                                .route(web::delete().to(revoke_other_sessions)),
+                        )
+                        .service(
+                            web::resource("/{session}/").route(web::delete().to(revoke_session)),
+                        ),
+                ),
+        )
+        .await;
+        let call = |method: Method, path: String, token: &JWTInternal| {
+            test::TestRequest::default()
+                .method(method)
+                .uri(&path)
+                .insert_header((AUTHORIZATION, format!("Bearer {}", token.token)))
+                .to_request()
+        };
+        let sessions = format!("/users/{alice}/sessions/");
+
+        let resp = test::call_service(&app, call(Method::GET, sessions.clone(), &laptop)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let listed: Value = test::read_body_json(resp).await;
+        let listed = listed.as_array().unwrap();
+        assert_eq!(listed.len(), 3);
+        let current = listed
+            .iter()
+            .filter(|session| session["current"] == true)
+            .collect::<Vec<&Value>>();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["id"], laptop.claims.family.as_str());
+        let resp = test::call_service(&app, call(Method::GET, sessions.clone(), &mallory)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // sessions of someone else aren't found under the user
+        let resp = test::call_service(
+            &app,
+            call(
+                Method::DELETE,
+                format!("{sessions}{}/", mallory.claims.family),
+                &laptop,
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(
+            &app,
+            call(
+                Method::DELETE,
+                format!("{sessions}{}/", phone.claims.family),
+                &laptop,
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, call(Method::GET, sessions.clone(), &phone)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // logging out everywhere else keeps the session asking
+        let resp = test::call_service(&app, call(Method::DELETE, sessions.clone(), &laptop)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, call(Method::GET, sessions.clone(), &tablet)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, call(Method::GET, sessions.clone(), &laptop)).await;
+        let listed: Value = test::read_body_json(resp).await;
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+
+        // a super logs them out everywhere
+        let resp = test::call_service(&app, call(Method::DELETE, sessions.clone(), &root)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, call(Method::GET, sessions, &laptop)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
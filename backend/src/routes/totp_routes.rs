@@ -0,0 +1,213 @@
+use crate::api_error::ApiError;
+use crate::helpers::{client_ip, try_get_connection};
+use crate::models::audit_log_model::{AuditAction, AuditEntry};
+use crate::models::jwt_model::{Actor, Claims};
+use crate::models::recovery_code_model::RecoveryCode;
+use crate::models::role_mfa_model::RoleMfa;
+use crate::models::role_model::Role;
+use crate::models::role_user_model::RoleUser;
+use crate::models::totp_model::{Totp, TotpEnrollment};
+use crate::models::user_model::User;
+use crate::StorageState;
+use actix_web::{web, HttpMessage, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TotpStatus {
+    enabled: bool,
+    recovery_codes_left: i64,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TotpCodePayload {
+    code: String,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TotpDisablePayload {
+    code: Option<String>,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+pub(crate) async fn totp_status(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<web::Json<TotpStatus>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    Ok(web::Json(TotpStatus {
+        enabled: Totp::is_enabled(&mut db, &user)?,
+        recovery_codes_left: RecoveryCode::remaining(&mut db, &user)?,
+    }))
+}
+
+pub(crate) async fn begin_totp_enrollment(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+) -> Result<web::Json<TotpEnrollment>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    Ok(web::Json(Totp::begin_enrollment(&mut db, &user)?))
+}
+
+pub(crate) async fn confirm_totp_enrollment(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+    payload: web::Json<TotpCodePayload>,
+) -> Result<web::Json<RecoveryCodesResponse>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    Ok(web::Json(RecoveryCodesResponse {
+        recovery_codes: Totp::confirm_enrollment(&mut db, &user, &payload.code)?,
+    }))
+}
+
+pub(crate) async fn regenerate_recovery_codes(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+    payload: web::Json<TotpCodePayload>,
+) -> Result<web::Json<RecoveryCodesResponse>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    if !Totp::verify(&mut db, &user, &payload.code)? {
+        return Err(ApiError::Mfa);
+    }
+    Ok(web::Json(RecoveryCodesResponse {
+        recovery_codes: RecoveryCode::regenerate(&mut db, &user)?,
+    }))
+}
+
+/// users turning off their own second factor have to prove they still hold it, and can't
+/// when their role requires it. any super can reset someone else's without a code, for
+/// users who lost theirs, so that is written to the audit log
+pub(crate) async fn disable_totp(
+    db: web::Data<StorageState>,
+    path: web::Path<i32>,
+    payload: Option<web::Json<TotpDisablePayload>>,
+    req: HttpRequest,
+) -> Result<&'static str, ApiError> {
+    let claims = match req.extensions().get::<Claims>() {
+        Some(claims) => claims.clone(),
+        None => return Err(ApiError::Internal),
+    };
+    let mut db = try_get_connection(&db)?;
+    let user = User::get(&mut db, path.into_inner())?;
+    if claims.user.id == user.id {
+        let role = RoleUser::roles_from_user(&mut db, &user)?;
+        if RoleMfa::required_for(&mut db, &role)? {
+            return Err(ApiError::MfaEnrollmentRequired);
+        }
+        let Some(code) = payload.and_then(|p| p.into_inner().code) else {
+            return Err(ApiError::Mfa);
+        };
+        if !Totp::verify(&mut db, &user, &code)? {
+            return Err(ApiError::Mfa);
+        }
+    }
+    Totp::disable(&mut db, &user)?;
+    if claims.user.id != user.id {
+        let actor = claims.act.unwrap_or(Actor {
+            sub: claims.user.id,
+            login: claims.user.login,
+        });
+        AuditEntry::record(
+            &mut db,
+            &actor,
+            &user,
+            AuditAction::DisableTotp,
+            "",
+            &client_ip(&req),
+        )?;
+    }
+    Ok("disabled.")
+}
+
+pub(crate) async fn list_mfa_roles(
+    db: web::Data<StorageState>,
+) -> Result<web::Json<Vec<RoleMfa>>, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    Ok(web::Json(RoleMfa::get_all(&mut db)?))
+}
+
+pub(crate) async fn require_mfa_for_role(
+    db: web::Data<StorageState>,
+    payload: web::Json<RoleMfa>,
+) -> Result<&'static str, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    RoleMfa::require(&mut db, &Role::from(&payload.role)?)?;
+    Ok("required.")
+}
+
+pub(crate) async fn drop_mfa_for_role(
+    db: web::Data<StorageState>,
+    path: web::Path<String>,
+) -> Result<&'static str, ApiError> {
+    let mut db = try_get_connection(&db)?;
+    RoleMfa::drop_requirement(&mut db, &Role::from(&path.into_inner())?)?;
+    Ok("dropped.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::audit_log_model::AuditQuery;
+    use crate::test_support;
+    use actix_web::test;
+
+    fn request_of(claims: Claims) -> HttpRequest {
+        let req = test::TestRequest::default().to_http_request();
+        req.extensions_mut().insert(claims);
+        req
+    }
+
+    #[actix_web::test]
+    async fn supers_disable_the_totp_of_others_without_a_code_on_the_record() {
+        let storage = test_support::storage();
+        let key_ring = test_support::key_ring();
+        let (alice, own, root) = {
+            let mut db = try_get_connection(&storage).unwrap();
+            let alice = test_support::user(&mut db, "alice", &[]);
+            test_support::enroll_totp(&mut db, &alice);
+            let root = User::get(&mut db, 1).unwrap();
+            (
+                alice.clone(),
+                test_support::session(&mut db, &key_ring, &alice, None).claims,
+                test_support::session(&mut db, &key_ring, &root, None).claims,
+            )
+        };
+
+        let refused = disable_totp(
+            storage.clone(),
+            web::Path::from(alice.id),
+            None,
+            request_of(own),
+        )
+        .await;
+        assert!(matches!(refused, Err(ApiError::Mfa)));
+
+        disable_totp(
+            storage.clone(),
+            web::Path::from(alice.id),
+            None,
+            request_of(root),
+        )
+        .await
+        .unwrap();
+        let mut db = try_get_connection(&storage).unwrap();
+        assert!(!Totp::is_enabled(&mut db, &alice).unwrap());
+        let entries = AuditEntry::list(
+            &mut db,
+            &AuditQuery {
+                actor: None,
+                user: Some(alice.id),
+                before: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].actor_id, 1);
+        assert_eq!(entries[0].action, "disable_totp");
+    }
+}
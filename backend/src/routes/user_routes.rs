@@ -6,17 +6,18 @@ use crate::models::jwt_model::{Claims, JWTInternal};
 use crate::models::role_model::Role;
 use crate::models::role_user_model::RoleUser;
 use crate::models::user_model::{NewUser, SafeUser, User};
+use crate::password_policy::PasswordPolicy;
 use crate::StorageState;
 use actix_web::{web, HttpMessage, HttpRequest};
-use log::error;
 use serde::{Deserialize, Serialize};
 
 pub(crate) async fn create_user(
     form_data: web::Json<NewUser>,
     db: web::Data<StorageState>,
+    policy: web::Data<PasswordPolicy>,
 ) -> Result<web::Json<User>, ApiError> {
     let mut db = try_get_connection(&db)?;
-    let user = User::create(&mut db, &form_data.0)?;
+    let user = User::create(&mut db, &policy, &form_data.0)?;
     let public_group = &Group::get(&mut db, 1)?;
     GroupUser::add_user_to_group(&mut db, &user, public_group)?; // group public with id 0 should always exist
     Ok(web::Json(user))
@@ -44,9 +45,11 @@ pub(crate) async fn one_user(
 pub(crate) struct UserUpdatePayload {
     new_login: Option<String>,
     new_hash: Option<String>,
+    new_email: Option<String>,
 }
 pub(crate) async fn update_user(
     db: web::Data<StorageState>,
+    policy: web::Data<PasswordPolicy>,
     user_update_payload: web::Json<UserUpdatePayload>,
     path: web::Path<i32>,
 ) -> Result<&'static str, ApiError> {
@@ -56,17 +59,11 @@ pub(crate) async fn update_user(
     if let Some(new_login) = user_update_payload.new_login.clone() {
         user_retrieved.login = new_login;
     };
-    if let Some(new_hash) = user_update_payload.new_hash.clone() {
-        if new_hash.len() < 4 {
-            return Err(ApiError::User);
-        }
-        user_retrieved.hash = match bcrypt::hash(new_hash, 12) {
-            Ok(new_hash) => new_hash,
-            Err(e) => {
-                error!("{e:?}");
-                return Err(ApiError::Internal);
-            }
-        };
+    if let Some(new_email) = user_update_payload.new_email.clone() {
+        user_retrieved.email = Some(new_email).filter(|new_email| !new_email.is_empty());
+    };
+    if let Some(new_hash) = &user_update_payload.new_hash {
+        user_retrieved.hash = User::hash_new_password(&mut db, &policy, &user_retrieved, new_hash)?;
     };
     User::update_user(&mut db, &user_retrieved)?;
     Ok("updated.")
@@ -106,7 +103,7 @@ pub struct UserDataResponsePayload {
     groups: Vec<Group>,
 }
 
-pub(crate) async fn get_user_data<'a>(
+pub(crate) async fn get_user_data(
     req: HttpRequest,
 ) -> Result<web::Json<UserDataResponsePayload>, ApiError> {
     let claims = match req.extensions().get::<Claims>() {
@@ -0,0 +1,73 @@
+use crate::api_error::ApiError;
+use crate::config;
+use crate::key_ring::{Jwks, KeyRing};
+use crate::models::oidc_code_model::SUPPORTED_SCOPES;
+use crate::routes::oidc_routes::USERINFO_PATH;
+use actix_web::web;
+use serde::Serialize;
+
+/// public keys tokens are currently signed with, for services verifying them on their own
+pub(crate) async fn jwks(key_ring: web::Data<KeyRing>) -> Result<web::Json<Jwks>, ApiError> {
+    Ok(web::Json(key_ring.jwks()?))
+}
+
+#[derive(Serialize)]
+pub(crate) struct DiscoveryDocument {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    end_session_endpoint: String,
+    introspection_endpoint: String,
+    jwks_uri: String,
+    scopes_supported: [&'static str; 4],
+    response_types_supported: [&'static str; 1],
+    response_modes_supported: [&'static str; 1],
+    grant_types_supported: [&'static str; 3],
+    subject_types_supported: [&'static str; 1],
+    id_token_signing_alg_values_supported: [&'static str; 1],
+    token_endpoint_auth_methods_supported: [&'static str; 3],
+    code_challenge_methods_supported: [&'static str; 1],
+    claims_supported: [&'static str; 11],
+    authorization_response_iss_parameter_supported: bool,
+}
+
+/// openid connect discovery, everything is relative to the configured issuer
+pub(crate) async fn openid_configuration() -> web::Json<DiscoveryDocument> {
+    let issuer = &config::get().oidc.issuer;
+    web::Json(DiscoveryDocument {
+        issuer: issuer.clone(),
+        authorization_endpoint: format!("{issuer}/auth/oidc/authorize/"),
+        token_endpoint: format!("{issuer}/auth/oidc/token/"),
+        userinfo_endpoint: format!("{issuer}{USERINFO_PATH}"),
+        end_session_endpoint: format!("{issuer}/auth/oidc/logout/"),
+        introspection_endpoint: format!("{issuer}/auth/introspect/"),
+        jwks_uri: format!("{issuer}/.well-known/jwks.json"),
+        scopes_supported: SUPPORTED_SCOPES,
+        response_types_supported: ["code"],
+        response_modes_supported: ["query"],
+        grant_types_supported: ["authorization_code", "refresh_token", "client_credentials"],
+        subject_types_supported: ["public"],
+        id_token_signing_alg_values_supported: ["EdDSA"],
+        token_endpoint_auth_methods_supported: [
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ],
+        code_challenge_methods_supported: ["S256"],
+        claims_supported: [
+            "iss",
+            "sub",
+            "aud",
+            "exp",
+            "iat",
+            "auth_time",
+            "nonce",
+            "preferred_username",
+            "email",
+            "role",
+            "groups",
+        ],
+        authorization_response_iss_parameter_supported: true,
+    })
+}
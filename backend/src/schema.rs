@@ -1,5 +1,33 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        prefix -> Text,
+        token_hash -> Text,
+        scopes -> Text,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+        last_used_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    audit_log (id) {
+        id -> Integer,
+        created_at -> BigInt,
+        actor_id -> Integer,
+        actor_login -> Text,
+        user_id -> Integer,
+        user_login -> Text,
+        action -> Text,
+        detail -> Text,
+        ip -> Text,
+    }
+}
+
 diesel::table! {
     domain_rules (id) {
         id -> Integer,
@@ -8,6 +36,28 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    federated_identities (id) {
+        id -> Integer,
+        provider -> Text,
+        subject -> Text,
+        user_id -> Integer,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    federation_states (state_hash) {
+        state_hash -> Text,
+        provider -> Text,
+        nonce -> Text,
+        code_verifier -> Text,
+        return_to -> Nullable<Text>,
+        link_user_id -> Nullable<Integer>,
+        expires_at -> BigInt,
+    }
+}
+
 diesel::table! {
     groups (id) {
         id -> Integer,
@@ -28,6 +78,95 @@ diesel::table! {
         jwt_id -> Text,
         user_id -> Integer,
         needs_refresh -> Integer,
+        family_id -> Text,
+        kind -> Text,
+        used_at -> Nullable<BigInt>,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    login_attempts (attempt_key) {
+        attempt_key -> Text,
+        failures -> Integer,
+        last_failure -> BigInt,
+        locked_until -> BigInt,
+    }
+}
+
+diesel::table! {
+    mfa_challenges (id) {
+        id -> Text,
+        user_id -> Integer,
+        attempts -> Integer,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    oidc_clients (client_id) {
+        client_id -> Text,
+        name -> Text,
+        secret_hash -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    oidc_codes (code_hash) {
+        code_hash -> Text,
+        client_id -> Text,
+        user_id -> Integer,
+        redirect_uri -> Text,
+        scope -> Text,
+        nonce -> Nullable<Text>,
+        code_challenge -> Nullable<Text>,
+        auth_time -> BigInt,
+        ip -> Text,
+        user_agent -> Text,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    oidc_redirect_uris (id) {
+        id -> Integer,
+        client_id -> Text,
+        uri -> Text,
+        kind -> Text,
+    }
+}
+
+diesel::table! {
+    password_history (id) {
+        id -> Integer,
+        user_id -> Integer,
+        hash -> Text,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    password_resets (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code_hash -> Text,
+        used -> Integer,
+    }
+}
+
+diesel::table! {
+    roles_mfa (role) {
+        role -> Text,
     }
 }
 
@@ -38,6 +177,37 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    service_account_credentials (user_id) {
+        user_id -> Integer,
+        client_id -> Text,
+        secret_hash -> Text,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Text,
+        user_id -> Integer,
+        created_at -> BigInt,
+        last_seen -> BigInt,
+        ip -> Text,
+        user_agent -> Text,
+        oidc_client_id -> Nullable<Text>,
+        scope -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    totp (user_id) {
+        user_id -> Integer,
+        secret -> Text,
+        enabled -> Integer,
+        last_step -> BigInt,
+    }
+}
+
 diesel::table! {
     url_rules (id) {
         id -> Integer,
@@ -51,18 +221,51 @@ diesel::table! {
         id -> Integer,
         login -> Text,
         hash -> Text,
+        email -> Nullable<Text>,
+        kind -> Text,
+        backend -> Text,
     }
 }
 
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(federated_identities -> users (user_id));
+diesel::joinable!(federation_states -> users (link_user_id));
 diesel::joinable!(jwt -> users (user_id));
+diesel::joinable!(mfa_challenges -> users (user_id));
+diesel::joinable!(oidc_codes -> oidc_clients (client_id));
+diesel::joinable!(oidc_codes -> users (user_id));
+diesel::joinable!(oidc_redirect_uris -> oidc_clients (client_id));
+diesel::joinable!(password_history -> users (user_id));
+diesel::joinable!(password_resets -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
 diesel::joinable!(roles_users -> users (user_id));
+diesel::joinable!(service_account_credentials -> users (user_id));
+diesel::joinable!(sessions -> oidc_clients (oidc_client_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(totp -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_log,
     domain_rules,
+    federated_identities,
+    federation_states,
     groups,
     groups_users,
     jwt,
+    login_attempts,
+    mfa_challenges,
+    oidc_clients,
+    oidc_codes,
+    oidc_redirect_uris,
+    password_history,
+    password_resets,
+    recovery_codes,
+    roles_mfa,
     roles_users,
+    service_account_credentials,
+    sessions,
+    totp,
     url_rules,
     users,
 );
@@ -0,0 +1,446 @@
+use crate::authenticator::Authenticators;
+use crate::config::Config;
+use crate::key_ring::KeyRing;
+use crate::mailer::mailer_from_config;
+use crate::middlewares::authentication_middleware::RequireAuth;
+use crate::middlewares::csrf_middleware::RequireTrustedOrigin;
+use crate::middlewares::impersonation_middleware::MarkImpersonation;
+use crate::middlewares::super_user::RequireSuperUser;
+use crate::middlewares::target_user_or_super_user_middleware::TargetUserOrSuperUser;
+use crate::routes::api_token_routes::{create_api_token, list_api_tokens, revoke_api_token};
+use crate::routes::audit_routes::list_audit_log;
+use crate::routes::auth_routes::{
+    auth, has_access, introspect, is_auth, logout, mfa, mfa_enroll, refresh,
+};
+use crate::routes::federation_routes::{
+    federation_callback, federation_link, federation_login, list_identities, list_providers,
+    unlink_identity,
+};
+use crate::routes::forward_auth_routes::forward_auth;
+use crate::routes::group_routes::{
+    add_user_to_group, all_groups, create_group, delete_group, delete_user_from_group,
+    list_users_from_group, one_group, update_group,
+};
+use crate::routes::impersonation_routes::impersonate_user;
+use crate::routes::lockout_routes::{
+    clear_ip_lockout, clear_user_lockout, list_lockouts, user_lockout,
+};
+use crate::routes::login_routes::{login, login_enroll, login_mfa, login_page};
+use crate::routes::oidc_client_routes::{
+    all_oidc_clients, create_oidc_client, delete_oidc_client, one_oidc_client, update_oidc_client,
+};
+use crate::routes::oidc_routes::{authorize, end_session, token, userinfo};
+use crate::routes::password_reset_routes::{confirm_password_reset, request_password_reset};
+use crate::routes::rules_routes::{
+    add_domain_rule, add_url_rule, delete_domain_rule, delete_url_rule, domain_rule,
+    domain_rules_for_domain, domain_rules_for_group, domain_rules_for_user, list_domain_rules,
+    list_url_rules, url_rule, url_rules_for_group, url_rules_for_url, url_rules_for_user,
+};
+use crate::routes::service_account_routes::{
+    all_service_accounts, create_service_account, delete_service_account, one_service_account,
+    rotate_service_account_secret,
+};
+use crate::routes::session_routes::{list_sessions, revoke_other_sessions, revoke_session};
+use crate::routes::totp_routes::{
+    begin_totp_enrollment, confirm_totp_enrollment, disable_totp, drop_mfa_for_role,
+    list_mfa_roles, regenerate_recovery_codes, require_mfa_for_role, totp_status,
+};
+use crate::routes::user_routes::get_user_data;
+use crate::routes::user_routes::{
+    all_users, create_user, delete_user, get_user_groups, one_user, update_user,
+};
+use crate::routes::well_known_routes::{jwks, openid_configuration};
+use crate::{config, establish_connection, ext_authz, proxy, StorageState};
+use actix_cors::Cors;
+use actix_web::middleware::{Logger, NormalizePath, TrailingSlash};
+use actix_web::{web, App, HttpServer};
+use dotenvy::dotenv;
+use env_logger::Env;
+use futures::future::try_join;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// # Errors
+/// fails if the listening addresses can't be bound
+/// # Panics
+/// panics on an invalid configuration, or when the database, the mailer or the signing keys
+/// can't be set up
+#[allow(clippy::too_many_lines)]
+pub async fn run() -> std::io::Result<()> {
+    env::set_var("RUST_BACKTRACE", "1");
+    dotenv().ok();
+    config::init(Config::load().unwrap_or_else(|e| panic!("Invalid configuration:\n{e}")));
+    let config = config::get();
+    env_logger::init_from_env(Env::default().default_filter_or(&config.server.log_level));
+    let storage = web::Data::new(StorageState {
+        db: Mutex::new(establish_connection(&config.database.url)),
+    });
+    let mailer = mailer_from_config(&config.mail).expect("Couldn't set up mailer");
+    let authenticators = web::Data::new(Authenticators::from_config(config));
+    let key_ring =
+        web::Data::new(KeyRing::load(&config.keys.dir).expect("Couldn't load signing keys"));
+    let watched_key_ring = key_ring.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval =
+            actix_web::rt::time::interval(Duration::from_secs(config.keys.reload_interval));
+        loop {
+            interval.tick().await;
+            watched_key_ring.reload();
+        }
+    });
+    let proxy = proxy::server(storage.clone(), key_ring.clone())?;
+    ext_authz::spawn(storage.clone(), key_ring.clone())?;
+    let server = HttpServer::new(move || {
+        let cors = config
+            .cors
+            .allowed_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(["GET", "POST", "PATCH", "DELETE", "OPTIONS"])
+            .allow_any_header()
+            .expose_any_header()
+            .supports_credentials(); // penser à exposer X-Refresh-Token
+        App::new()
+            .wrap(MarkImpersonation)
+            .wrap(RequireTrustedOrigin)
+            .wrap(cors)
+            .app_data(storage.clone())
+            .app_data(key_ring.clone())
+            .app_data(web::Data::new(config.passwords.policy.clone()))
+            .app_data(web::Data::from(mailer.clone()))
+            .app_data(authenticators.clone())
+            .wrap(NormalizePath::new(TrailingSlash::Always))
+            .wrap(Logger::new("%r - %s - %a %{User-Agent}i"))
+            .service(web::resource("/.well-known/jwks.json/").route(web::get().to(jwks)))
+            .service(
+                web::resource("/.well-known/openid-configuration/")
+                    .route(web::get().to(openid_configuration)),
+            )
+            .service(
+                web::scope("/auth")
+                    .service(
+                        web::resource("/")
+                            .route(web::get().to(is_auth).wrap(RequireAuth))
+                            .route(web::post().to(auth)),
+                    )
+                    .service(web::resource("/mfa/").route(web::post().to(mfa)))
+                    .service(web::resource("/mfa/enroll/").route(web::post().to(mfa_enroll)))
+                    .service(
+                        web::resource("/login/")
+                            .route(web::get().to(login_page))
+                            .route(web::post().to(login)),
+                    )
+                    .service(web::resource("/login/mfa/").route(web::post().to(login_mfa)))
+                    .service(
+                        web::resource("/login/mfa/enroll/").route(web::post().to(login_enroll)),
+                    )
+                    .service(web::resource("/refresh/").route(web::post().to(refresh)))
+                    .service(web::resource("/reset/").route(web::post().to(request_password_reset)))
+                    .service(
+                        web::resource("/reset/confirm/")
+                            .route(web::post().to(confirm_password_reset)),
+                    )
+                    .service(web::resource("/logout/").route(web::get().to(logout)))
+                    .service(web::resource("/has_access/").route(web::get().to(has_access)))
+                    .service(web::resource("/forward/").route(web::route().to(forward_auth)))
+                    .service(
+                        web::resource("/introspect/")
+                            .wrap(RequireAuth)
+                            .route(web::post().to(introspect)),
+                    )
+                    .service(
+                        web::scope("/oidc")
+                            .service(web::resource("/authorize/").route(web::get().to(authorize)))
+                            .service(web::resource("/token/").route(web::post().to(token)))
+                            .service(
+                                web::resource("/userinfo/")
+                                    .wrap(RequireAuth)
+                                    .route(web::get().to(userinfo))
+                                    .route(web::post().to(userinfo)),
+                            )
+                            .service(web::resource("/logout/").route(web::get().to(end_session))),
+                    )
+                    .service(
+                        web::scope("/federation")
+                            .service(web::resource("/").route(web::get().to(list_providers)))
+                            .service(
+                                web::resource("/{provider}/login/")
+                                    .route(web::get().to(federation_login)),
+                            )
+                            .service(
+                                web::resource("/{provider}/link/")
+                                    .wrap(RequireAuth)
+                                    .route(web::get().to(federation_link)),
+                            )
+                            .service(
+                                web::resource("/{provider}/callback/")
+                                    .route(web::get().to(federation_callback)),
+                            ),
+                    )
+                    .service(
+                        web::resource("/is_super/")
+                            .wrap(RequireSuperUser)
+                            .route(web::get().to(has_access)),
+                    ),
+            )
+            .service(
+                web::scope("/api")
+                    .wrap(RequireAuth)
+                    .service(
+                        web::scope("/users")
+                            .service(
+                                web::resource("/me/")
+                                    .route(web::get().to(get_user_data))
+                                    .wrap(RequireAuth),
+                            )
+                            .service(
+                                web::resource("/")
+                                    .wrap(RequireSuperUser)
+                                    .route(web::get().to(all_users))
+                                    .route(web::post().to(create_user)),
+                            )
+                            .service(
+                                web::scope("/{user}")
+                                    .wrap(TargetUserOrSuperUser)
+                                    .service(
+                                        web::resource("/")
+                                            .route(web::get().to(one_user))
+                                            .route(web::patch().to(update_user))
+                                            .route(web::delete().to(delete_user)),
+                                    )
+                                    .service(
+                                        web::resource("/groups/")
+                                            .route(web::get().to(get_user_groups)),
+                                    )
+                                    .service(
+                                        web::scope("/sessions")
+                                            .service(
+                                                web::resource("/")
+                                                    .route(web::get().to(list_sessions))
+                                                    .route(web::delete().to(revoke_other_sessions)),
+                                            )
+                                            .service(
+                                                web::resource("/{session}/")
+                                                    .route(web::delete().to(revoke_session)),
+                                            ),
+                                    )
+                                    .service(
+                                        web::scope("/tokens")
+                                            .service(
+                                                web::resource("/")
+                                                    .route(web::get().to(list_api_tokens))
+                                                    .route(web::post().to(create_api_token)),
+                                            )
+                                            .service(
+                                                web::resource("/{token}/")
+                                                    .route(web::delete().to(revoke_api_token)),
+                                            ),
+                                    )
+                                    .service(
+                                        web::scope("/identities")
+                                            .service(
+                                                web::resource("/")
+                                                    .route(web::get().to(list_identities)),
+                                            )
+                                            .service(
+                                                web::resource("/{identity}/")
+                                                    .route(web::delete().to(unlink_identity)),
+                                            ),
+                                    )
+                                    .service(
+                                        web::resource("/impersonate/")
+                                            .wrap(RequireSuperUser)
+                                            .route(web::post().to(impersonate_user)),
+                                    )
+                                    .service(
+                                        web::resource("/lockout/")
+                                            .wrap(RequireSuperUser)
+                                            .route(web::get().to(user_lockout))
+                                            .route(web::delete().to(clear_user_lockout)),
+                                    )
+                                    .service(
+                                        web::scope("/totp")
+                                            .service(
+                                                web::resource("/")
+                                                    .route(web::get().to(totp_status))
+                                                    .route(web::post().to(begin_totp_enrollment))
+                                                    .route(web::delete().to(disable_totp)),
+                                            )
+                                            .service(
+                                                web::resource("/confirm/")
+                                                    .route(web::post().to(confirm_totp_enrollment)),
+                                            )
+                                            .service(
+                                                web::resource("/recovery_codes/").route(
+                                                    web::post().to(regenerate_recovery_codes),
+                                                ),
+                                            ),
+                                    ),
+                            ),
+                    )
+                    .service(
+                        web::scope("/groups")
+                            .wrap(RequireSuperUser)
+                            .service(
+                                web::resource("/")
+                                    .route(web::get().to(all_groups))
+                                    .route(web::post().to(create_group)),
+                            )
+                            .service(
+                                web::scope("/{group_id}")
+                                    .service(
+                                        web::resource("/")
+                                            .route(web::get().to(one_group))
+                                            .route(web::patch().to(update_group))
+                                            .route(web::delete().to(delete_group)),
+                                    )
+                                    .service(
+                                        web::scope("/users")
+                                            .service(
+                                                web::resource("/")
+                                                    .route(web::get().to(list_users_from_group))
+                                                    .route(web::post().to(add_user_to_group)),
+                                            )
+                                            .service(
+                                                web::resource("/{user_id}/").route(
+                                                    web::delete().to(delete_user_from_group),
+                                                ),
+                                            ),
+                                    ),
+                            ),
+                    )
+                    .service(
+                        web::resource("/audit_log/")
+                            .wrap(RequireSuperUser)
+                            .route(web::get().to(list_audit_log)),
+                    )
+                    .service(
+                        web::scope("/lockouts")
+                            .wrap(RequireSuperUser)
+                            .service(web::resource("/").route(web::get().to(list_lockouts)))
+                            .service(
+                                web::resource("/ip/{ip}/")
+                                    .route(web::delete().to(clear_ip_lockout)),
+                            ),
+                    )
+                    .service(
+                        web::scope("/mfa")
+                            .wrap(RequireSuperUser)
+                            .service(
+                                web::resource("/roles/")
+                                    .route(web::get().to(list_mfa_roles))
+                                    .route(web::post().to(require_mfa_for_role)),
+                            )
+                            .service(
+                                web::resource("/roles/{role}/")
+                                    .route(web::delete().to(drop_mfa_for_role)),
+                            ),
+                    )
+                    .service(
+                        web::scope("/oidc/clients")
+                            .wrap(RequireSuperUser)
+                            .service(
+                                web::resource("/")
+                                    .route(web::get().to(all_oidc_clients))
+                                    .route(web::post().to(create_oidc_client)),
+                            )
+                            .service(
+                                web::resource("/{client_id}/")
+                                    .route(web::get().to(one_oidc_client))
+                                    .route(web::patch().to(update_oidc_client))
+                                    .route(web::delete().to(delete_oidc_client)),
+                            ),
+                    )
+                    .service(
+                        web::scope("/service_accounts")
+                            .wrap(RequireSuperUser)
+                            .service(
+                                web::resource("/")
+                                    .route(web::get().to(all_service_accounts))
+                                    .route(web::post().to(create_service_account)),
+                            )
+                            .service(
+                                web::resource("/{service_account}/")
+                                    .route(web::get().to(one_service_account))
+                                    .route(web::delete().to(delete_service_account)),
+                            )
+                            .service(
+                                web::resource("/{service_account}/secret/")
+                                    .route(web::post().to(rotate_service_account_secret)),
+                            ),
+                    )
+                    .service(
+                        web::scope("/rules")
+                            .wrap(RequireSuperUser)
+                            .service(
+                                web::scope("/domain")
+                                    .service(
+                                        web::resource("/")
+                                            .route(web::post().to(add_domain_rule))
+                                            .route(web::get().to(list_domain_rules)),
+                                    )
+                                    .service(
+                                        web::scope("/for")
+                                            .service(
+                                                web::resource("/domain/{domain_id}")
+                                                    .route(web::get().to(domain_rules_for_domain)),
+                                            )
+                                            .service(
+                                                web::resource("/group/{group_id}")
+                                                    .route(web::get().to(domain_rules_for_group)),
+                                            )
+                                            .service(
+                                                web::resource("/user/{user_id}")
+                                                    .route(web::get().to(domain_rules_for_user)),
+                                            ),
+                                    )
+                                    .service(
+                                        web::scope("/{rule_id}").service(
+                                            web::resource("/")
+                                                .route(web::get().to(domain_rule))
+                                                .route(web::delete().to(delete_domain_rule)),
+                                        ),
+                                    ),
+                            )
+                            .service(
+                                web::scope("/url")
+                                    .wrap(RequireSuperUser)
+                                    .service(
+                                        web::resource("/")
+                                            .route(web::post().to(add_url_rule))
+                                            .route(web::get().to(list_url_rules)),
+                                    )
+                                    .service(
+                                        web::scope("/for")
+                                            .service(
+                                                web::resource("/url/{url_id}")
+                                                    .route(web::get().to(url_rules_for_url)),
+                                            )
+                                            .service(
+                                                web::resource("/group/{group_id}")
+                                                    .route(web::get().to(url_rules_for_group)),
+                                            )
+                                            .service(
+                                                web::resource("/user/{user_id}")
+                                                    .route(web::get().to(url_rules_for_user)),
+                                            ),
+                                    )
+                                    .service(
+                                        web::scope("/{rule_id}").service(
+                                            web::resource("/")
+                                                .route(web::get().to(url_rule))
+                                                .route(web::delete().to(delete_url_rule)),
+                                        ),
+                                    ),
+                            ),
+                    ),
+            )
+    })
+    .bind(&config.server.bind)?
+    .run();
+    match proxy {
+        Some(proxy) => try_join(server, proxy).await.map(|_| ()),
+        None => server.await,
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config;
+use crate::models::federation_state_model::STATE_LIFETIME;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::HttpRequest;
+use serde::Deserialize;
+
+/// `SameSite` attribute of the session cookies. `none` is for frontends on another site
+/// than rauth, and needs secure cookies
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CookieSameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(same_site: CookieSameSite) -> Self {
+        match same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
+}
+
+/// name of the cookie binding a federated login to the browser that started it
+const FEDERATION_STATE_NAME: &str = "rauth_federation";
+
+/// the cookies a browser session is kept in. they are only ever built here, so that setting,
+/// refreshing and removing them agree on their name, path and domain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SessionCookie {
+    /// the access token, sent with every request
+    Access,
+    /// the refresh token, only sent to rauth's `/auth/` routes
+    Refresh,
+    /// the state of a login in progress at an upstream provider, the callback only takes the
+    /// state back from the browser it was handed to
+    FederationState,
+}
+
+impl SessionCookie {
+    pub(crate) fn name(self) -> &'static str {
+        let cookies = &config::get().cookies;
+        match self {
+            SessionCookie::Access => &cookies.name,
+            SessionCookie::Refresh => &cookies.refresh_name,
+            SessionCookie::FederationState => FEDERATION_STATE_NAME,
+        }
+    }
+
+    fn path(self) -> &'static str {
+        match self {
+            SessionCookie::Access => "/",
+            SessionCookie::Refresh => "/auth/",
+            SessionCookie::FederationState => "/auth/federation/",
+        }
+    }
+
+    /// the cookie holding `token`, living as long as the token
+    pub(crate) fn build(self, token: &str) -> Cookie<'static> {
+        let config = config::get();
+        let (lifetime, same_site) = match self {
+            SessionCookie::Access => (config.tokens.access_lifetime, config.cookies.same_site),
+            SessionCookie::Refresh => (config.tokens.refresh_lifetime, config.cookies.same_site),
+            // coming back from the provider is a cross-site navigation, strict would lose it
+            SessionCookie::FederationState => (STATE_LIFETIME, CookieSameSite::Lax),
+        };
+        let mut cookie = Cookie::build(self.name(), token.to_string())
+            .path(self.path())
+            .http_only(true)
+            .secure(config.secure_cookies())
+            .same_site(same_site.into())
+            .max_age(Duration::seconds(lifetime))
+            .finish();
+        if let Some(domain) = config.cookie_domain() {
+            cookie.set_domain(domain);
+        }
+        cookie
+    }
+
+    /// the cookie that makes the browser drop this one, with the same path and domain or the
+    /// browser keeps it
+    pub(crate) fn removal(self) -> Cookie<'static> {
+        let mut cookie = self.build("");
+        cookie.make_removal();
+        cookie
+    }
+
+    pub(crate) fn read(self, req: &HttpRequest) -> Option<String> {
+        req.cookie(self.name())
+            .map(|cookie| cookie.value().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::time::OffsetDateTime;
+
+    #[test]
+    fn cookies_are_set_and_removed_with_the_same_attributes() {
+        config::init_for_tests();
+        let config = config::get();
+        let expected = [
+            (
+                SessionCookie::Access,
+                "jwt",
+                "/",
+                config.tokens.access_lifetime,
+            ),
+            (
+                SessionCookie::Refresh,
+                "refresh_token",
+                "/auth/",
+                config.tokens.refresh_lifetime,
+            ),
+            (
+                SessionCookie::FederationState,
+                FEDERATION_STATE_NAME,
+                "/auth/federation/",
+                STATE_LIFETIME,
+            ),
+        ];
+        for (session_cookie, name, path, lifetime) in expected {
+            let cookie = session_cookie.build("token");
+            assert_eq!(cookie.name(), name);
+            assert_eq!(cookie.value(), "token");
+            assert_eq!(cookie.path(), Some(path));
+            assert_eq!(cookie.domain(), None);
+            assert_eq!(cookie.http_only(), Some(true));
+            // the test issuer is on plain http, like the development setup
+            assert_eq!(cookie.secure(), Some(false));
+            assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+            assert_eq!(cookie.max_age(), Some(Duration::seconds(lifetime)));
+
+            let removal = session_cookie.removal();
+            assert_eq!(removal.name(), name);
+            assert_eq!(removal.value(), "");
+            assert_eq!(removal.path(), Some(path));
+            assert_eq!(removal.domain(), None);
+            assert_eq!(removal.max_age(), Some(Duration::ZERO));
+            assert!(removal
+                .expires_datetime()
+                .is_some_and(|expires| expires < OffsetDateTime::now_utc()));
+        }
+    }
+}
@@ -12,12 +12,14 @@ export const ApiService = {
    * @param {"get" | "post" | "patch" | "delete"} method - The HTTP method for the request.
    * @param {string} uri - The URI to make the request to.
    * @param {object} payload - The payload to send with the request.
+   * @param {boolean} retried - Whether the request is already a retry after a token refresh.
    * @return {Promise<T>} The response data from the API request.
    */
   makeAuthenticatedApiRequest: async <T>(
     method: "get" | "post" | "patch" | "delete",
     uri: string,
     payload?: object,
+    retried = false,
   ): Promise<T> => {
     const authStore = useAuthStore();
     const envStore = useEnvStore();
@@ -83,6 +85,9 @@ export const ApiService = {
       }
       switch (res.status) {
         case 403:
+          if (!retried && (await authStore.refresh())) {
+            return ApiService.makeAuthenticatedApiRequest<T>(method, uri, payload, true);
+          }
           throw new BadCreditentials();
         case 404:
           throw new NotFound(res.data as string);
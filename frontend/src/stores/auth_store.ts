@@ -10,15 +10,35 @@ export const useAuthStore = defineStore("auth", () => {
   const authed = ref(false);
   const isSuper = ref(false);
 
-  const isAuth = async (): Promise<boolean> => {
+  const refresh = async (): Promise<boolean> => {
     try {
-      const { status } = await axios.get(`${envStore.app_base}auth`, {
-        /*headers: {
-          Authorization: `Bearer ${token}`,
-        },*/
+      const { status } = await axios.post(`${envStore.app_base}auth/refresh`, undefined, {
         withCredentials: true,
         validateStatus: (s) => s < 500,
       });
+      return status === 200;
+    } catch (e) {
+      console.error(e);
+      return false;
+    }
+  };
+
+  const isAuth = async (): Promise<boolean> => {
+    try {
+      const checkAuth = async () =>
+        (
+          await axios.get(`${envStore.app_base}auth`, {
+            /*headers: {
+              Authorization: `Bearer ${token}`,
+            },*/
+            withCredentials: true,
+            validateStatus: (s) => s < 500,
+          })
+        ).status;
+      let status = await checkAuth();
+      if (status === 403 && (await refresh())) {
+        status = await checkAuth();
+      }
       authed.value = status === 200;
       await checkIsSuper();
       return status === 200;
@@ -51,6 +71,7 @@ export const useAuthStore = defineStore("auth", () => {
 
   return {
     isAuth,
+    refresh,
     isSuper,
     logOut,
     authed,